@@ -0,0 +1,134 @@
+//! The Linode accounts the client acts for, each with its own token and
+//! request budget, and which account each nodebalancer belongs to.
+//!
+//! `TOKEN` is the token of the default account. `ACCOUNTS` names more,
+//! comma-separated, each with its token in a variable named after it:
+//! `legacy` reads `TOKEN_LEGACY`, and `API_RATE_LIMIT_LEGACY` to override the
+//! shared request budget per minute.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use crate::error::Error;
+use crate::linode::{LinodeClient, DEFAULT_ACCOUNT};
+use crate::location::{env_suffix, parse_list};
+
+/// What every account's client is built from, unless its variables say
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    pub api_version: String,
+    pub requests_per_minute: u32,
+    pub concurrency: usize,
+}
+
+/// A client per account, by name, and the routes of the nodebalancers the
+/// sync has seen. Clones share the routes.
+#[derive(Clone)]
+pub struct Accounts {
+    clients: BTreeMap<String, LinodeClient>,
+    default: String,
+    routes: Arc<RwLock<HashMap<i32, String>>>,
+}
+
+impl From<LinodeClient> for Accounts {
+    fn from(client: LinodeClient) -> Self {
+        Accounts::new(vec![client]).expect("one client is enough")
+    }
+}
+
+impl Accounts {
+    /// The accounts of `clients`, named by [`LinodeClient::account`]. The
+    /// first one takes the nodebalancers no other account is known for.
+    pub fn new(clients: Vec<LinodeClient>) -> Result<Self, Error> {
+        let default = clients.first()
+            .ok_or_else(|| Error::Config("no Linode account configured".to_string()))?
+            .account_name()
+            .to_string();
+        let mut named = BTreeMap::new();
+        for client in clients {
+            let name = client.account_name().to_string();
+            if named.insert(name.clone(), client).is_some() {
+                return Err(Error::Config(format!("account {} is configured twice", name)));
+            }
+        }
+
+        Ok(Accounts { clients: named, default, routes: Arc::default() })
+    }
+
+    /// The default account of `TOKEN`, if set, followed by the accounts
+    /// `ACCOUNTS` lists, with variables read through `var`.
+    pub fn from_vars(settings: &ClientSettings, var: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let mut named: Vec<(String, String)> = Vec::new();
+        if let Some(token) = var("TOKEN") {
+            named.push((DEFAULT_ACCOUNT.to_string(), token));
+        }
+        for account in parse_list(&var("ACCOUNTS").unwrap_or_default()) {
+            let token_var = format!("TOKEN_{}", env_suffix(&account));
+            let token = var(&token_var).ok_or_else(|| Error::Config(format!("{} not set!", token_var)))?;
+            named.push((account, token));
+        }
+        if named.is_empty() {
+            return Err(Error::Config("TOKEN not set!".to_string()));
+        }
+
+        let clients = named.into_iter().map(|(account, token)| {
+            let limit_var = format!("API_RATE_LIMIT_{}", env_suffix(&account));
+            let requests_per_minute = match var(&limit_var) {
+                Some(limit) => limit.parse().map_err(|_| Error::Config(format!("{} is not a number: {}", limit_var, limit)))?,
+                None => settings.requests_per_minute,
+            };
+            Ok(LinodeClient::new(&token, &settings.api_version, requests_per_minute, settings.concurrency)?.account(&account))
+        }).collect::<Result<Vec<_>, Error>>()?;
+
+        Accounts::new(clients)
+    }
+
+    /// The same accounts with their own limit of requests in flight each,
+    /// still sharing request budgets and routes with these.
+    pub fn concurrency(&self, concurrency: usize) -> Self {
+        Accounts {
+            clients: self.clients.iter().map(|(name, c)| (name.clone(), c.clone().concurrency(concurrency))).collect(),
+            default: self.default.clone(),
+            routes: Arc::clone(&self.routes),
+        }
+    }
+
+    /// Every account, by name.
+    pub fn clients(&self) -> impl Iterator<Item = &LinodeClient> {
+        self.clients.values()
+    }
+
+    pub fn default_client(&self) -> &LinodeClient {
+        &self.clients[&self.default]
+    }
+
+    /// Routes the calls about `nodebalancer_id` to `account`.
+    pub fn route(&self, nodebalancer_id: i32, account: &str) {
+        let mut routes = self.routes.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        routes.insert(nodebalancer_id, account.to_string());
+    }
+
+    /// Drops the routes of `nodebalancer_ids`, e.g. once they are no longer
+    /// listed.
+    pub fn unroute(&self, nodebalancer_ids: &HashSet<i32>) {
+        let mut routes = self.routes.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        routes.retain(|id, _| !nodebalancer_ids.contains(id));
+    }
+
+    /// The nodebalancers routed to `account`.
+    pub fn routed_to(&self, account: &str) -> HashSet<i32> {
+        let routes = self.routes.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        routes.iter().filter(|(_, routed)| *routed == account).map(|(&id, _)| id).collect()
+    }
+
+    /// The client for one nodebalancer: the one of its account, or the
+    /// default one while its account is not known.
+    pub fn for_nodebalancer(&self, nodebalancer_id: i32) -> Result<&LinodeClient, Error> {
+        let routes = self.routes.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match routes.get(&nodebalancer_id) {
+            None => Ok(self.default_client()),
+            Some(account) => self.clients.get(account)
+                .ok_or_else(|| Error::Config(format!("NB {} belongs to account {}, which has no token", nodebalancer_id, account))),
+        }
+    }
+}
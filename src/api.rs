@@ -0,0 +1,134 @@
+//! Read-only HTTP API over the local store, and the `/metrics` endpoint.
+
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+use crate::duration;
+use crate::error::Error;
+use crate::latency::{self, ago, NodeLatency};
+use crate::metrics;
+use crate::models::{DriftViolation, LocalNodeBalancerListObject, NodeDetailObject, TrafficSample};
+use crate::store::Store;
+use crate::traffic;
+
+type ApiResult<T> = Result<T, Response>;
+
+/// The store of every location served, by location.
+type Stores = Arc<Vec<(String, Arc<dyn Store>)>>;
+
+fn internal(e: Error) -> Response {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+}
+
+fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+/// `?window=` of the latency endpoint, e.g. `1h`.
+#[derive(Debug, Deserialize)]
+pub struct WindowQuery {
+    pub window: Option<String>,
+}
+
+/// `?window=&nb_id=` of the traffic endpoint.
+#[derive(Debug, Deserialize)]
+pub struct TrafficQuery {
+    pub window: Option<String>,
+    pub nb_id: Option<i32>,
+}
+
+/// Routes, over the stores of every location:
+///
+/// - `GET /healthz`
+/// - `GET /nodebalancers`: every nodebalancer with its label, hostname and
+///   LKE cluster
+/// - `GET /nodes`: every node with Linode's status and the last probe
+/// - `GET /nodes/latency?window=15m`: per-node latency percentiles and
+///   availability of the probe time series
+/// - `GET /drift`: configs that do not meet the drift policy
+/// - `GET /traffic?window=6h&nb_id=1`: the connections and traffic time
+///   series of every nodebalancer, or of one
+/// - `GET /metrics`: Prometheus metrics
+pub fn router(stores: Vec<(String, Arc<dyn Store>)>) -> Router {
+    Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/nodebalancers", get(nodebalancers))
+        .route("/nodes", get(nodes))
+        .route("/nodes/latency", get(node_latency))
+        .route("/drift", get(drift))
+        .route("/traffic", get(nodebalancer_traffic))
+        .route("/metrics", get(prometheus))
+        .with_state(Arc::new(stores))
+}
+
+async fn nodebalancers(State(stores): State<Stores>) -> ApiResult<Json<Vec<LocalNodeBalancerListObject>>> {
+    let mut nodebalancers = Vec::new();
+    for (_, store) in stores.iter() {
+        nodebalancers.extend(store.nodebalancers().await.map_err(internal)?);
+    }
+
+    Ok(Json(nodebalancers))
+}
+
+async fn nodes(State(stores): State<Stores>) -> ApiResult<Json<Vec<NodeDetailObject>>> {
+    let mut nodes = Vec::new();
+    for (_, store) in stores.iter() {
+        nodes.extend(store.node_details().await.map_err(internal)?);
+    }
+
+    Ok(Json(nodes))
+}
+
+async fn node_latency(
+    State(stores): State<Stores>,
+    Query(query): Query<WindowQuery>,
+) -> ApiResult<Json<Vec<NodeLatency>>> {
+    let window = match query.window {
+        Some(window) => duration::parse(&window).map_err(bad_request)?,
+        None => metrics::LATENCY_WINDOW,
+    };
+    let since = ago(Utc::now(), window);
+    let mut latencies = Vec::new();
+    for (_, store) in stores.iter() {
+        latencies.extend(latency::summarize(&store.probe_samples(since).await.map_err(internal)?));
+    }
+
+    Ok(Json(latencies))
+}
+
+async fn drift(State(stores): State<Stores>) -> ApiResult<Json<Vec<DriftViolation>>> {
+    let mut violations = Vec::new();
+    for (_, store) in stores.iter() {
+        violations.extend(store.config_drift().await.map_err(internal)?);
+    }
+
+    Ok(Json(violations))
+}
+
+async fn nodebalancer_traffic(
+    State(stores): State<Stores>,
+    Query(query): Query<TrafficQuery>,
+) -> ApiResult<Json<Vec<TrafficSample>>> {
+    let window = match query.window {
+        Some(window) => duration::parse(&window).map_err(bad_request)?,
+        None => traffic::DEFAULT_WINDOW,
+    };
+    let since = ago(Utc::now(), window);
+    let mut samples = Vec::new();
+    for (_, store) in stores.iter() {
+        samples.extend(store.traffic_samples(since, query.nb_id).await.map_err(internal)?);
+    }
+
+    Ok(Json(samples))
+}
+
+async fn prometheus(State(stores): State<Stores>) -> ApiResult<impl IntoResponse> {
+    let text = metrics::render(&stores).await.map_err(internal)?;
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text))
+}
@@ -0,0 +1,223 @@
+//! The audit log: every change the sync observes on the Linode side and
+//! every switch the client makes itself, appended to the `audit_event` table
+//! and logged as `AUDIT` lines of JSON.
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::error::Error;
+use crate::models::{
+    AuditAction,
+    AuditEvent,
+    AuditObject,
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeObject,
+};
+use crate::store::Store;
+
+/// The audited fields of a nodebalancer.
+pub fn nodebalancer_fields(nb: &LocalNodeBalancerListObject) -> Value {
+    json!({
+        "ipv4": nb.ipv4,
+        "region": nb.region,
+        "lke_id": nb.lke_id,
+    })
+}
+
+/// The audited fields of a config: its port and how it balances and checks
+/// nodes. The node counts are left out, they follow node status.
+pub fn config_fields(c: &NodeBalancerConfigObject) -> Value {
+    json!({
+        "port": c.port,
+        "protocol": c.protocol,
+        "algorithm": c.algorithm,
+        "stickiness": c.stickiness,
+        "cipher_suite": c.cipher_suite,
+        "proxy_protocol": c.proxy_protocol,
+        "check": c.check,
+        "check_path": c.check_path,
+        "check_body": c.check_body,
+        "check_interval": c.check_interval,
+        "check_timeout": c.check_timeout,
+        "check_attempts": c.check_attempts,
+        "check_passive": c.check_passive,
+        "udp_check_port": c.udp_check_port,
+    })
+}
+
+/// The audited fields of a node.
+pub fn node_fields(n: &NodeObject) -> Value {
+    json!({
+        "config_id": n.config_id,
+        "address": n.address,
+        "label": n.label,
+        "status": n.status,
+        "mode": n.mode,
+        "weight": n.weight,
+    })
+}
+
+/// Logs each event as an `AUDIT` line.
+pub fn log(events: &[AuditEvent]) {
+    for event in events {
+        println!("AUDIT {}", json!(event));
+    }
+}
+
+/// One line describing an event for people: the fields that changed, or
+/// every field of an added or removed object.
+pub fn describe(event: &AuditEvent) -> String {
+    match (&event.before, &event.after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let fields: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+            let changes: Vec<String> = fields.into_iter().filter_map(|field| {
+                let (old, new) = (before.get(field).unwrap_or(&Value::Null), after.get(field).unwrap_or(&Value::Null));
+                (old != new).then(|| format!("{}: {} -> {}", field, old, new))
+            }).collect();
+            changes.join(", ")
+        }
+        (Some(fields), None) | (None, Some(fields)) => fields.to_string(),
+        (Some(before), Some(after)) => format!("{} -> {}", before, after),
+        (None, None) => String::new(),
+    }
+}
+
+type Key = (AuditObject, i32, i32);
+
+// Nodebalancers before their configs, configs before their nodes.
+fn order(key: &Key) -> (i32, u8, i32) {
+    let rank = match key.0 {
+        AuditObject::Nodebalancer => 0,
+        AuditObject::Config => 1,
+        _ => 2,
+    };
+    (key.1, rank, key.2)
+}
+
+fn key(event: &AuditEvent) -> Key {
+    (event.object.clone(), event.nodebalancer_id, event.object_id)
+}
+
+fn event(at: DateTime<Utc>, (object, nodebalancer_id, object_id): Key, action: AuditAction, before: Option<Value>, after: Option<Value>) -> AuditEvent {
+    AuditEvent { at, object, action, nodebalancer_id, object_id, before, after }
+}
+
+/// Turns what each sync cycle fetched into audit events, by comparing the
+/// audited fields of every object with the ones last seen.
+///
+/// What was last seen is loaded from the audit log itself, so changes made
+/// while the client was not running are caught on its first cycle, and the
+/// very first cycle against an empty log records every object as added.
+/// Clones share what was seen.
+#[derive(Debug, Clone, Default)]
+pub struct Auditor {
+    // `None` until loaded from the store.
+    seen: Arc<Mutex<Option<HashMap<Key, Value>>>>,
+}
+
+impl Auditor {
+    pub fn new() -> Self {
+        Auditor::default()
+    }
+
+    fn seen(&self) -> MutexGuard<'_, Option<HashMap<Key, Value>>> {
+        self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Loads the fields of every object the audit log holds, unless that
+    /// was done already. Until it succeeds nothing is observed.
+    pub async fn load(&self, store: &dyn Store) -> Result<(), Error> {
+        if self.seen().is_some() {
+            return Ok(());
+        }
+        let objects = store.audited_objects().await?;
+        let seen = objects.into_iter().filter_map(|e| Some((key(&e), e.after?))).collect();
+        *self.seen() = Some(seen);
+
+        Ok(())
+    }
+
+    /// Forgets what was seen, so the next [`load`](Self::load) reads it
+    /// from the store again.
+    pub fn unload(&self) {
+        *self.seen() = None;
+    }
+
+    /// Events for one fetched nodebalancer: the nodebalancer, its configs
+    /// and its nodes compared with what was seen of them, and the configs
+    /// and nodes that are gone.
+    pub fn observe(
+        &self,
+        nodebalancer: &LocalNodeBalancerListObject,
+        configs: &[NodeBalancerConfigObject],
+        nodes: &[NodeObject],
+        at: DateTime<Utc>,
+    ) -> Vec<AuditEvent> {
+        let mut guard = self.seen();
+        let Some(seen) = guard.as_mut() else { return Vec::new() };
+        let nb_id = nodebalancer.nb_id;
+
+        let mut current = vec![((AuditObject::Nodebalancer, nb_id, nb_id), nodebalancer_fields(nodebalancer))];
+        current.extend(configs.iter().map(|c| ((AuditObject::Config, nb_id, c.id), config_fields(c))));
+        current.extend(nodes.iter().map(|n| ((AuditObject::Node, nb_id, n.id), node_fields(n))));
+        let present: HashSet<&Key> = current.iter().map(|(key, _)| key).collect();
+
+        let mut gone: Vec<Key> = seen.keys().filter(|k| k.1 == nb_id && !present.contains(k)).cloned().collect();
+        gone.sort_by_key(order);
+        let mut events: Vec<AuditEvent> = gone.into_iter().map(|key| {
+            let before = seen.remove(&key);
+            event(at, key, AuditAction::Removed, before, None)
+        }).collect();
+
+        for (key, after) in current {
+            match seen.insert(key.clone(), after.clone()) {
+                None => events.push(event(at, key, AuditAction::Added, None, Some(after))),
+                Some(before) if before != after => events.push(event(at, key, AuditAction::Changed, Some(before), Some(after))),
+                Some(_) => {}
+            }
+        }
+
+        events
+    }
+
+    /// Removal events for every nodebalancer that is no longer listed, and
+    /// for its configs and nodes, given every id listed this cycle.
+    pub fn observe_listed(&self, listed: &HashSet<i32>, at: DateTime<Utc>) -> Vec<AuditEvent> {
+        let mut guard = self.seen();
+        let Some(seen) = guard.as_mut() else { return Vec::new() };
+
+        let mut gone: Vec<Key> = seen.keys().filter(|k| !listed.contains(&k.1)).cloned().collect();
+        gone.sort_by_key(order);
+
+        gone.into_iter().map(|key| {
+            let before = seen.remove(&key);
+            event(at, key, AuditAction::Removed, before, None)
+        }).collect()
+    }
+
+    /// Logs `events` and appends them to the audit log. If they cannot be
+    /// stored, what they replaced is seen again so the next cycle raises
+    /// them once more.
+    pub async fn record(&self, store: &dyn Store, events: Vec<AuditEvent>) -> Result<(), Error> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        log(&events);
+        let reverted: Vec<(Key, Option<Value>)> = events.iter().map(|e| (key(e), e.before.clone())).collect();
+        let recorded = store.record_audit(events).await;
+        if recorded.is_err()
+            && let Some(seen) = self.seen().as_mut()
+        {
+            for (key, before) in reverted {
+                match before {
+                    Some(before) => seen.insert(key, before),
+                    None => seen.remove(&key),
+                };
+            }
+        }
+
+        recorded
+    }
+}
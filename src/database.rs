@@ -1,344 +1,1135 @@
-use tokio_postgres::{Row, Client, Error};
-use std::collections::HashMap;
+//! PostgreSQL storage: the local DB written by the sync loop and the main
+//! hc-nb-api DB that nodebalancers can be listed from.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio_postgres::{Row, Client, Error as PgError};
+use tokio_postgres::types::Json;
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use postgres_openssl::MakeTlsConnector;
 use std::env;
-use serde::{Serialize};
-use std::sync::LazyLock;
+use crate::error::Error;
+use crate::models::{
+    check_known,
+    Algorithm,
+    AuditAction,
+    AuditEvent,
+    AuditObject,
+    CheckType,
+    CipherSuite,
+    ClientInstance,
+    DatacenterReport,
+    DriftViolation,
+    LocalNodeBalancerConfigObject,
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeDetailObject,
+    NodeHealth,
+    NodeMode,
+    NodeObject,
+    NodeProbe,
+    NodeState,
+    NodeStatus,
+    Probe,
+    ProbeSample,
+    ProbeTarget,
+    Protocol,
+    Stickiness,
+    TrafficSample,
+    Verdict,
+};
+use crate::store::{Store, WriteCounts};
 
+/// Maps a row onto a type by column name, so adding or reordering columns
+/// does not shift values into the wrong fields.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, PgError>;
+}
 
-static maindb_pw: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("MAINDB_PASSWORD").expect("MAINDB_PASSWORD not set!") });
-static localdb_pw: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("LOCALDB_PASSWORD").expect("LOCALDB_PASSWORD not set!") });
-static maindb_hostport: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("MAINDB_HOSTPORT").expect("MAINDB_HOSTPORT not set!") });
-static localdb_hostport: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("LOCALDB_HOSTPORT").expect("LOCALDB_HOSTPORT not set!") });
+fn from_rows<T: FromRow>(rows: &[Row]) -> Result<Vec<T>, PgError> {
+    rows.iter().map(T::from_row).collect()
+}
+
+// Enum columns are NULL when the API sent a value this build did not know.
+fn get_enum<T: for<'a> From<&'a str> + Default>(row: &Row, column: &str) -> Result<T, PgError> {
+    let value: Option<&str> = row.try_get(column)?;
+    Ok(value.map(T::from).unwrap_or_default())
+}
 
-#[derive(Debug)]
-pub struct Nodebalancer {
-    _id: i32,
-    ip_address: String,
-    port: i32,
+// A local DB row, with everything the sync stores.
+impl FromRow for LocalNodeBalancerListObject {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(LocalNodeBalancerListObject {
+            nb_id: row.try_get("id")?,
+            ipv4: row.try_get("ipv4")?,
+            region: row.try_get("region")?,
+            lke_id: row.try_get("lke_id")?,
+            label: row.try_get("label")?,
+            account: None,
+            updated: row.try_get("updated")?,
+            hostname: row.try_get("hostname")?,
+            ipv6: row.try_get("ipv6")?,
+            client_conn_throttle: row.try_get("client_conn_throttle")?,
+            nb_type: row.try_get("type")?,
+            created: row.try_get("created")?,
+            lke_label: row.try_get("lke_label")?,
+            lke_type: row.try_get("lke_type")?,
+            lke_url: row.try_get("lke_url")?,
+        })
+    }
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-pub struct NodeBalancerListObject {
-    client_conn_throttle: i32,
-    created: String,
-    hostname: String,
-    pub id: i32,
-    ipv4: String,
-    ipv6: String,
-    label: String,
-    lke_cluster: Option<LkeCluster>,
-    region: String,
-    r#type: String,
-    updated: String,
+// A main DB row, which has no metadata and may name an account.
+fn maindb_nodebalancer(row: &Row) -> Result<LocalNodeBalancerListObject, PgError> {
+    Ok(LocalNodeBalancerListObject {
+        nb_id: row.try_get("id")?,
+        ipv4: row.try_get("ipv4")?,
+        region: row.try_get("region")?,
+        lke_id: row.try_get("lke_id")?,
+        account: row.try_get("account")?,
+        ..Default::default()
+    })
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-pub struct LocalNodeBalancerListObject {
-    pub nb_id: i32,
-    pub ipv4: String,
-    pub region: String,
-    pub lke_id: i32,
+impl FromRow for LocalNodeBalancerConfigObject {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(LocalNodeBalancerConfigObject {
+            id: row.try_get("id")?,
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            algorithm: get_enum(row, "algorithm")?,
+            port: row.try_get("port")?,
+            up: row.try_get("up")?,
+            down: row.try_get("down")?,
+        })
+    }
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-pub struct NodeObject {
-    address: String,
-    config_id: i32,
-    id: i32,
-    label: String,
-    mode: String,
-    nodebalancer_id: i32,
-    status: String,
-    weight: i32 
+impl FromRow for NodeDetailObject {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(NodeDetailObject {
+            id: row.try_get("id")?,
+            address: row.try_get("address")?,
+            status: get_enum(row, "status")?,
+            mode: get_enum(row, "mode")?,
+            config_id: row.try_get("config_id")?,
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            ipv4: row.try_get("ipv4")?,
+            region: row.try_get("region")?,
+            nodebalancer_label: row.try_get("nodebalancer_label")?,
+            algorithm: get_enum(row, "algorithm")?,
+            port: row.try_get("port")?,
+            up: row.try_get("up")?,
+            down: row.try_get("down")?,
+            probe: get_probe(row)?,
+        })
+    }
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-pub struct LkeCluster{
-    id: i32,
-    label: String,
-    r#type: String,
-    url: String,
+fn get_probe(row: &Row) -> Result<Option<Probe>, PgError> {
+    let Some(probed_at) = row.try_get("probed_at")? else {
+        return Ok(None);
+    };
+    Ok(Some(Probe {
+        status: get_enum(row, "probe_status")?,
+        latency_ms: row.try_get("probe_latency_ms")?,
+        error: row.try_get("probe_error")?,
+        probed_at,
+    }))
 }
 
-impl Default for LkeCluster {
-    fn default() -> Self {
-        LkeCluster {
-            id: 0,
-            label: String::new(),
-            r#type: String::new(),
-            url: String::new(),
-        }
+impl FromRow for ProbeTarget {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(ProbeTarget {
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            config_id: row.try_get("config_id")?,
+            node_id: row.try_get("node_id")?,
+            address: row.try_get("address")?,
+            protocol: get_enum(row, "protocol")?,
+            check: get_enum(row, "check")?,
+            check_path: row.try_get::<_, Option<String>>("check_path")?.unwrap_or_default(),
+            check_body: row.try_get::<_, Option<String>>("check_body")?.unwrap_or_default(),
+            check_interval: row.try_get::<_, Option<i32>>("check_interval")?.unwrap_or_default(),
+            check_timeout: row.try_get::<_, Option<i32>>("check_timeout")?.unwrap_or_default(),
+        })
     }
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-pub struct NodeBalancerConfigObject {
-    algorithm: String,
-    check: String,
-    check_attempts: i32,
-    check_body: String,
-    check_interval: i32,
-    check_passive: bool,
-    check_path: String,
-    check_timeout: i32,
-    cipher_suite: String,
-    pub id: i32,
-    pub nodebalancer_id: i32,
-    nodes_status: NodeStatus,
-    port: i32,
-    protocol: String,
-    proxy_protocol: String,
-    stickiness: String,
-    udp_check_port: i32,
-    udp_session_timeout: i32, 
+impl FromRow for NodeHealth {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(NodeHealth {
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            config_id: row.try_get("config_id")?,
+            node_id: row.try_get("node_id")?,
+            port: row.try_get("port")?,
+            address: row.try_get("address")?,
+            lastverdict: get_enum(row, "lastverdict")?,
+            current: get_enum(row, "current")?,
+            observed: get_enum(row, "observed")?,
+            streak: row.try_get("streak")?,
+            since: row.try_get("since")?,
+            probed_at: row.try_get("probed_at")?,
+        })
+    }
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-pub struct NodeStatus {
-    down: i32,
-    up: i32,
+impl FromRow for NodeState {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(NodeState {
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            nodebalancer_config_id: row.try_get("nodebalancer_config_id")?,
+            node_id: row.try_get("node_id")?,
+            port: row.try_get("port")?,
+            lastmode: get_enum(row, "lastmode")?,
+            current: get_enum(row, "current")?,
+        })
+    }
 }
 
-async fn create_connector() -> MakeTlsConnector {
-    let mut builder = SslConnector::builder(SslMethod::tls()).expect("unable to create sslconnector builder");
-    builder.set_ca_file("/tmp/ca.cert").expect("unable to load ca.cert");
-    builder.set_verify(SslVerifyMode::NONE);
-    let connector = MakeTlsConnector::new(builder.build());
+impl FromRow for ProbeSample {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(ProbeSample {
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            node_id: row.try_get("node_id")?,
+            probed_at: row.try_get("probed_at")?,
+            resolution: row.try_get("resolution")?,
+            probes: row.try_get("probes")?,
+            up: row.try_get("up")?,
+            latency_ms: row.try_get("latency_ms")?,
+            connect_ms: row.try_get("connect_ms")?,
+            tls_ms: row.try_get("tls_ms")?,
+            ttfb_ms: row.try_get("ttfb_ms")?,
+            http_status: row.try_get("http_status")?,
+        })
+    }
+}
 
-    connector
-} 
+impl FromRow for TrafficSample {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(TrafficSample {
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            at: row.try_get("at")?,
+            connections: row.try_get("connections")?,
+            traffic_in: row.try_get("traffic_in")?,
+            traffic_out: row.try_get("traffic_out")?,
+        })
+    }
+}
 
-pub async fn create_localdb_client() -> Client {
-    let connector = create_connector().await;
+impl FromRow for AuditEvent {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(AuditEvent {
+            at: row.try_get("at")?,
+            object: get_enum(row, "object")?,
+            action: get_enum(row, "action")?,
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            object_id: row.try_get("object_id")?,
+            before: row.try_get("before")?,
+            after: row.try_get("after")?,
+        })
+    }
+}
 
-    let url = format!("postgresql://akmadmin:{}@{}/defaultdb", localdb_pw.to_string(), localdb_hostport.to_string());
-    let Ok((client, connection)) = tokio_postgres::connect(&url, connector).await else { panic!("Client failure.") };
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+impl FromRow for ClientInstance {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(ClientInstance {
+            instance_id: row.try_get("instance_id")?,
+            location: row.try_get("location")?,
+            version: row.try_get("version")?,
+            started_at: row.try_get("started_at")?,
+            last_heartbeat: row.try_get("last_heartbeat")?,
+        })
+    }
+}
 
-    client 
+impl FromRow for DriftViolation {
+    fn from_row(row: &Row) -> Result<Self, PgError> {
+        Ok(DriftViolation {
+            nodebalancer_id: row.try_get("nodebalancer_id")?,
+            config_id: row.try_get("config_id")?,
+            port: row.try_get("port")?,
+            rule: row.try_get("rule")?,
+            field: row.try_get("field")?,
+            expected: row.try_get("expected")?,
+            actual: row.try_get("actual")?,
+            since: row.try_get("since")?,
+        })
+    }
+}
 
+/// Where to reach one Postgres database.
+#[derive(Clone, Debug)]
+pub struct PgTarget {
+    pub hostport: String,
+    pub password: String,
 }
 
-pub async fn create_maindb_client() -> Client {
-    let connector = create_connector().await;
+impl PgTarget {
+    pub fn new(hostport: &str, password: &str) -> Self {
+        PgTarget { hostport: hostport.to_string(), password: password.to_string() }
+    }
 
-    let url = format!("postgresql://akmadmin:{}@{}/defaultdb", maindb_pw.to_string(), maindb_hostport.to_string());
-    let Ok((client, connection)) = tokio_postgres::connect(&url, connector).await else { panic!("Client failure.") };
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+    /// Reads `<prefix>_HOSTPORT` and `<prefix>_PASSWORD`, e.g. `LOCALDB_HOSTPORT`.
+    pub fn from_env(prefix: &str) -> Result<Self, Error> {
+        let var = |suffix: &str| {
+            let name = format!("{}_{}", prefix, suffix);
+            env::var(&name).map_err(|_| Error::Config(format!("{} not set!", name)))
+        };
+        Ok(PgTarget { hostport: var("HOSTPORT")?, password: var("PASSWORD")? })
+    }
+
+    pub async fn connect(&self) -> Result<Client, Error> {
+        let connector = create_connector()?;
 
-    client 
+        let url = format!("postgresql://akmadmin:{}@{}/defaultdb", self.password, self.hostport);
+        let (client, connection) = tokio_postgres::connect(&url, connector).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("connection error: {}", e);
+            }
+        });
 
+        Ok(client)
+    }
 }
-pub async fn localdb_init() -> Result<(), Box<dyn std::error::Error>> {
-    let mut connection = create_localdb_client().await;
-    let main_table = connection.batch_execute("
-        CREATE TABLE IF NOT EXISTS nodebalancer (
-            id INTEGER NOT NULL,
-            ipv4 VARCHAR NOT NULL,
-            region VARCHAR NOT NULL,
-            lke_id INTEGER,
-            PRIMARY KEY (id)
-            );
-    ");
-
-    match main_table.await {
-        Ok(success) => println!("Nodebalancer table availabe"),
-        Err(e) => println!("{:?}", e),
-        }
 
-    let nb_cfg_conn  = connection.batch_execute("
-        CREATE TABLE IF NOT EXISTS nodebalancer_config  (
-            id INTEGER NOT NULL,
-            algorithm VARCHAR NOT NULL,
-            port INTEGER NOT NULL,
-            up INTEGER NOT NULL,
-            down INTEGER NOT NULL,
-            nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
-            PRIMARY KEY (id, nodebalancer_id)
-            );
-    ");
-    match nb_cfg_conn.await {
-        Ok(success) => println!("Nodebalancer config table availabe"),
-        Err(e) => println!("{:?}", e),
-        }
+fn create_connector() -> Result<MakeTlsConnector, Error> {
+    let tls_error = |e: openssl::error::ErrorStack| Error::Config(format!("unable to set up DB TLS: {}", e));
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+    builder.set_ca_file("/tmp/ca.cert").map_err(tls_error)?;
+    builder.set_verify(SslVerifyMode::NONE);
+    Ok(MakeTlsConnector::new(builder.build()))
+}
 
+/// The central hc-nb-api database.
+#[derive(Clone, Debug)]
+pub struct MainDb {
+    target: PgTarget,
+}
 
-    let node_table  = connection.batch_execute("
-        CREATE TABLE IF NOT EXISTS node  (
-            id INTEGER NOT NULL,
-            address VARCHAR NOT NULL,
-            status VARCHAR NOT NULL,
-            config_id INTEGER NOT NULL,
-            nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
-            PRIMARY KEY (id, nodebalancer_id)
-            );
-    ");
-    match node_table.await {
-        Ok(success) => println!("Node table available"),
-        Err(e) => println!("{:?}", e),
-        }
+impl MainDb {
+    pub fn new(target: PgTarget) -> Self {
+        MainDb { target }
+    }
 
+    pub async fn nodebalancers_in_region(&self, loc: &str) -> Result<Vec<LocalNodeBalancerListObject>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            // `account` is read through the row's JSON so that main DBs
+            // without the column still work.
+            "SELECT id, ipv4, region, lke_id, to_jsonb(nb) ->> 'account' AS account
+            FROM nodebalancer nb WHERE region = $1",
+            &[&loc],
+        ).await?;
 
-    let state_table  = connection.batch_execute("
-        CREATE TABLE IF NOT EXISTS state  (
-            id SERIAL PRIMARY KEY,
-            nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
-            nodebalancer_config_id INTEGER NOT NULL,
-            node_id INTEGER NOT NULL,
-            port INTEGER NOT NULL,
-            lastmode VARCHAR,
-            current VARCHAR
-            );
-    ");
-    match state_table.await {
-        Ok(success) => println!("State table available"),
-        Err(e) => println!("{:?}", e),
-        }
+        Ok(rows.iter().map(maindb_nodebalancer).collect::<Result<_, _>>()?)
+    }
+
+    /// Writes the report of one cycle. Writing it again for the same
+    /// location and cycle replaces it.
+    pub async fn record_report(&self, report: &DatacenterReport) -> Result<(), Error> {
+        let connection = self.target.connect().await?;
+        connection.batch_execute(DATACENTER_REPORT_TABLE_SQL).await?;
+        connection.execute(
+            "INSERT INTO datacenter_report
+                (location, synced_at, reported_at, nodebalancers, configs, nodes, up, down, errors, unhealthy)
+            VALUES ($1, $2, now(), $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (location, synced_at) DO UPDATE SET
+                reported_at = EXCLUDED.reported_at,
+                nodebalancers = EXCLUDED.nodebalancers,
+                configs = EXCLUDED.configs,
+                nodes = EXCLUDED.nodes,
+                up = EXCLUDED.up,
+                down = EXCLUDED.down,
+                errors = EXCLUDED.errors,
+                unhealthy = EXCLUDED.unhealthy",
+            &[
+                &report.location,
+                &report.synced_at,
+                &report.nodebalancers,
+                &report.configs,
+                &report.nodes,
+                &report.up,
+                &report.down,
+                &report.errors,
+                &Json(&report.unhealthy),
+            ],
+        ).await?;
 
-    Ok(())
+        Ok(())
+    }
+
+    /// Registers `instance` for its location, or moves its heartbeat on.
+    pub async fn heartbeat(&self, instance: &ClientInstance) -> Result<(), Error> {
+        let connection = self.target.connect().await?;
+        connection.batch_execute(CLIENT_INSTANCE_TABLE_SQL).await?;
+        connection.execute(
+            "INSERT INTO client_instance (instance_id, location, version, started_at, last_heartbeat)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (instance_id, location) DO UPDATE SET
+                version = EXCLUDED.version,
+                started_at = EXCLUDED.started_at,
+                last_heartbeat = EXCLUDED.last_heartbeat",
+            &[&instance.instance_id, &instance.location, &instance.version, &instance.started_at, &instance.last_heartbeat],
+        ).await?;
+
+        Ok(())
+    }
+
+    /// Every registered instance.
+    pub async fn instances(&self) -> Result<Vec<ClientInstance>, Error> {
+        let connection = self.target.connect().await?;
+        connection.batch_execute(CLIENT_INSTANCE_TABLE_SQL).await?;
+        let rows = connection.query(
+            "SELECT instance_id, location, version, started_at, last_heartbeat FROM client_instance ORDER BY location, instance_id",
+            &[],
+        ).await?;
 
+        Ok(from_rows(&rows)?)
+    }
 }
 
-pub async fn update_state(nbid: i32, nbcfgid: i32, nodeid: i32, port: i32, lastmode: String, current: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut connection = create_localdb_client().await;
-    let update = connection.execute(
-            "INSERT INTO state (nodebalancerid, nodebalancer_config_id, node_id, port, lastmode, current) VALUES ($1, $2, $3, $4, $5, $6)",
-            &[&nbid, &nbcfgid, &nodeid, &port, &lastmode.to_string(), &current.to_string()],
-    ).await;
-
-    match update {
-        Ok(success) => (),
-        Err(e) => {
-            if e.to_string().contains("duplicate key value violates unique constraint") {
-                ();
-            } else {
-                println!("{:?}", e);
-            }
-        }
+// Main DB tables are created on first use rather than at start, since the
+// main DB may be unreachable then.
+const CLIENT_INSTANCE_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS client_instance (
+        instance_id VARCHAR NOT NULL,
+        location VARCHAR NOT NULL,
+        version VARCHAR NOT NULL,
+        started_at TIMESTAMPTZ NOT NULL,
+        last_heartbeat TIMESTAMPTZ NOT NULL,
+        PRIMARY KEY (instance_id, location)
+    )";
+
+const DATACENTER_REPORT_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS datacenter_report (
+        location VARCHAR NOT NULL,
+        synced_at TIMESTAMPTZ NOT NULL,
+        reported_at TIMESTAMPTZ NOT NULL,
+        nodebalancers INTEGER NOT NULL,
+        configs INTEGER NOT NULL,
+        nodes INTEGER NOT NULL,
+        up INTEGER NOT NULL,
+        down INTEGER NOT NULL,
+        errors INTEGER NOT NULL,
+        unhealthy JSONB NOT NULL,
+        PRIMARY KEY (location, synced_at)
+    )";
+
+/// [`Store`] backed by the local Postgres database.
+#[derive(Clone, Debug)]
+pub struct PgStore {
+    target: PgTarget,
+}
+
+impl PgStore {
+    pub fn new(target: PgTarget) -> Self {
+        PgStore { target }
     }
+}
 
-    Ok(())
+// Enum-valued columns are nullable text limited to the known values by a
+// CHECK constraint. The constraints are recreated on every start so they
+// follow the values this build knows; NOT VALID leaves existing rows alone.
+fn enum_columns_sql() -> String {
+    let columns = [
+        ("nodebalancer_config", "algorithm", Algorithm::KNOWN),
+        ("nodebalancer_config", "protocol", Protocol::KNOWN),
+        ("nodebalancer_config", "check", CheckType::KNOWN),
+        ("nodebalancer_config", "stickiness", Stickiness::KNOWN),
+        ("nodebalancer_config", "cipher_suite", CipherSuite::KNOWN),
+        ("node", "status", NodeStatus::KNOWN),
+        ("node", "mode", NodeMode::KNOWN),
+        ("node", "probe_status", NodeStatus::KNOWN),
+        ("node_health", "lastverdict", Verdict::KNOWN),
+        ("node_health", "current", Verdict::KNOWN),
+        ("node_health", "observed", Verdict::KNOWN),
+        ("audit_event", "object", AuditObject::KNOWN),
+        ("audit_event", "action", AuditAction::KNOWN),
+    ];
+    let mut sql = String::new();
+    for (table, column, known) in columns {
+        sql.push_str(&format!("
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS \"{column}\" VARCHAR;
+            ALTER TABLE {table} ALTER COLUMN \"{column}\" DROP NOT NULL;
+            ALTER TABLE {table} DROP CONSTRAINT IF EXISTS {table}_{column}_check;
+            ALTER TABLE {table} ADD CONSTRAINT {table}_{column}_check {} NOT VALID;",
+            check_known(column, known)));
+    }
 
+    sql
+}
 
+// `xmax` is only zero on a freshly inserted tuple, which tells an insert
+// apart from the ON CONFLICT update in `RETURNING (xmax = 0) AS inserted`.
+fn count_upserts(counts: &mut WriteCounts, rows: &[Row]) {
+    for row in rows {
+        if row.get::<_, bool>("inserted") {
+            counts.inserted += 1;
+        } else {
+            counts.updated += 1;
+        }
+    }
 }
 
-pub async fn update_db_nb(nodebalancers: LocalNodeBalancerListObject) -> Result<(), Box<dyn std::error::Error>> {
-    let mut connection = create_localdb_client().await;
-    //println!("{:#?}", nodebalancers);
-
-    let update = connection.execute(
-            "INSERT INTO nodebalancer (id, ipv4, region, lke_id) VALUES ($1, $2, $3, $4)",
-            &[&nodebalancers.nb_id, &nodebalancers.ipv4, &nodebalancers.region, &nodebalancers.lke_id],
-    ).await;
-
-    match update {
-        Ok(success) => (),
-        Err(e) => {
-            if e.to_string().contains("duplicate key value violates unique constraint") {
-                ();
-            } else {
-                println!("{:?}", e);
+// Health check settings, for probing nodes locally, and the last probe of
+// each node.
+const PROBE_COLUMNS_SQL: &str = "
+    ALTER TABLE nodebalancer_config
+        ADD COLUMN IF NOT EXISTS check_path VARCHAR,
+        ADD COLUMN IF NOT EXISTS check_body VARCHAR,
+        ADD COLUMN IF NOT EXISTS check_interval INTEGER,
+        ADD COLUMN IF NOT EXISTS check_timeout INTEGER;
+    ALTER TABLE node
+        ADD COLUMN IF NOT EXISTS probe_latency_ms DOUBLE PRECISION,
+        ADD COLUMN IF NOT EXISTS probe_error VARCHAR,
+        ADD COLUMN IF NOT EXISTS probed_at TIMESTAMPTZ;
+";
+
+// What the API lists of each nodebalancer besides its addresses, and when
+// it was no longer listed.
+const NODEBALANCER_METADATA_SQL: &str = "
+    ALTER TABLE nodebalancer
+        ADD COLUMN IF NOT EXISTS label VARCHAR NOT NULL DEFAULT '',
+        ADD COLUMN IF NOT EXISTS hostname VARCHAR NOT NULL DEFAULT '',
+        ADD COLUMN IF NOT EXISTS ipv6 VARCHAR NOT NULL DEFAULT '',
+        ADD COLUMN IF NOT EXISTS client_conn_throttle INTEGER NOT NULL DEFAULT 0,
+        ADD COLUMN IF NOT EXISTS \"type\" VARCHAR NOT NULL DEFAULT '',
+        ADD COLUMN IF NOT EXISTS created VARCHAR NOT NULL DEFAULT '',
+        ADD COLUMN IF NOT EXISTS updated VARCHAR NOT NULL DEFAULT '',
+        ADD COLUMN IF NOT EXISTS lke_label VARCHAR,
+        ADD COLUMN IF NOT EXISTS lke_type VARCHAR,
+        ADD COLUMN IF NOT EXISTS lke_url VARCHAR,
+        ADD COLUMN IF NOT EXISTS removed_at TIMESTAMPTZ;
+";
+
+const NODEBALANCER_COLUMNS: &str = "id, ipv4, region, lke_id, label, hostname, ipv6, client_conn_throttle, \"type\",
+    created, updated, lke_label, lke_type, lke_url";
+
+// Nodes joined with the config and nodebalancer they belong to.
+const NODE_DETAIL_SELECT: &str = "
+    SELECT node.id, node.address, node.status, node.mode, node.config_id, node.nodebalancer_id,
+           node.probe_status, node.probe_latency_ms, node.probe_error, node.probed_at,
+           nodebalancer.ipv4, nodebalancer.region, nodebalancer.label AS nodebalancer_label,
+           nodebalancer_config.algorithm, nodebalancer_config.port, nodebalancer_config.up, nodebalancer_config.down
+    FROM node
+    JOIN nodebalancer ON node.nodebalancer_id = nodebalancer.id
+    JOIN nodebalancer_config ON nodebalancer_config.id = node.config_id
+        AND nodebalancer_config.nodebalancer_id = node.nodebalancer_id";
+
+// Nodes with the health check settings of their config.
+const PROBE_TARGET_SELECT: &str = "
+    SELECT node.nodebalancer_id, node.config_id, node.id AS node_id, node.address,
+           nodebalancer_config.protocol, nodebalancer_config.\"check\", nodebalancer_config.check_path, nodebalancer_config.check_body,
+           nodebalancer_config.check_interval, nodebalancer_config.check_timeout
+    FROM node
+    JOIN nodebalancer_config ON nodebalancer_config.id = node.config_id
+        AND nodebalancer_config.nodebalancer_id = node.nodebalancer_id";
+
+// The latest row of each node in the append-only `state` table.
+const NODE_STATE_SELECT: &str = "
+    SELECT nodebalancer_id, nodebalancer_config_id, node_id, port, lastmode, current
+    FROM state
+    WHERE id IN (SELECT max(id) FROM state GROUP BY nodebalancer_id, node_id)
+    ORDER BY nodebalancer_id, node_id";
+
+const AUDIT_EVENT_COLUMNS: &str = "at, object, action, nodebalancer_id, object_id, \"before\", \"after\"";
+
+#[async_trait]
+impl Store for PgStore {
+    async fn init(&self) -> Result<(), Error> {
+        let connection = self.target.connect().await?;
+        let main_table = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS nodebalancer (
+                id INTEGER NOT NULL,
+                ipv4 VARCHAR NOT NULL,
+                region VARCHAR NOT NULL,
+                lke_id INTEGER,
+                PRIMARY KEY (id)
+                );
+        ");
+
+        match main_table.await {
+            Ok(_) => println!("Nodebalancer table availabe"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        let nb_cfg_conn  = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS nodebalancer_config  (
+                id INTEGER NOT NULL,
+                algorithm VARCHAR NOT NULL,
+                port INTEGER NOT NULL,
+                up INTEGER NOT NULL,
+                down INTEGER NOT NULL,
+                nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+                PRIMARY KEY (id, nodebalancer_id)
+                );
+        ");
+        match nb_cfg_conn.await {
+            Ok(_) => println!("Nodebalancer config table availabe"),
+            Err(e) => println!("{:?}", e),
+            }
+
+
+        let node_table  = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS node  (
+                id INTEGER NOT NULL,
+                address VARCHAR NOT NULL,
+                status VARCHAR NOT NULL,
+                config_id INTEGER NOT NULL,
+                nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+                PRIMARY KEY (id, nodebalancer_id)
+                );
+        ");
+        match node_table.await {
+            Ok(_) => println!("Node table available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+
+        let state_table  = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS state  (
+                id SERIAL PRIMARY KEY,
+                nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+                nodebalancer_config_id INTEGER NOT NULL,
+                node_id INTEGER NOT NULL,
+                port INTEGER NOT NULL,
+                lastmode VARCHAR,
+                current VARCHAR
+                );
+        ");
+        match state_table.await {
+            Ok(_) => println!("State table available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        match connection.batch_execute(NODEBALANCER_METADATA_SQL).await {
+            Ok(_) => println!("Nodebalancer metadata columns available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        match connection.batch_execute(PROBE_COLUMNS_SQL).await {
+            Ok(_) => println!("Probe columns available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        let health_table = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS node_health (
+                nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+                node_id INTEGER NOT NULL,
+                config_id INTEGER NOT NULL,
+                port INTEGER NOT NULL,
+                address VARCHAR NOT NULL,
+                lastverdict VARCHAR,
+                current VARCHAR,
+                observed VARCHAR,
+                streak INTEGER NOT NULL,
+                since TIMESTAMPTZ NOT NULL,
+                probed_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (nodebalancer_id, node_id)
+                );
+        ");
+        match health_table.await {
+            Ok(_) => println!("Node health table available"),
+            Err(e) => println!("{:?}", e),
             }
+
+        let sample_table = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS probe_sample (
+                nodebalancer_id INTEGER NOT NULL,
+                node_id INTEGER NOT NULL,
+                probed_at TIMESTAMPTZ NOT NULL,
+                resolution INTEGER NOT NULL,
+                probes INTEGER NOT NULL,
+                up INTEGER NOT NULL,
+                latency_ms DOUBLE PRECISION,
+                connect_ms DOUBLE PRECISION,
+                tls_ms DOUBLE PRECISION,
+                ttfb_ms DOUBLE PRECISION,
+                http_status INTEGER,
+                PRIMARY KEY (nodebalancer_id, node_id, probed_at, resolution)
+                );
+        ");
+        match sample_table.await {
+            Ok(_) => println!("Probe sample table available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        let traffic_table = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS nodebalancer_traffic (
+                nodebalancer_id INTEGER NOT NULL,
+                at TIMESTAMPTZ NOT NULL,
+                connections DOUBLE PRECISION,
+                traffic_in DOUBLE PRECISION,
+                traffic_out DOUBLE PRECISION,
+                PRIMARY KEY (nodebalancer_id, at)
+                );
+        ");
+        match traffic_table.await {
+            Ok(_) => println!("Nodebalancer traffic table available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        let audit_table = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS audit_event (
+                id BIGSERIAL PRIMARY KEY,
+                at TIMESTAMPTZ NOT NULL,
+                object VARCHAR,
+                action VARCHAR,
+                nodebalancer_id INTEGER NOT NULL,
+                object_id INTEGER NOT NULL,
+                \"before\" JSONB,
+                \"after\" JSONB
+                );
+            CREATE INDEX IF NOT EXISTS audit_event_at ON audit_event (at);
+        ");
+        match audit_table.await {
+            Ok(_) => println!("Audit event table available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        let drift_table = connection.batch_execute("
+            CREATE TABLE IF NOT EXISTS config_drift (
+                nodebalancer_id INTEGER NOT NULL,
+                config_id INTEGER NOT NULL,
+                port INTEGER NOT NULL,
+                rule VARCHAR NOT NULL,
+                field VARCHAR NOT NULL,
+                expected VARCHAR NOT NULL,
+                actual VARCHAR NOT NULL,
+                since TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (nodebalancer_id, config_id, rule, field)
+                );
+        ");
+        match drift_table.await {
+            Ok(_) => println!("Config drift table available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        match connection.batch_execute(&enum_columns_sql()).await {
+            Ok(_) => println!("Enum columns available"),
+            Err(e) => println!("{:?}", e),
+            }
+
+        Ok(())
+    }
+
+    /// Configs and nodes are upserted as a single `UNNEST` batch each, inside
+    /// one transaction.
+    async fn write_nodebalancer(
+        &self,
+        nodebalancer: LocalNodeBalancerListObject,
+        configs: Vec<NodeBalancerConfigObject>,
+        nodes: Vec<NodeObject>,
+    ) -> Result<WriteCounts, Error> {
+        let mut connection = self.target.connect().await?;
+        let transaction = connection.transaction().await?;
+        let mut counts = WriteCounts::default();
+
+        // Rows without metadata, from the main DB, keep what is stored.
+        let metadata = match nodebalancer.has_metadata() {
+            true => ", label = EXCLUDED.label, hostname = EXCLUDED.hostname, ipv6 = EXCLUDED.ipv6,
+                 client_conn_throttle = EXCLUDED.client_conn_throttle, \"type\" = EXCLUDED.\"type\", created = EXCLUDED.created,
+                 updated = EXCLUDED.updated, lke_label = EXCLUDED.lke_label, lke_type = EXCLUDED.lke_type, lke_url = EXCLUDED.lke_url",
+            false => "",
+        };
+        let rows = transaction.query(
+                &format!(
+                    "INSERT INTO nodebalancer ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+                     ON CONFLICT (id) DO UPDATE SET ipv4 = EXCLUDED.ipv4, region = EXCLUDED.region, lke_id = EXCLUDED.lke_id, removed_at = NULL{}
+                     RETURNING (xmax = 0) AS inserted",
+                    NODEBALANCER_COLUMNS, metadata,
+                ),
+                &[
+                    &nodebalancer.nb_id, &nodebalancer.ipv4, &nodebalancer.region, &nodebalancer.lke_id,
+                    &nodebalancer.label, &nodebalancer.hostname, &nodebalancer.ipv6, &nodebalancer.client_conn_throttle, &nodebalancer.nb_type,
+                    &nodebalancer.created, &nodebalancer.updated, &nodebalancer.lke_label, &nodebalancer.lke_type, &nodebalancer.lke_url,
+                ],
+        ).await?;
+        count_upserts(&mut counts, &rows);
+
+        if !configs.is_empty() {
+            let ids: Vec<i32> = configs.iter().map(|c| c.id).collect();
+            let algorithms: Vec<Option<&str>> = configs.iter().map(|c| c.algorithm.known()).collect();
+            let ports: Vec<i32> = configs.iter().map(|c| c.port).collect();
+            let ups: Vec<i32> = configs.iter().map(|c| c.nodes_status.up).collect();
+            let downs: Vec<i32> = configs.iter().map(|c| c.nodes_status.down).collect();
+            let nb_ids: Vec<i32> = configs.iter().map(|c| c.nodebalancer_id).collect();
+            let protocols: Vec<Option<&str>> = configs.iter().map(|c| c.protocol.known()).collect();
+            let checks: Vec<Option<&str>> = configs.iter().map(|c| c.check.known()).collect();
+            let stickinesses: Vec<Option<&str>> = configs.iter().map(|c| c.stickiness.known()).collect();
+            let cipher_suites: Vec<Option<&str>> = configs.iter().map(|c| c.cipher_suite.known()).collect();
+            let check_paths: Vec<&str> = configs.iter().map(|c| c.check_path.as_str()).collect();
+            let check_bodies: Vec<&str> = configs.iter().map(|c| c.check_body.as_str()).collect();
+            let check_intervals: Vec<i32> = configs.iter().map(|c| c.check_interval).collect();
+            let check_timeouts: Vec<i32> = configs.iter().map(|c| c.check_timeout).collect();
+            let rows = transaction.query(
+                    "INSERT INTO nodebalancer_config (id, algorithm, port, up, down, nodebalancer_id, protocol, \"check\", stickiness, cipher_suite,
+                         check_path, check_body, check_interval, check_timeout)
+                     SELECT * FROM UNNEST($1::INTEGER[], $2::VARCHAR[], $3::INTEGER[], $4::INTEGER[], $5::INTEGER[], $6::INTEGER[], $7::VARCHAR[], $8::VARCHAR[], $9::VARCHAR[], $10::VARCHAR[],
+                         $11::VARCHAR[], $12::VARCHAR[], $13::INTEGER[], $14::INTEGER[])
+                     ON CONFLICT (id, nodebalancer_id) DO UPDATE SET algorithm = EXCLUDED.algorithm, port = EXCLUDED.port, up = EXCLUDED.up, down = EXCLUDED.down,
+                         protocol = EXCLUDED.protocol, \"check\" = EXCLUDED.\"check\", stickiness = EXCLUDED.stickiness, cipher_suite = EXCLUDED.cipher_suite,
+                         check_path = EXCLUDED.check_path, check_body = EXCLUDED.check_body, check_interval = EXCLUDED.check_interval, check_timeout = EXCLUDED.check_timeout
+                     RETURNING (xmax = 0) AS inserted",
+                    &[&ids, &algorithms, &ports, &ups, &downs, &nb_ids, &protocols, &checks, &stickinesses, &cipher_suites,
+                      &check_paths, &check_bodies, &check_intervals, &check_timeouts],
+            ).await?;
+            count_upserts(&mut counts, &rows);
         }
+
+        if !nodes.is_empty() {
+            let ids: Vec<i32> = nodes.iter().map(|n| n.id).collect();
+            let addresses: Vec<&str> = nodes.iter().map(|n| n.address.as_str()).collect();
+            let statuses: Vec<Option<&str>> = nodes.iter().map(|n| n.status.known()).collect();
+            let config_ids: Vec<i32> = nodes.iter().map(|n| n.config_id).collect();
+            let nb_ids: Vec<i32> = nodes.iter().map(|n| n.nodebalancer_id).collect();
+            let modes: Vec<Option<&str>> = nodes.iter().map(|n| n.mode.known()).collect();
+            let rows = transaction.query(
+                    "INSERT INTO node (id, address, status, config_id, nodebalancer_id, mode)
+                     SELECT * FROM UNNEST($1::INTEGER[], $2::VARCHAR[], $3::VARCHAR[], $4::INTEGER[], $5::INTEGER[], $6::VARCHAR[])
+                     ON CONFLICT (id, nodebalancer_id) DO UPDATE SET address = EXCLUDED.address, status = EXCLUDED.status, config_id = EXCLUDED.config_id, mode = EXCLUDED.mode
+                     RETURNING (xmax = 0) AS inserted",
+                    &[&ids, &addresses, &statuses, &config_ids, &nb_ids, &modes],
+            ).await?;
+            count_upserts(&mut counts, &rows);
+        }
+
+        let config_ids: Vec<i32> = configs.iter().map(|c| c.id).collect();
+        let node_ids: Vec<i32> = nodes.iter().map(|n| n.id).collect();
+        counts.deleted += transaction.execute(
+            "DELETE FROM node WHERE nodebalancer_id = $1 AND NOT (id = ANY($2))", &[&nodebalancer.nb_id, &node_ids],
+        ).await?;
+        counts.deleted += transaction.execute(
+            "DELETE FROM nodebalancer_config WHERE nodebalancer_id = $1 AND NOT (id = ANY($2))", &[&nodebalancer.nb_id, &config_ids],
+        ).await?;
+        transaction.execute(
+            "DELETE FROM node_health WHERE nodebalancer_id = $1 AND NOT (node_id = ANY($2))", &[&nodebalancer.nb_id, &node_ids],
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(counts)
     }
 
-    Ok(())
+    async fn remove_nodebalancers(&self, nodebalancer_ids: Vec<i32>) -> Result<WriteCounts, Error> {
+        if nodebalancer_ids.is_empty() {
+            return Ok(WriteCounts::default());
+        }
+        let mut connection = self.target.connect().await?;
+        let transaction = connection.transaction().await?;
+        let mut counts = WriteCounts::default();
 
-}
+        // Only nodebalancers that were not removed already.
+        let rows = transaction.query(
+            "UPDATE nodebalancer SET removed_at = $2 WHERE id = ANY($1) AND removed_at IS NULL RETURNING id",
+            &[&nodebalancer_ids, &Utc::now()],
+        ).await?;
+        let removed: Vec<i32> = rows.iter().map(|row| row.try_get("id")).collect::<Result<_, _>>()?;
+        counts.deleted += removed.len() as u64;
+        counts.deleted += transaction.execute("DELETE FROM node WHERE nodebalancer_id = ANY($1)", &[&removed]).await?;
+        counts.deleted += transaction.execute("DELETE FROM nodebalancer_config WHERE nodebalancer_id = ANY($1)", &[&removed]).await?;
+        transaction.execute("DELETE FROM node_health WHERE nodebalancer_id = ANY($1)", &[&removed]).await?;
+        transaction.execute("DELETE FROM config_drift WHERE nodebalancer_id = ANY($1)", &[&removed]).await?;
+        transaction.commit().await?;
 
-pub async fn get_nb_ids() -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "SELECT id FROM nodebalancer", &[],
-    ).await;
+        Ok(counts)
+    }
 
-    Ok(nb_table?)
+    async fn nodebalancer_ids(&self) -> Result<Vec<i32>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            "SELECT id FROM nodebalancer WHERE removed_at IS NULL", &[],
+        ).await?;
 
-}
+        Ok(rows.iter().map(|row| row.try_get("id")).collect::<Result<_, _>>()?)
+    }
 
-pub async fn get_nb_by_loc(loc: String) -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_maindb_client().await;
-    let nb_table = node_connection.query(
-        "SELECT * FROM nodebalancer where region = $1", &[&loc],
-    ).await;
+    async fn nodebalancers(&self) -> Result<Vec<LocalNodeBalancerListObject>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            &format!("SELECT {} FROM nodebalancer WHERE removed_at IS NULL ORDER BY id", NODEBALANCER_COLUMNS), &[],
+        ).await?;
 
-    Ok(nb_table?)
+        Ok(from_rows(&rows)?)
+    }
 
-}
+    async fn configs(&self) -> Result<Vec<LocalNodeBalancerConfigObject>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            "SELECT id, nodebalancer_id, algorithm, port, up, down FROM nodebalancer_config", &[],
+        ).await?;
 
-pub async fn get_nbcfg_ids() -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "SELECT id, nodebalancer_id FROM nodebalancer_config", &[],
-    ).await;
+        Ok(from_rows(&rows)?)
+    }
 
-    Ok(nb_table?)
+    async fn node_details(&self) -> Result<Vec<NodeDetailObject>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            &format!("{} ORDER BY node.nodebalancer_id, node.config_id, node.id", NODE_DETAIL_SELECT), &[],
+        ).await?;
 
-}
+        Ok(from_rows(&rows)?)
+    }
 
-pub async fn get_by_node_ip_nbcfg(ip: String) -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "select * from node where address like '%$1%' INNER JOIN nodebalancer_config ON node.config_id = nodebalancer_config.id;", &[&ip],
-    ).await;
+    async fn nodes_by_address(&self, ip: &str) -> Result<Vec<NodeDetailObject>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            &format!("{} WHERE node.address LIKE '%' || $1 || '%'", NODE_DETAIL_SELECT), &[&ip],
+        ).await?;
 
-    Ok(nb_table?)
+        Ok(from_rows(&rows)?)
+    }
 
-}
+    async fn probe_targets(&self) -> Result<Vec<ProbeTarget>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(PROBE_TARGET_SELECT, &[]).await?;
 
-pub async fn get_by_node_ip_nb(ip: String) -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "select * from node INNER JOIN nodebalancer ON node.nodebalancer_id = nodebalancer.id where address like '%$1%';", &[&ip],
-    ).await;
+        Ok(from_rows(&rows)?)
+    }
 
-    Ok(nb_table?)
+    async fn record_probes(&self, probes: Vec<NodeProbe>) -> Result<(), Error> {
+        if probes.is_empty() {
+            return Ok(());
+        }
+        let nb_ids: Vec<i32> = probes.iter().map(|p| p.nodebalancer_id).collect();
+        let node_ids: Vec<i32> = probes.iter().map(|p| p.node_id).collect();
+        let statuses: Vec<Option<&str>> = probes.iter().map(|p| p.probe.status.known()).collect();
+        let latencies: Vec<Option<f64>> = probes.iter().map(|p| p.probe.latency_ms).collect();
+        let errors: Vec<Option<&str>> = probes.iter().map(|p| p.probe.error.as_deref()).collect();
+        let probed_at: Vec<_> = probes.iter().map(|p| p.probe.probed_at).collect();
 
-}
+        let connect_ms: Vec<Option<f64>> = probes.iter().map(|p| p.timings.connect_ms).collect();
+        let tls_ms: Vec<Option<f64>> = probes.iter().map(|p| p.timings.tls_ms).collect();
+        let ttfb_ms: Vec<Option<f64>> = probes.iter().map(|p| p.timings.ttfb_ms).collect();
+        let http_statuses: Vec<Option<i32>> = probes.iter().map(|p| p.timings.http_status).collect();
 
-pub async fn update_db_node(node: NodeObject) -> Result<(), Box<dyn std::error::Error>> {
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.execute(
-            "INSERT INTO node (id, address, status, config_id, nodebalancer_id) VALUES ($1, $2, $3, $4, $5)",
-            &[&node.id, &node.address, &node.status, &node.config_id, &node.nodebalancer_id],
-    ).await;
+        let mut connection = self.target.connect().await?;
+        let transaction = connection.transaction().await?;
+        transaction.execute(
+                "UPDATE node SET probe_status = p.status, probe_latency_ms = p.latency_ms, probe_error = p.error, probed_at = p.probed_at
+                 FROM UNNEST($1::INTEGER[], $2::INTEGER[], $3::VARCHAR[], $4::DOUBLE PRECISION[], $5::VARCHAR[], $6::TIMESTAMPTZ[])
+                     AS p(nodebalancer_id, id, status, latency_ms, error, probed_at)
+                 WHERE node.nodebalancer_id = p.nodebalancer_id AND node.id = p.id",
+                &[&nb_ids, &node_ids, &statuses, &latencies, &errors, &probed_at],
+        ).await?;
+        transaction.execute(
+                "INSERT INTO probe_sample (nodebalancer_id, node_id, probed_at, resolution, probes, up, latency_ms, connect_ms, tls_ms, ttfb_ms, http_status)
+                 SELECT p.nodebalancer_id, p.node_id, p.probed_at, 0, 1, (p.status = 'UP')::INTEGER, p.latency_ms, p.connect_ms, p.tls_ms, p.ttfb_ms, p.http_status
+                 FROM UNNEST($1::INTEGER[], $2::INTEGER[], $3::VARCHAR[], $4::DOUBLE PRECISION[], $5::TIMESTAMPTZ[],
+                     $6::DOUBLE PRECISION[], $7::DOUBLE PRECISION[], $8::DOUBLE PRECISION[], $9::INTEGER[])
+                     AS p(nodebalancer_id, node_id, status, latency_ms, probed_at, connect_ms, tls_ms, ttfb_ms, http_status)
+                 JOIN node ON node.nodebalancer_id = p.nodebalancer_id AND node.id = p.node_id
+                 ON CONFLICT DO NOTHING",
+                &[&nb_ids, &node_ids, &statuses, &latencies, &probed_at, &connect_ms, &tls_ms, &ttfb_ms, &http_statuses],
+        ).await?;
+        transaction.commit().await?;
 
-    Ok(())
-}
+        Ok(())
+    }
 
-pub async fn update_db_config(nodebalancer_config: NodeBalancerConfigObject) -> Result<(), Box<dyn std::error::Error>> {
-    let mut config_connection = create_localdb_client().await;
-    //println!("{:#?}", nodebalancer_config);
-
-    let nb_cfg_table = config_connection.execute(
-            "INSERT INTO nodebalancer_config (id, algorithm, port, up, down, nodebalancer_id) VALUES ($1, $2, $3, $4, $5, $6)",
-            &[&nodebalancer_config.id, &nodebalancer_config.algorithm, &nodebalancer_config.port, &nodebalancer_config.nodes_status.up, &nodebalancer_config.nodes_status.down, &nodebalancer_config.nodebalancer_id],
-    ).await;
-
-    match nb_cfg_table {
-        //Ok(success) => println!("Nodebalancer config row updated"),
-        Ok(success) => (),
-        Err(e) => {
-            if e.to_string().contains("duplicate key value violates unique constraint") {
-                ();
-                //println!("Config already in DB: {} - Node: {}", &nodebalancer_config.id, &nodebalancer_config.nodebalancer_id);
-            } else {
-                println!("{:?}", e);
-            }
+    async fn probe_samples(&self, since: DateTime<Utc>) -> Result<Vec<ProbeSample>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            "SELECT nodebalancer_id, node_id, probed_at, resolution, probes, up, latency_ms, connect_ms, tls_ms, ttfb_ms, http_status
+             FROM probe_sample WHERE probed_at >= $1 ORDER BY nodebalancer_id, node_id, probed_at, resolution", &[&since],
+        ).await?;
+
+        Ok(from_rows(&rows)?)
+    }
+
+    /// Downsamples in SQL with the same rules as [`crate::latency::downsample`].
+    async fn compact_probe_samples(
+        &self,
+        raw_before: DateTime<Utc>,
+        resolution: i32,
+        expire_before: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut connection = self.target.connect().await?;
+        let transaction = connection.transaction().await?;
+        transaction.execute("DELETE FROM probe_sample WHERE probed_at < $1", &[&expire_before]).await?;
+        transaction.execute(
+                "WITH raw AS (
+                     DELETE FROM probe_sample WHERE resolution = 0 AND probed_at < $1 RETURNING *
+                 )
+                 INSERT INTO probe_sample (nodebalancer_id, node_id, probed_at, resolution, probes, up, latency_ms, connect_ms, tls_ms, ttfb_ms, http_status)
+                 SELECT nodebalancer_id, node_id, to_timestamp(floor(extract(epoch FROM probed_at) / $2::INTEGER) * $2::INTEGER), $2::INTEGER,
+                     sum(probes), sum(up), avg(latency_ms), avg(connect_ms), avg(tls_ms), avg(ttfb_ms), max(http_status)
+                 FROM raw GROUP BY 1, 2, 3
+                 ON CONFLICT DO NOTHING",
+                &[&raw_before, &resolution],
+        ).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn record_traffic(&self, samples: Vec<TrafficSample>, expire_before: DateTime<Utc>) -> Result<(), Error> {
+        let nb_ids: Vec<i32> = samples.iter().map(|s| s.nodebalancer_id).collect();
+        let at: Vec<_> = samples.iter().map(|s| s.at).collect();
+        let connections: Vec<Option<f64>> = samples.iter().map(|s| s.connections).collect();
+        let traffic_in: Vec<Option<f64>> = samples.iter().map(|s| s.traffic_in).collect();
+        let traffic_out: Vec<Option<f64>> = samples.iter().map(|s| s.traffic_out).collect();
+
+        let mut connection = self.target.connect().await?;
+        let transaction = connection.transaction().await?;
+        transaction.execute(
+                "INSERT INTO nodebalancer_traffic (nodebalancer_id, at, connections, traffic_in, traffic_out)
+                 SELECT * FROM UNNEST($1::INTEGER[], $2::TIMESTAMPTZ[], $3::DOUBLE PRECISION[], $4::DOUBLE PRECISION[], $5::DOUBLE PRECISION[])
+                 ON CONFLICT (nodebalancer_id, at) DO UPDATE SET connections = EXCLUDED.connections,
+                     traffic_in = EXCLUDED.traffic_in, traffic_out = EXCLUDED.traffic_out",
+                &[&nb_ids, &at, &connections, &traffic_in, &traffic_out],
+        ).await?;
+        transaction.execute("DELETE FROM nodebalancer_traffic WHERE at < $1", &[&expire_before]).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn traffic_samples(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<TrafficSample>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            "SELECT nodebalancer_id, at, connections, traffic_in, traffic_out FROM nodebalancer_traffic
+             WHERE at >= $1 AND ($2::INTEGER IS NULL OR nodebalancer_id = $2) ORDER BY nodebalancer_id, at",
+            &[&since, &nodebalancer_id],
+        ).await?;
+
+        Ok(from_rows(&rows)?)
+    }
+
+    async fn node_health(&self) -> Result<Vec<NodeHealth>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            "SELECT nodebalancer_id, config_id, node_id, port, address, lastverdict, current, observed, streak, since, probed_at FROM node_health", &[],
+        ).await?;
+
+        Ok(from_rows(&rows)?)
+    }
+
+    async fn record_node_health(&self, rows: Vec<NodeHealth>) -> Result<(), Error> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let nb_ids: Vec<i32> = rows.iter().map(|h| h.nodebalancer_id).collect();
+        let node_ids: Vec<i32> = rows.iter().map(|h| h.node_id).collect();
+        let config_ids: Vec<i32> = rows.iter().map(|h| h.config_id).collect();
+        let ports: Vec<i32> = rows.iter().map(|h| h.port).collect();
+        let addresses: Vec<&str> = rows.iter().map(|h| h.address.as_str()).collect();
+        let lastverdicts: Vec<Option<&str>> = rows.iter().map(|h| h.lastverdict.known()).collect();
+        let currents: Vec<Option<&str>> = rows.iter().map(|h| h.current.known()).collect();
+        let observed: Vec<Option<&str>> = rows.iter().map(|h| h.observed.known()).collect();
+        let streaks: Vec<i32> = rows.iter().map(|h| h.streak).collect();
+        let since: Vec<_> = rows.iter().map(|h| h.since).collect();
+        let probed_at: Vec<_> = rows.iter().map(|h| h.probed_at).collect();
+
+        let connection = self.target.connect().await?;
+        connection.execute(
+                "INSERT INTO node_health (nodebalancer_id, node_id, config_id, port, address, lastverdict, current, observed, streak, since, probed_at)
+                 SELECT * FROM UNNEST($1::INTEGER[], $2::INTEGER[], $3::INTEGER[], $4::INTEGER[], $5::VARCHAR[], $6::VARCHAR[], $7::VARCHAR[], $8::VARCHAR[],
+                     $9::INTEGER[], $10::TIMESTAMPTZ[], $11::TIMESTAMPTZ[])
+                 ON CONFLICT (nodebalancer_id, node_id) DO UPDATE SET config_id = EXCLUDED.config_id, port = EXCLUDED.port, address = EXCLUDED.address,
+                     lastverdict = EXCLUDED.lastverdict, current = EXCLUDED.current, observed = EXCLUDED.observed, streak = EXCLUDED.streak,
+                     since = EXCLUDED.since, probed_at = EXCLUDED.probed_at",
+                &[&nb_ids, &node_ids, &config_ids, &ports, &addresses, &lastverdicts, &currents, &observed, &streaks, &since, &probed_at],
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn node_states(&self) -> Result<Vec<NodeState>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(NODE_STATE_SELECT, &[]).await?;
+
+        Ok(from_rows(&rows)?)
+    }
+
+    async fn update_state(&self, nbid: i32, nbcfgid: i32, nodeid: i32, port: i32, lastmode: &str, current: &str) -> Result<(), Error> {
+        let connection = self.target.connect().await?;
+        connection.execute(
+                "INSERT INTO state (nodebalancer_id, nodebalancer_config_id, node_id, port, lastmode, current) VALUES ($1, $2, $3, $4, $5, $6)",
+                &[&nbid, &nbcfgid, &nodeid, &port, &lastmode, &current],
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn record_audit(&self, events: Vec<AuditEvent>) -> Result<(), Error> {
+        if events.is_empty() {
+            return Ok(());
         }
+        let at: Vec<_> = events.iter().map(|e| e.at).collect();
+        let objects: Vec<Option<&str>> = events.iter().map(|e| e.object.known()).collect();
+        let actions: Vec<Option<&str>> = events.iter().map(|e| e.action.known()).collect();
+        let nb_ids: Vec<i32> = events.iter().map(|e| e.nodebalancer_id).collect();
+        let object_ids: Vec<i32> = events.iter().map(|e| e.object_id).collect();
+        let befores: Vec<Option<&serde_json::Value>> = events.iter().map(|e| e.before.as_ref()).collect();
+        let afters: Vec<Option<&serde_json::Value>> = events.iter().map(|e| e.after.as_ref()).collect();
+
+        // WITH ORDINALITY keeps the ids in the order of the batch.
+        let connection = self.target.connect().await?;
+        connection.execute(
+                &format!("INSERT INTO audit_event ({})
+                 SELECT at, object, action, nodebalancer_id, object_id, b, a
+                 FROM UNNEST($1::TIMESTAMPTZ[], $2::VARCHAR[], $3::VARCHAR[], $4::INTEGER[], $5::INTEGER[], $6::JSONB[], $7::JSONB[])
+                     WITH ORDINALITY AS e(at, object, action, nodebalancer_id, object_id, b, a, n)
+                 ORDER BY n", AUDIT_EVENT_COLUMNS),
+                &[&at, &objects, &actions, &nb_ids, &object_ids, &befores, &afters],
+        ).await?;
+
+        Ok(())
     }
 
-    Ok(())
+    async fn audit_events(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<AuditEvent>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            &format!("SELECT {} FROM audit_event WHERE at >= $1 AND ($2::INTEGER IS NULL OR nodebalancer_id = $2) ORDER BY id", AUDIT_EVENT_COLUMNS),
+            &[&since, &nodebalancer_id],
+        ).await?;
 
+        Ok(from_rows(&rows)?)
+    }
 
+    async fn audited_objects(&self) -> Result<Vec<AuditEvent>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            &format!("SELECT {} FROM audit_event
+             WHERE id IN (SELECT max(id) FROM audit_event WHERE action <> 'remediated' GROUP BY object, nodebalancer_id, object_id)
+                 AND \"after\" IS NOT NULL
+             ORDER BY id", AUDIT_EVENT_COLUMNS), &[],
+        ).await?;
+
+        Ok(from_rows(&rows)?)
+    }
+
+    async fn config_drift(&self) -> Result<Vec<DriftViolation>, Error> {
+        let node_connection = self.target.connect().await?;
+        let rows = node_connection.query(
+            "SELECT nodebalancer_id, config_id, port, rule, field, expected, actual, since FROM config_drift
+             ORDER BY nodebalancer_id, config_id, rule, field", &[],
+        ).await?;
+
+        Ok(from_rows(&rows)?)
+    }
+
+    async fn record_config_drift(&self, nodebalancer_ids: Vec<i32>, violations: Vec<DriftViolation>) -> Result<(), Error> {
+        let nb_ids: Vec<i32> = violations.iter().map(|v| v.nodebalancer_id).collect();
+        let config_ids: Vec<i32> = violations.iter().map(|v| v.config_id).collect();
+        let ports: Vec<i32> = violations.iter().map(|v| v.port).collect();
+        let rules: Vec<&str> = violations.iter().map(|v| v.rule.as_str()).collect();
+        let fields: Vec<&str> = violations.iter().map(|v| v.field.as_str()).collect();
+        let expected: Vec<&str> = violations.iter().map(|v| v.expected.as_str()).collect();
+        let actual: Vec<&str> = violations.iter().map(|v| v.actual.as_str()).collect();
+        let since: Vec<_> = violations.iter().map(|v| v.since).collect();
+
+        let mut connection = self.target.connect().await?;
+        let transaction = connection.transaction().await?;
+        transaction.execute("DELETE FROM config_drift WHERE nodebalancer_id = ANY($1)", &[&nodebalancer_ids]).await?;
+        transaction.execute(
+                "INSERT INTO config_drift (nodebalancer_id, config_id, port, rule, field, expected, actual, since)
+                 SELECT * FROM UNNEST($1::INTEGER[], $2::INTEGER[], $3::INTEGER[], $4::VARCHAR[], $5::VARCHAR[], $6::VARCHAR[], $7::VARCHAR[], $8::TIMESTAMPTZ[])",
+                &[&nb_ids, &config_ids, &ports, &rules, &fields, &expected, &actual, &since],
+        ).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
 }
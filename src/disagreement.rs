@@ -0,0 +1,158 @@
+//! Catches NodeBalancer health-check blind spots: nodes that Linode reports
+//! UP while the local probe fails, or the reverse, probe after probe.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use crate::error::Error;
+use crate::models::{NodeDetailObject, NodeHealth, NodeStatus, Verdict};
+use crate::store::Store;
+use crate::webhook::Webhook;
+
+/// What Linode and the probe say about a node, `Agree` unless one is sure
+/// the node is up and the other is sure it is down.
+pub fn verdict(linode: &NodeStatus, probe: &NodeStatus) -> Verdict {
+    match (linode, probe) {
+        (NodeStatus::Up, NodeStatus::Down) => Verdict::LinodeUpProbeDown,
+        (NodeStatus::Down, NodeStatus::Up) => Verdict::LinodeDownProbeUp,
+        _ => Verdict::Agree,
+    }
+}
+
+/// A node whose verdict changed, in either direction.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub health: NodeHealth,
+    /// Why the latest probe failed, if it did.
+    pub probe_error: Option<String>,
+}
+
+impl Alert {
+    /// Whether the node went back to agreeing.
+    pub fn resolved(&self) -> bool {
+        self.health.current == Verdict::Agree
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let h = &self.health;
+        write!(f, "NB {} config {} port {} node {} ({}): ", h.nodebalancer_id, h.config_id, h.port, h.node_id, h.address)?;
+        match h.current {
+            Verdict::LinodeUpProbeDown => write!(f, "Linode reports UP but the local probe fails")?,
+            Verdict::LinodeDownProbeUp => write!(f, "Linode reports DOWN but the local probe passes")?,
+            _ => write!(f, "Linode and the local probe agree again, was {}", h.lastverdict)?,
+        }
+        if let Some(error) = &self.probe_error {
+            write!(f, " ({})", error)?;
+        }
+        write!(f, " for {} consecutive probes", h.streak)
+    }
+}
+
+/// Outcome of one detection pass.
+#[derive(Debug, Default, Clone)]
+pub struct Detection {
+    pub alerts: Vec<Alert>,
+    /// Nodes currently in disagreement, whether or not they just changed.
+    pub disagreeing: u64,
+}
+
+/// Compares every freshly probed node with Linode's status and changes its
+/// verdict once the new one has held for `threshold` consecutive probes, in
+/// either direction, so a flapping node does not alert on every probe.
+#[derive(Debug, Clone)]
+pub struct DisagreementDetector {
+    threshold: i32,
+    webhook: Option<Webhook>,
+}
+
+impl DisagreementDetector {
+    pub fn new(threshold: u32) -> Self {
+        DisagreementDetector {
+            threshold: threshold.clamp(1, i32::MAX as u32) as i32,
+            webhook: None,
+        }
+    }
+
+    /// URL every alert is `POST`ed to as JSON, with a Slack-style `text`.
+    pub fn webhook(mut self, url: Option<String>) -> Self {
+        self.webhook = url.as_deref().map(Webhook::new);
+        self
+    }
+
+    /// Folds the latest probes of `nodes` into their `previous` tracking
+    /// rows. Returns the rows that changed and the alerts they raised.
+    /// Nodes without a probe, or whose probe was already counted, are left
+    /// alone.
+    pub fn evaluate(&self, nodes: &[NodeDetailObject], previous: &[NodeHealth]) -> (Vec<NodeHealth>, Vec<Alert>) {
+        let previous: HashMap<(i32, i32), &NodeHealth> = previous.iter()
+            .map(|h| ((h.nodebalancer_id, h.node_id), h))
+            .collect();
+        let mut rows = Vec::new();
+        let mut alerts = Vec::new();
+
+        for node in nodes {
+            let Some(probe) = &node.probe else { continue };
+            let before = previous.get(&(node.nodebalancer_id, node.id));
+            if before.is_some_and(|b| b.probed_at >= probe.probed_at) {
+                continue;
+            }
+
+            let observed = verdict(&node.status, &probe.status);
+            let (lastverdict, current, since, streak) = match before {
+                Some(b) if b.observed == observed => (b.lastverdict.clone(), b.current.clone(), b.since, b.streak + 1),
+                Some(b) => (b.lastverdict.clone(), b.current.clone(), b.since, 1),
+                None => (Verdict::Agree, Verdict::Agree, probe.probed_at, 1),
+            };
+            let mut health = NodeHealth {
+                nodebalancer_id: node.nodebalancer_id,
+                config_id: node.config_id,
+                node_id: node.id,
+                port: node.port,
+                address: node.address.clone(),
+                lastverdict,
+                current,
+                observed,
+                streak,
+                since,
+                probed_at: probe.probed_at,
+            };
+
+            if health.observed != health.current && health.streak >= self.threshold {
+                health.lastverdict = std::mem::replace(&mut health.current, health.observed.clone());
+                health.since = probe.probed_at;
+                alerts.push(Alert { health: health.clone(), probe_error: probe.error.clone() });
+            }
+            rows.push(health);
+        }
+
+        (rows, alerts)
+    }
+
+    /// Evaluates every stored node, records the result and sends alerts in
+    /// the background.
+    pub async fn run(&self, store: &dyn Store) -> Result<Detection, Error> {
+        let nodes = store.node_details().await?;
+        let previous = store.node_health().await?;
+        let (rows, alerts) = self.evaluate(&nodes, &previous);
+
+        let mut current: HashMap<(i32, i32), Verdict> = previous.into_iter()
+            .map(|h| ((h.nodebalancer_id, h.node_id), h.current))
+            .collect();
+        for h in &rows {
+            current.insert((h.nodebalancer_id, h.node_id), h.current.clone());
+        }
+        let disagreeing = current.values().filter(|v| **v != Verdict::Agree).count() as u64;
+
+        store.record_node_health(rows).await?;
+        for alert in &alerts {
+            println!("ALERT: {}", alert);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.send_in_background(alerts.clone());
+        }
+
+        Ok(Detection { alerts, disagreeing })
+    }
+}
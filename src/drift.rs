@@ -0,0 +1,334 @@
+//! Config drift: compares every synced config with a YAML policy declaring
+//! how configs should be set up, keeps the violations in the `config_drift`
+//! table and alerts when they appear or are fixed.
+//!
+//! ```yaml
+//! rules:
+//!   - name: tls-frontends-are-checked
+//!     match:
+//!       label: "prod-*"
+//!       lke_cluster: true
+//!       port: 443
+//!     expect:
+//!       check: [connection, http, http_body]
+//!       check_attempts: { min: 3 }
+//!       algorithm: roundrobin
+//! ```
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use crate::error::Error;
+use crate::models::{
+    Algorithm,
+    CheckType,
+    DriftViolation,
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    Protocol,
+    Stickiness,
+};
+use crate::store::Store;
+use crate::webhook::Webhook;
+
+/// A value, or a list of allowed values.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum OneOf<T> {
+    One(T),
+    Any(Vec<T>),
+}
+
+impl<T: PartialEq + fmt::Display> OneOf<T> {
+    pub fn allows(&self, value: &T) -> bool {
+        match self {
+            OneOf::One(allowed) => allowed == value,
+            OneOf::Any(allowed) => allowed.contains(value),
+        }
+    }
+
+    fn expected(&self) -> String {
+        match self {
+            OneOf::One(allowed) => allowed.to_string(),
+            OneOf::Any(allowed) => {
+                let allowed: Vec<String> = allowed.iter().map(T::to_string).collect();
+                format!("one of {}", allowed.join(", "))
+            }
+        }
+    }
+}
+
+/// Inclusive bounds on a number of seconds or attempts.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bounds {
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+}
+
+impl Bounds {
+    pub fn allows(&self, value: i32) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+
+    fn expected(&self) -> String {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => format!("between {} and {}", min, max),
+            (Some(min), None) => format!("at least {}", min),
+            (None, Some(max)) => format!("at most {}", max),
+            (None, None) => "anything".to_string(),
+        }
+    }
+}
+
+/// `true` for nodebalancers of any LKE cluster, `false` for the others, or
+/// the id of one cluster.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum LkeMatch {
+    Any(bool),
+    Id(i32),
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of
+/// characters.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else { return false };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else { return rest.is_empty() };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Which configs a rule applies to. Every condition that is set must hold.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Match {
+    /// Pattern for the nodebalancer label, see [`glob`].
+    pub label: Option<String>,
+    pub lke_cluster: Option<LkeMatch>,
+    pub port: Option<i32>,
+}
+
+impl Match {
+    pub fn matches(&self, nodebalancer: &LocalNodeBalancerListObject, config: &NodeBalancerConfigObject) -> bool {
+        self.label.as_ref().is_none_or(|pattern| glob(pattern, &nodebalancer.label))
+            && self.port.is_none_or(|port| port == config.port)
+            && match self.lke_cluster {
+                None => true,
+                Some(LkeMatch::Any(any)) => nodebalancer.lke_id.is_some() == any,
+                Some(LkeMatch::Id(id)) => nodebalancer.lke_id == Some(id),
+            }
+    }
+}
+
+/// The settings a rule requires. Settings left out are not checked.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    pub algorithm: Option<OneOf<Algorithm>>,
+    pub protocol: Option<OneOf<Protocol>>,
+    pub stickiness: Option<OneOf<Stickiness>>,
+    pub check: Option<OneOf<CheckType>>,
+    pub check_path: Option<OneOf<String>>,
+    pub check_interval: Option<Bounds>,
+    pub check_timeout: Option<Bounds>,
+    pub check_attempts: Option<Bounds>,
+    pub check_passive: Option<bool>,
+}
+
+impl Expect {
+    /// `(field, expected, actual)` for every setting of `config` that does
+    /// not meet the expectation.
+    pub fn violations(&self, config: &NodeBalancerConfigObject) -> Vec<(&'static str, String, String)> {
+        let checks = [
+            ("algorithm", unmet(&self.algorithm, &config.algorithm), config.algorithm.to_string()),
+            ("protocol", unmet(&self.protocol, &config.protocol), config.protocol.to_string()),
+            ("stickiness", unmet(&self.stickiness, &config.stickiness), config.stickiness.to_string()),
+            ("check", unmet(&self.check, &config.check), config.check.to_string()),
+            ("check_path", unmet(&self.check_path, &config.check_path), config.check_path.clone()),
+            ("check_interval", out_of(&self.check_interval, config.check_interval), config.check_interval.to_string()),
+            ("check_timeout", out_of(&self.check_timeout, config.check_timeout), config.check_timeout.to_string()),
+            ("check_attempts", out_of(&self.check_attempts, config.check_attempts), config.check_attempts.to_string()),
+            (
+                "check_passive",
+                self.check_passive.filter(|p| *p != config.check_passive).map(|p| p.to_string()),
+                config.check_passive.to_string(),
+            ),
+        ];
+
+        checks.into_iter().filter_map(|(field, expected, actual)| Some((field, expected?, actual))).collect()
+    }
+}
+
+// What was expected, if `value` is not allowed.
+fn unmet<T: PartialEq + fmt::Display>(allowed: &Option<OneOf<T>>, value: &T) -> Option<String> {
+    allowed.as_ref().filter(|a| !a.allows(value)).map(OneOf::expected)
+}
+
+fn out_of(bounds: &Option<Bounds>, value: i32) -> Option<String> {
+    bounds.as_ref().filter(|b| !b.allows(value)).map(Bounds::expected)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default, rename = "match")]
+    pub matches: Match,
+    pub expect: Expect,
+}
+
+/// The declared state of every config, as a list of rules. A config can
+/// match several rules and is checked against each.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn parse(yaml: &str) -> Result<Self, Error> {
+        serde_yaml::from_str(yaml).map_err(|e| Error::Config(format!("invalid drift policy: {}", e)))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let yaml = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("unable to read drift policy {}: {}", path.display(), e)))?;
+        Self::parse(&yaml)
+    }
+
+    /// Whether any rule matches on labels, which the main DB does not have.
+    pub fn uses_labels(&self) -> bool {
+        self.rules.iter().any(|r| r.matches.label.is_some())
+    }
+
+    /// Every violation of the configs of one nodebalancer, seen at `now`.
+    pub fn check(&self, nodebalancer: &LocalNodeBalancerListObject, configs: &[NodeBalancerConfigObject], now: DateTime<Utc>) -> Vec<DriftViolation> {
+        let mut violations = Vec::new();
+        for config in configs {
+            for rule in self.rules.iter().filter(|r| r.matches.matches(nodebalancer, config)) {
+                violations.extend(rule.expect.violations(config).into_iter().map(|(field, expected, actual)| DriftViolation {
+                    nodebalancer_id: nodebalancer.nb_id,
+                    config_id: config.id,
+                    port: config.port,
+                    rule: rule.name.clone(),
+                    field: field.to_string(),
+                    expected,
+                    actual,
+                    since: now,
+                }));
+            }
+        }
+
+        violations
+    }
+}
+
+/// A violation that appeared, or was fixed.
+#[derive(Debug, Clone, Serialize)]
+pub struct DriftAlert {
+    pub violation: DriftViolation,
+    pub resolved: bool,
+}
+
+impl fmt::Display for DriftAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = &self.violation;
+        write!(f, "NB {} config {} port {}: ", v.nodebalancer_id, v.config_id, v.port)?;
+        if self.resolved {
+            write!(f, "{} meets rule {} again", v.field, v.rule)
+        } else {
+            write!(f, "{} is {}, rule {} expects {}", v.field, v.actual, v.rule, v.expected)
+        }
+    }
+}
+
+type Key = (i32, i32, String, String);
+
+fn key(v: &DriftViolation) -> Key {
+    (v.nodebalancer_id, v.config_id, v.rule.clone(), v.field.clone())
+}
+
+/// Checks synced configs against a [`Policy`] and keeps the `config_drift`
+/// table and its alerts up to date.
+#[derive(Debug, Clone)]
+pub struct DriftDetector {
+    policy: Policy,
+    webhook: Option<Webhook>,
+}
+
+impl DriftDetector {
+    pub fn new(policy: Policy) -> Self {
+        DriftDetector { policy, webhook: None }
+    }
+
+    /// URL every alert is `POST`ed to as JSON, with a Slack-style `text`.
+    pub fn webhook(mut self, url: Option<String>) -> Self {
+        self.webhook = url.as_deref().map(Webhook::new);
+        self
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Merges the violations `found` in the `checked` nodebalancers into the
+    /// `previous` ones: violations that were already known keep their
+    /// `since`. Returns the rows of the checked nodebalancers and an alert
+    /// for each violation that appeared or went away.
+    pub fn compare(&self, previous: &[DriftViolation], found: Vec<DriftViolation>, checked: &HashSet<i32>) -> (Vec<DriftViolation>, Vec<DriftAlert>) {
+        let mut previous: HashMap<Key, &DriftViolation> = previous.iter()
+            .filter(|v| checked.contains(&v.nodebalancer_id))
+            .map(|v| (key(v), v))
+            .collect();
+        let mut alerts = Vec::new();
+
+        let rows: Vec<DriftViolation> = found.into_iter().map(|v| match previous.remove(&key(&v)) {
+            Some(known) => DriftViolation { since: known.since, ..v },
+            None => {
+                alerts.push(DriftAlert { violation: v.clone(), resolved: false });
+                v
+            }
+        }).collect();
+        let mut fixed: Vec<&DriftViolation> = previous.into_values().collect();
+        fixed.sort_by_key(|v| key(v));
+        alerts.extend(fixed.into_iter().map(|v| DriftAlert { violation: v.clone(), resolved: true }));
+
+        (rows, alerts)
+    }
+
+    /// Records what one cycle found in the `checked` nodebalancers, drops
+    /// the violations of nodebalancers that are no longer `listed`, and
+    /// sends alerts in the background.
+    pub async fn run(&self, store: &dyn Store, checked: &HashSet<i32>, listed: &HashSet<i32>, found: Vec<DriftViolation>) -> Result<Vec<DriftAlert>, Error> {
+        let previous = store.config_drift().await?;
+        let (rows, alerts) = self.compare(&previous, found, checked);
+
+        let mut replaced: Vec<i32> = checked.iter().copied()
+            .chain(previous.iter().map(|v| v.nodebalancer_id).filter(|id| !listed.contains(id)))
+            .collect();
+        replaced.sort();
+        replaced.dedup();
+        store.record_config_drift(replaced, rows).await?;
+
+        for alert in &alerts {
+            println!("ALERT: config drift: {}", alert);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.send_in_background(alerts.clone());
+        }
+
+        Ok(alerts)
+    }
+}
@@ -0,0 +1,24 @@
+//! Durations written the short way, like `90s`, `15m`, `1h` or `7d`, for
+//! flags and query strings.
+
+use std::time::Duration;
+
+/// Parses a whole number followed by `s`, `m`, `h` or `d`. A bare number is
+/// seconds.
+pub fn parse(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration {:?}", value))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration {:?}, expected a unit of s, m, h or d", value)),
+    };
+
+    number.checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {:?} is too long", value))
+}
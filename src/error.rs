@@ -0,0 +1,25 @@
+//! The crate-wide error type.
+
+use reqwest::StatusCode;
+
+/// Everything that can go wrong while syncing. One failing nodebalancer
+/// produces one of these in the cycle report instead of stopping the cycle.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("API request failed: {0}")]
+    Api(#[from] reqwest::Error),
+    #[error("API returned {status} for {url}")]
+    ApiStatus { url: String, status: StatusCode },
+    #[error("account {account} was refused with {status} for {url}, check its token")]
+    Unauthorized { account: String, url: String, status: StatusCode },
+    #[error("could not decode API response: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("database error: {0}")]
+    Db(#[from] tokio_postgres::Error),
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("configuration error: {0}")]
+    Config(String),
+    #[error("no longer the leader of {0}")]
+    NotLeader(String),
+}
@@ -0,0 +1,125 @@
+//! Incremental sync: which nodebalancers a cycle fetches again, going by the
+//! `updated` timestamp the API lists each one with.
+
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use crate::latency::ago;
+use crate::models::{LocalNodeBalancerListObject, NodeBalancerConfigObject, NodeObject, NodeStatus, NodesStatus};
+
+/// How often an incremental sync fetches what has not changed anyway.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Incremental {
+    /// Every nodebalancer's configs and nodes are fetched at least this
+    /// often, whatever its `updated` says.
+    pub full_resync: Duration,
+    /// The nodes of unchanged nodebalancers are fetched this often, for
+    /// their status. Zero fetches them every cycle.
+    pub node_refresh: Duration,
+}
+
+/// What a cycle fetches of one nodebalancer.
+#[derive(Debug, Clone)]
+pub enum Fetch {
+    /// Its configs and their nodes.
+    Full,
+    /// The nodes of the configs it had when last fetched in full.
+    Nodes(Vec<NodeBalancerConfigObject>),
+    /// Nothing, the stored rows are current.
+    Skip,
+}
+
+#[derive(Debug)]
+struct Fetched {
+    updated: String,
+    configs: Vec<NodeBalancerConfigObject>,
+    full_at: DateTime<Utc>,
+    nodes_at: DateTime<Utc>,
+}
+
+/// `configs` with their nodes counted by status from `nodes`, as the API
+/// reports them, for configs that were not fetched again with their nodes.
+pub fn recount(mut configs: Vec<NodeBalancerConfigObject>, nodes: &[NodeObject]) -> Vec<NodeBalancerConfigObject> {
+    for config in &mut configs {
+        let of_config = nodes.iter().filter(|n| n.config_id == config.id);
+        config.nodes_status = of_config.fold(NodesStatus::default(), |mut counts, n| {
+            match n.status {
+                NodeStatus::Up => counts.up += 1,
+                NodeStatus::Down => counts.down += 1,
+                _ => {}
+            }
+            counts
+        });
+    }
+
+    configs
+}
+
+/// What was last fetched and written of each nodebalancer. Clones share it.
+#[derive(Debug, Clone, Default)]
+pub struct Tracker {
+    fetched: Arc<Mutex<HashMap<i32, Fetched>>>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker::default()
+    }
+
+    fn fetched(&self) -> MutexGuard<'_, HashMap<i32, Fetched>> {
+        self.fetched.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// What to fetch of `nb` at `now`. Nodebalancers listed without an
+    /// `updated`, or never written, are fetched in full.
+    pub fn plan(&self, nb: &LocalNodeBalancerListObject, settings: &Incremental, now: DateTime<Utc>) -> Fetch {
+        let fetched = self.fetched();
+        match fetched.get(&nb.nb_id) {
+            Some(last) if !nb.updated.is_empty()
+                && last.updated == nb.updated
+                && last.full_at > ago(now, settings.full_resync) => {
+                match last.nodes_at > ago(now, settings.node_refresh) {
+                    true => Fetch::Skip,
+                    false => Fetch::Nodes(last.configs.clone()),
+                }
+            }
+            _ => Fetch::Full,
+        }
+    }
+
+    /// Records that `nodebalancer_id`, listed as `updated`, was fetched in
+    /// full at `at`, with `configs`, and written.
+    pub fn written_full(&self, nodebalancer_id: i32, updated: String, configs: Vec<NodeBalancerConfigObject>, at: DateTime<Utc>) {
+        self.fetched().insert(nodebalancer_id, Fetched {
+            updated,
+            configs,
+            full_at: at,
+            nodes_at: at,
+        });
+    }
+
+    /// Records that the nodes of `nodebalancer_id` were fetched at `at` and
+    /// written.
+    pub fn written_nodes(&self, nodebalancer_id: i32, at: DateTime<Utc>) {
+        if let Some(last) = self.fetched().get_mut(&nodebalancer_id) {
+            last.nodes_at = at;
+        }
+    }
+
+    /// Fetches `nodebalancer_id` in full next time, e.g. after a config it
+    /// was last seen with could not be fetched.
+    pub fn forget(&self, nodebalancer_id: i32) {
+        self.fetched().remove(&nodebalancer_id);
+    }
+
+    /// Forgets every nodebalancer that is not `listed`.
+    pub fn retain(&self, listed: &HashSet<i32>) {
+        self.fetched().retain(|id, _| listed.contains(id));
+    }
+
+    /// Forgets everything, so every nodebalancer is fetched in full again.
+    pub fn clear(&self) {
+        self.fetched().clear();
+    }
+}
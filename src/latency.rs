@@ -0,0 +1,136 @@
+//! The probe time series: downsampling, retention and per-node latency
+//! percentiles, so slowing backends show up before they start failing.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::error::Error;
+use crate::models::ProbeSample;
+use crate::store::Store;
+
+/// Latency and availability of one node over a window of its time series.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeLatency {
+    pub nodebalancer_id: i32,
+    pub node_id: i32,
+    pub probes: i64,
+    pub up: i64,
+    /// Share of probes that passed.
+    pub availability: f64,
+    /// Percentiles of passing raw probes; downsampled points only count
+    /// towards availability.
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub ttfb_p95_ms: Option<f64>,
+}
+
+/// `now` minus `duration`, saturating at the earliest representable time.
+pub fn ago(now: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+    TimeDelta::from_std(duration).ok()
+        .and_then(|d| now.checked_sub_signed(d))
+        .unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+/// Start of the `resolution`-second period `at` falls in, counted from the
+/// Unix epoch.
+pub fn period_start(at: DateTime<Utc>, resolution: i32) -> DateTime<Utc> {
+    let resolution = i64::from(resolution.max(1));
+    let start = at.timestamp().div_euclid(resolution) * resolution;
+    DateTime::from_timestamp(start, 0).unwrap_or(at)
+}
+
+/// Nearest-rank percentile of an ascending slice.
+pub fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+fn mean(values: impl Iterator<Item = Option<f64>>) -> Option<f64> {
+    let (sum, count) = values.flatten().fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// Folds raw points into one point per node and `resolution` seconds, in
+/// node and time order. Stores call this on the points they compact.
+pub fn downsample(samples: &[ProbeSample], resolution: i32) -> Vec<ProbeSample> {
+    let mut periods: BTreeMap<(i32, i32, DateTime<Utc>), Vec<&ProbeSample>> = BTreeMap::new();
+    for s in samples {
+        periods.entry((s.nodebalancer_id, s.node_id, period_start(s.probed_at, resolution))).or_default().push(s);
+    }
+
+    periods.into_iter().map(|((nodebalancer_id, node_id, probed_at), points)| ProbeSample {
+        nodebalancer_id,
+        node_id,
+        probed_at,
+        resolution,
+        probes: points.iter().map(|p| p.probes).sum(),
+        up: points.iter().map(|p| p.up).sum(),
+        latency_ms: mean(points.iter().map(|p| p.latency_ms)),
+        connect_ms: mean(points.iter().map(|p| p.connect_ms)),
+        tls_ms: mean(points.iter().map(|p| p.tls_ms)),
+        ttfb_ms: mean(points.iter().map(|p| p.ttfb_ms)),
+        http_status: points.iter().filter_map(|p| p.http_status).max(),
+    }).collect()
+}
+
+/// Sums up each node's points, in node order.
+pub fn summarize(samples: &[ProbeSample]) -> Vec<NodeLatency> {
+    let mut nodes: BTreeMap<(i32, i32), Vec<&ProbeSample>> = BTreeMap::new();
+    for s in samples {
+        nodes.entry((s.nodebalancer_id, s.node_id)).or_default().push(s);
+    }
+
+    nodes.into_iter().map(|((nodebalancer_id, node_id), points)| {
+        let probes: i64 = points.iter().map(|p| i64::from(p.probes)).sum();
+        let up: i64 = points.iter().map(|p| i64::from(p.up)).sum();
+        let raw = || points.iter().filter(|p| p.resolution == 0);
+        let mut latency: Vec<f64> = raw().filter(|p| p.up > 0).filter_map(|p| p.latency_ms).collect();
+        let mut ttfb: Vec<f64> = raw().filter_map(|p| p.ttfb_ms).collect();
+        latency.sort_by(f64::total_cmp);
+        ttfb.sort_by(f64::total_cmp);
+        NodeLatency {
+            nodebalancer_id,
+            node_id,
+            probes,
+            up,
+            availability: if probes > 0 { up as f64 / probes as f64 } else { 0.0 },
+            p50_ms: percentile(&latency, 50.0),
+            p95_ms: percentile(&latency, 95.0),
+            ttfb_p95_ms: percentile(&ttfb, 95.0),
+        }
+    }).collect()
+}
+
+/// How long the probe time series is kept: raw points for `raw`, then one
+/// point per node and `resolution` until they are `keep` old.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub raw: Duration,
+    pub resolution: Duration,
+    pub keep: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            raw: Duration::from_secs(60 * 60),
+            resolution: Duration::from_secs(5 * 60),
+            keep: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl Retention {
+    /// Downsamples and expires the time series as of `now`. The raw cutoff
+    /// is aligned to `resolution` so that every period is downsampled once,
+    /// with all of its probes.
+    pub async fn apply(&self, store: &dyn Store, now: DateTime<Utc>) -> Result<(), Error> {
+        let resolution = self.resolution.as_secs().clamp(1, i32::MAX as u64) as i32;
+        let raw_before = period_start(ago(now, self.raw), resolution);
+        store.compact_probe_samples(raw_before, resolution, ago(now, self.keep)).await
+    }
+}
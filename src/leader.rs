@@ -0,0 +1,121 @@
+//! Leader election between replicas that share one local Postgres database,
+//! so only one of them syncs and probes while the others serve the read API.
+//!
+//! The leader holds a session-level advisory lock on a connection of its
+//! own. When it dies the connection closes and Postgres releases the lock;
+//! a standby polling for it takes over on its next try.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio_postgres::Client;
+use crate::database::PgTarget;
+use crate::error::Error;
+
+/// Whether this replica currently leads, as followed by the work that only
+/// the leader does.
+#[derive(Debug, Clone)]
+pub struct Leadership {
+    leader: watch::Receiver<bool>,
+    // The location and the connection holding its lock; `None` for
+    // leadership that is always held.
+    held: Option<(String, Arc<Mutex<Option<Client>>>)>,
+}
+
+impl Leadership {
+    /// Leadership that is always held, for a single replica.
+    pub fn always() -> Self {
+        let (_, leader) = watch::channel(true);
+        Leadership { leader, held: None }
+    }
+
+    /// Whether this replica led at the election's last check.
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    /// Checks on the connection holding the lock that it is still held,
+    /// for writes that must not be made by a replica that lost it since
+    /// the election's last check.
+    pub async fn confirm(&self) -> Result<(), Error> {
+        let Some((location, held)) = &self.held else { return Ok(()) };
+        let confirmed = self.is_leader() && match held.lock().await.as_ref() {
+            Some(connection) => connection.simple_query("SELECT 1").await.is_ok(),
+            None => false,
+        };
+        match confirmed {
+            true => Ok(()),
+            false => Err(Error::NotLeader(location.clone())),
+        }
+    }
+
+    /// Waits until this replica leads. Returns at once if it does.
+    pub async fn acquired(&mut self) {
+        // The sender only goes away with the election task; a replica that
+        // can no longer be elected waits for good.
+        if self.leader.wait_for(|leader| *leader).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Campaigns for the advisory lock of one location on its local database.
+pub struct Election {
+    target: PgTarget,
+    location: String,
+    key: String,
+    leader: watch::Sender<bool>,
+    held: Arc<Mutex<Option<Client>>>,
+}
+
+impl Election {
+    pub fn new(target: PgTarget, location: &str) -> Self {
+        Election {
+            target,
+            location: location.to_string(),
+            key: format!("hc-nb-api-client:{}", location),
+            leader: watch::Sender::new(false),
+            held: Arc::default(),
+        }
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        Leadership { leader: self.leader.subscribe(), held: Some((self.location.clone(), Arc::clone(&self.held))) }
+    }
+
+    async fn try_lock(&self) -> Result<Option<Client>, Error> {
+        let connection = self.target.connect().await?;
+        let row = connection.query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&self.key]).await?;
+
+        Ok(row.get::<_, bool>(0).then_some(connection))
+    }
+
+    /// Tries for the lock every `tick` until it is held, then checks every
+    /// `tick` that the connection holding it is still alive. Leadership is
+    /// given up as soon as that check fails.
+    pub async fn run(self, tick: Duration) {
+        loop {
+            let mut held = self.held.lock().await;
+            match held.as_ref() {
+                None => match self.try_lock().await {
+                    Ok(Some(connection)) => {
+                        println!("Became the leader for {}", self.key);
+                        *held = Some(connection);
+                        self.leader.send_replace(true);
+                    }
+                    Ok(None) => {}
+                    Err(e) => println!("Leader election failed: {}", e),
+                },
+                Some(connection) => {
+                    if let Err(e) = connection.simple_query("SELECT 1").await {
+                        println!("Lost the leader lock for {}: {}", self.key, e);
+                        *held = None;
+                        self.leader.send_replace(false);
+                    }
+                }
+            }
+            drop(held);
+            tokio::time::sleep(tick).await;
+        }
+    }
+}
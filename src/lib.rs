@@ -0,0 +1,63 @@
+//! Per-datacenter client of [hc-nb-api](https://github.com/nathanle/hc-nb-api).
+//!
+//! Mirrors the NodeBalancers of one Linode region, with their configs and
+//! backend nodes, from the Linode API into a local database.
+//!
+//! - [`models`]: Linode API objects and local rows
+//! - [`linode::LinodeClient`]: rate-limited, paginating API client, one per
+//!   Linode account in [`accounts::Accounts`]
+//! - [`location::LocationSettings`]: the settings of each location a process
+//!   syncs
+//! - [`store::Store`]: the local database, implemented for Postgres by
+//!   [`database::PgStore`], for SQLite by [`sqlite::SqliteStore`] and in
+//!   memory by [`memory::MemoryStore`]
+//! - [`sync::Syncer`]: runs a sync cycle and returns its
+//!   [`scheduler::CycleReport`], fetching only what changed with
+//!   [`incremental::Incremental`]
+//! - [`probe::Prober`]: checks every node from inside the datacenter and
+//!   records the result next to Linode's status
+//! - [`disagreement::DisagreementDetector`]: alerts when Linode and the probe
+//!   keep disagreeing about a node
+//! - [`remediation::Remediator`]: drains or rejects nodes that keep failing
+//!   probes, and restores them
+//! - [`drift::DriftDetector`]: checks synced configs against a drift policy
+//! - [`audit::Auditor`]: turns what each cycle fetched into the audit log of
+//!   changes
+//! - [`traffic::TrafficCollector`]: pulls each NodeBalancer's connections
+//!   and traffic into a local time series
+//! - [`leader::Election`]: lets one of several replicas sync and probe
+//! - [`upstream`]: the per-cycle report each datacenter writes to the main DB
+//! - [`latency`]: the probe time series and per-node latency percentiles
+//! - [`api::router`]: read-only HTTP API and Prometheus [`metrics`]
+
+pub mod accounts;
+pub mod api;
+pub mod audit;
+pub mod database;
+pub mod disagreement;
+pub mod drift;
+pub mod duration;
+pub mod error;
+pub mod incremental;
+pub mod latency;
+pub mod leader;
+pub mod linode;
+pub mod location;
+pub mod memory;
+pub mod metrics;
+pub mod models;
+pub mod probe;
+pub mod ratelimit;
+pub mod remediation;
+pub mod scheduler;
+pub mod sqlite;
+pub mod store;
+pub mod sync;
+pub mod traffic;
+pub mod upstream;
+pub mod webhook;
+
+pub use error::Error;
+pub use linode::LinodeClient;
+pub use store::Store;
+pub use sync::Syncer;
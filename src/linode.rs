@@ -0,0 +1,201 @@
+//! Client for the parts of the Linode API the sync loop and the traffic
+//! collector read, and the node updates remediation makes.
+
+use std::sync::Arc;
+use std::time::Duration;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use crate::models::{NodeBalancerConfigObject, NodeBalancerListObject, NodeBalancerStats, NodeMode, NodeObject};
+use crate::error::Error;
+use crate::ratelimit::RateLimiter;
+
+// How often a 429 is retried before the request is given up on.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// The account a client acts for unless named otherwise, the one whose
+/// token is in `TOKEN`.
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// One page of any Linode list endpoint.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ListData<T> {
+    pub data: Vec<T>,
+    pub page: u64,
+    pub pages: u64,
+    pub results: u64,
+}
+
+fn decode_items<T: DeserializeOwned>(path: &str, items: Vec<serde_json::Value>) -> Vec<T> {
+    items.into_iter().filter_map(|item| {
+        match T::deserialize(&item) {
+            Ok(decoded) => Some(decoded),
+            Err(e) => {
+                println!("Skipping unparseable object from {}: {} ({})", path, e, item);
+                None
+            }
+        }
+    }).collect()
+}
+
+/// Linode API client. Clones share one rate limiter and one cap on requests
+/// in flight, so callers can fan fetches out freely.
+#[derive(Clone)]
+pub struct LinodeClient {
+    http: Client,
+    base_url: String,
+    limiter: Arc<RateLimiter>,
+    in_flight: Arc<Semaphore>,
+    account: String,
+}
+
+impl LinodeClient {
+    pub fn new(token: &str, api_version: &str, requests_per_minute: u32, concurrency: usize) -> Result<Self, Error> {
+        let mut headers = HeaderMap::new();
+        let auth_header = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| Error::Config("TOKEN is not a valid header value".to_string()))?;
+        headers.insert(AUTHORIZATION, auth_header);
+        headers.insert("accept", HeaderValue::from_static("application/json"));
+
+        let http = Client::builder()
+            .default_headers(headers)
+            .build()?;
+
+        Ok(LinodeClient {
+            http,
+            base_url: format!("https://api.linode.com/{}", api_version),
+            limiter: Arc::new(RateLimiter::per_minute(requests_per_minute)),
+            in_flight: Arc::new(Semaphore::new(concurrency.max(1))),
+            account: DEFAULT_ACCOUNT.to_string(),
+        })
+    }
+
+    /// Sends requests to another API root instead, such as a proxy or a
+    /// mock server in tests.
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Names the account the token belongs to, in errors.
+    pub fn account(mut self, account: &str) -> Self {
+        self.account = account.to_string();
+        self
+    }
+
+    pub fn account_name(&self) -> &str {
+        &self.account
+    }
+
+    /// A client with its own limit of requests in flight, still sharing the
+    /// request budget per minute with this one.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(concurrency.max(1)));
+        self
+    }
+
+    // Sends the request `build` makes within the shared budget, retrying it
+    // on 429, and returns the response if it succeeded.
+    async fn send(&self, url: &str, build: impl Fn() -> RequestBuilder) -> Result<Response, Error> {
+        let _permit = self.in_flight.acquire().await.expect("request semaphore closed");
+        let mut retries = 0;
+        loop {
+            self.limiter.acquire().await;
+            let response = build().send().await?;
+            let status = response.status();
+
+            if status == StatusCode::TOO_MANY_REQUESTS && retries < MAX_RATE_LIMIT_RETRIES {
+                let wait = response.headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(1);
+                println!("Rate limited on {}, retrying in {}s", url, wait);
+                tokio::time::sleep(Duration::from_secs(wait)).await;
+                retries += 1;
+                continue;
+            }
+            if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+                return Err(Error::Unauthorized { account: self.account.clone(), url: url.to_string(), status });
+            }
+            if !status.is_success() {
+                return Err(Error::ApiStatus { url: url.to_string(), status });
+            }
+
+            return Ok(response);
+        }
+    }
+
+    async fn get_page<T: DeserializeOwned>(&self, path: &str, page: u64, filter: Option<&str>) -> Result<ListData<T>, Error> {
+        let url = format!("{}{}?page={}", self.base_url, path, page);
+        let response = self.send(&url, || {
+            let request = self.http.get(&url);
+            match filter {
+                Some(filter) => request.header("X-Filter", filter),
+                None => request,
+            }
+        }).await?;
+
+        let json: serde_json::Value = response.json().await?;
+        Ok(serde_json::from_value(json)?)
+    }
+
+    /// Fetches every page of a list endpoint, optionally narrowed by an
+    /// `X-Filter` expression. Any failed page fails the whole list, so a
+    /// partial result is never mistaken for a complete one. Individual
+    /// objects that do not decode are logged and skipped.
+    pub async fn get_all<T: DeserializeOwned>(&self, path: &str, filter: Option<&serde_json::Value>) -> Result<Vec<T>, Error> {
+        let filter = filter.map(|f| f.to_string());
+        let filter = filter.as_deref();
+        let first = self.get_page::<serde_json::Value>(path, 1, filter).await?;
+        let pages = first.pages;
+        let mut data = decode_items(path, first.data);
+        for page in 2..=pages {
+            println!("Processing {} page {}", path, page);
+            let next = self.get_page::<serde_json::Value>(path, page, filter).await?;
+            data.extend(decode_items(path, next.data));
+        }
+
+        Ok(data)
+    }
+
+    pub async fn nodebalancers_in_region(&self, region: &str) -> Result<Vec<NodeBalancerListObject>, Error> {
+        self.get_all("/nodebalancers", Some(&serde_json::json!({ "region": region }))).await
+    }
+
+    pub async fn nodebalancer(&self, nb_id: i32) -> Result<NodeBalancerListObject, Error> {
+        let url = format!("{}/nodebalancers/{}", self.base_url, nb_id);
+        let response = self.send(&url, || self.http.get(&url)).await?;
+
+        let json: serde_json::Value = response.json().await?;
+        Ok(serde_json::from_value(json)?)
+    }
+
+    pub async fn nodebalancer_configs(&self, nb_id: i32) -> Result<Vec<NodeBalancerConfigObject>, Error> {
+        self.get_all(&format!("/nodebalancers/{}/configs", nb_id), None).await
+    }
+
+    pub async fn config_nodes(&self, nb_id: i32, config_id: i32) -> Result<Vec<NodeObject>, Error> {
+        self.get_all(&format!("/nodebalancers/{}/configs/{}/nodes", nb_id, config_id), None).await
+    }
+
+    pub async fn nodebalancer_stats(&self, nb_id: i32) -> Result<NodeBalancerStats, Error> {
+        let url = format!("{}/nodebalancers/{}/stats", self.base_url, nb_id);
+        let response = self.send(&url, || self.http.get(&url)).await?;
+
+        let json: serde_json::Value = response.json().await?;
+        Ok(serde_json::from_value(json)?)
+    }
+
+    /// `PUT`s a node's `mode` and returns the node as Linode now has it.
+    pub async fn update_node_mode(&self, nb_id: i32, config_id: i32, node_id: i32, mode: &NodeMode) -> Result<NodeObject, Error> {
+        let url = format!("{}/nodebalancers/{}/configs/{}/nodes/{}", self.base_url, nb_id, config_id, node_id);
+        let body = serde_json::json!({ "mode": mode });
+        let response = self.send(&url, || self.http.put(&url).json(&body)).await?;
+
+        let json: serde_json::Value = response.json().await?;
+        Ok(serde_json::from_value(json)?)
+    }
+}
@@ -0,0 +1,113 @@
+//! Settings of each location one process syncs. `LOCATION` lists them,
+//! comma-separated, and each can override the shared settings with
+//! variables named after it: `us-ord` reads `LOCALDB_US_ORD_HOSTPORT` and
+//! `LOCALDB_US_ORD_PASSWORD`, `LOCALDB_US_ORD_PATH`, `DB_CONCURRENCY_US_ORD`
+//! and `FETCH_CONCURRENCY_US_ORD`.
+
+use std::path::{Path, PathBuf};
+use crate::error::Error;
+
+/// The prefix of the shared local Postgres variables, `LOCALDB_HOSTPORT`
+/// and `LOCALDB_PASSWORD`.
+pub const SHARED_LOCALDB: &str = "LOCALDB";
+
+/// `us-ord` as it appears in variable names, `US_ORD`.
+pub fn env_suffix(location: &str) -> String {
+    location.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect()
+}
+
+/// The locations of a comma-separated list, in order and without repeats.
+pub fn parse_list(list: &str) -> Vec<String> {
+    let mut locations: Vec<String> = Vec::new();
+    for location in list.split(',').map(str::trim).filter(|l| !l.is_empty()) {
+        if !locations.iter().any(|l| l == location) {
+            locations.push(location.to_string());
+        }
+    }
+
+    locations
+}
+
+// `hc-nb-client.db` becomes `hc-nb-client-us-ord.db`.
+fn sqlite_path_for(shared: &Path, location: &str) -> PathBuf {
+    let stem = shared.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match shared.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, location, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, location),
+    };
+    shared.with_file_name(name)
+}
+
+/// The shared settings, from the command line.
+#[derive(Debug, Clone)]
+pub struct Defaults {
+    pub sqlite_path: PathBuf,
+    pub db_concurrency: usize,
+    pub fetch_concurrency: usize,
+}
+
+/// Where one location is kept and how much it may run at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocationSettings {
+    pub location: String,
+    /// Prefix of the variables of its local Postgres database, either its
+    /// own or [`SHARED_LOCALDB`].
+    pub localdb_prefix: String,
+    pub sqlite_path: PathBuf,
+    pub db_concurrency: usize,
+    pub fetch_concurrency: usize,
+}
+
+impl LocationSettings {
+    /// The settings of every location, with overrides read through `var`.
+    /// A single location keeps the shared SQLite file; several get one each
+    /// next to it.
+    pub fn resolve(locations: &[String], defaults: &Defaults, var: impl Fn(&str) -> Option<String>) -> Result<Vec<Self>, Error> {
+        if locations.is_empty() {
+            return Err(Error::Config("LOCATION lists no locations".to_string()));
+        }
+        let number = |name: String, default: usize| match var(&name) {
+            Some(value) => value.parse::<usize>().map_err(|_| Error::Config(format!("{} is not a number: {}", name, value))),
+            None => Ok(default),
+        };
+
+        locations.iter().map(|location| {
+            let suffix = env_suffix(location);
+            let own_localdb = format!("{}_{}", SHARED_LOCALDB, suffix);
+            let sqlite_path = match (var(&format!("{}_PATH", own_localdb)), locations.len()) {
+                (Some(path), _) => PathBuf::from(path),
+                (None, 1) => defaults.sqlite_path.clone(),
+                (None, _) => sqlite_path_for(&defaults.sqlite_path, location),
+            };
+            Ok(LocationSettings {
+                location: location.clone(),
+                localdb_prefix: match var(&format!("{}_HOSTPORT", own_localdb)) {
+                    Some(_) => own_localdb,
+                    None => SHARED_LOCALDB.to_string(),
+                },
+                sqlite_path,
+                db_concurrency: number(format!("DB_CONCURRENCY_{}", suffix), defaults.db_concurrency)?,
+                fetch_concurrency: number(format!("FETCH_CONCURRENCY_{}", suffix), defaults.fetch_concurrency)?,
+            })
+        }).collect()
+    }
+
+    /// Fails if several locations would share one local Postgres database,
+    /// which keeps a single location.
+    pub fn check_postgres(settings: &[Self]) -> Result<(), Error> {
+        let shared: Vec<&str> = settings.iter()
+            .filter(|s| s.localdb_prefix == SHARED_LOCALDB)
+            .map(|s| s.location.as_str())
+            .collect();
+        if shared.len() > 1 {
+            let variables: Vec<String> = shared[1..].iter().map(|l| format!("{}_{}_HOSTPORT", SHARED_LOCALDB, env_suffix(l))).collect();
+            return Err(Error::Config(format!(
+                "{} would share the local database, set {}",
+                shared.join(", "),
+                variables.join(", "),
+            )));
+        }
+
+        Ok(())
+    }
+}
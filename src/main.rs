@@ -1,275 +1,452 @@
-use serde_json;
-use futures::future::join_all;
-use std::sync::Arc;
-use tokio::sync::Semaphore;
-use tokio::task;
-use serde::{Serialize};
-use clap::Parser;
-use rust_decimal::prelude::*;
-use chrono::{NaiveDateTime, DateTime, Utc, Local, TimeZone};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use reqwest::Client;
+use clap::{Parser, Subcommand, ValueEnum};
+use chrono::Utc;
 use std::env;
-use std::sync::LazyLock;
-use crate::database::{
-    create_maindb_client,
-    create_localdb_client,
-    localdb_init,
-    get_nb_ids,
-    get_nbcfg_ids,
-    get_nb_by_loc,
-    update_db_node,
-    update_db_nb,
-    update_db_config,
-    LocalNodeBalancerListObject,
-    NodeBalancerListObject,
-    NodeBalancerConfigObject,
-    NodeObject
-};
-
-mod database;
-
-
-static api_version: LazyLock<String> = LazyLock::new(|| {
-    env::var("APIVERSION").expect("APIVERSION not set!") 
-});
-static token: LazyLock<String> = LazyLock::new(|| {
-    env::var("TOKEN").expect("TOKEN not set!")
-});
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use hc_nb_api_client::database::{MainDb, PgStore, PgTarget};
+use hc_nb_api_client::disagreement::DisagreementDetector;
+use hc_nb_api_client::drift::{DriftDetector, Policy};
+use hc_nb_api_client::incremental::Incremental;
+use hc_nb_api_client::audit;
+use hc_nb_api_client::latency::{ago, Retention};
+use hc_nb_api_client::leader::{Election, Leadership};
+use hc_nb_api_client::location::{self, Defaults, LocationSettings};
+use hc_nb_api_client::models::{ClientInstance, NodeStatus};
+use hc_nb_api_client::probe::{Checker, Prober};
+use hc_nb_api_client::remediation::{RemediationMode, Remediator};
+use hc_nb_api_client::sqlite::SqliteStore;
+use hc_nb_api_client::sync::Discovery;
+use hc_nb_api_client::traffic::TrafficCollector;
+use hc_nb_api_client::upstream::{self, Liveness};
+use hc_nb_api_client::accounts::{Accounts, ClientSettings};
+use hc_nb_api_client::{api, duration, Error, Store, Syncer};
+
+// How long to wait before retrying a cycle that could not list any NBs.
+const DISCOVERY_RETRY_DELAY: Duration = Duration::from_secs(30);
+// How often the prober looks for nodes that are due a check.
+const PROBE_TICK: Duration = Duration::from_secs(2);
+
+fn env_var(name: &str) -> Result<String, Error> {
+    env::var(name).map_err(|_| Error::Config(format!("{} not set!", name)))
+}
+
+/// Where the local copy of the location is kept.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Backend {
+    /// The Postgres database at LOCALDB_HOSTPORT
+    Postgres,
+    /// An embedded SQLite file, see --sqlite-path
+    Sqlite,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the audit log of changes seen by the sync and switches made by remediation
+    Audit {
+        /// How far back to look, e.g. 1h
+        #[arg(long, value_parser = duration::parse, default_value = "24h")]
+        since: Duration,
+        /// Only events of this nodebalancer
+        #[arg(long)]
+        nb_id: Option<i32>,
+        /// Print the events as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the locations whose client is stale or missing, from the heartbeats in the main DB
+    Instances {
+        /// How old the last heartbeat of a location may be before it is stale
+        #[arg(long, value_parser = duration::parse, default_value = "10m")]
+        stale_after: Duration,
+        /// Locations that should have a client, e.g. us-ord,us-iad
+        #[arg(long, value_delimiter = ',', env = "EXPECTED_LOCATIONS")]
+        expect: Vec<String>,
+        /// Print live locations too
+        #[arg(long)]
+        all: bool,
+    },
+    /// Print the configs that do not meet the drift policy, as of the last cycle
+    Drift {
+        /// Only violations of this nodebalancer
+        #[arg(long)]
+        nb_id: Option<i32>,
+    },
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long)]
     data: bool,
+    /// Only print nodes with this status with --data (UP, DOWN or unknown)
+    #[arg(long)]
+    status: Option<NodeStatus>,
+    /// Maximum number of nodebalancer transactions in flight across a whole cycle, per location
+    #[arg(long, default_value_t = 100)]
+    db_concurrency: usize,
+    /// Maximum number of Linode API requests in flight, per location
+    #[arg(long, default_value_t = 16)]
+    fetch_concurrency: usize,
+    /// Linode API request budget per minute of each account, shared by all fetches of every location
+    #[arg(long, default_value_t = 800)]
+    api_rate_limit: u32,
+    /// Fetch the configs of a nodebalancer again only when its `updated` timestamp changed or a full resync is due
+    #[arg(long)]
+    incremental: bool,
+    /// How often an incremental sync fetches every nodebalancer in full anyway
+    #[arg(long, value_parser = duration::parse, default_value = "1h")]
+    full_resync_interval: Duration,
+    /// How often an incremental sync fetches the nodes of unchanged nodebalancers for their status, 0 for every cycle
+    #[arg(long, value_parser = duration::parse, default_value = "0s")]
+    node_refresh_interval: Duration,
+    /// Source of the nodebalancer list for this location
+    #[arg(long, value_enum, default_value_t = Discovery::Auto)]
+    discovery: Discovery,
+    /// Local database backend
+    #[arg(long, global = true, value_enum, env = "LOCALDB_BACKEND", default_value_t = Backend::Postgres)]
+    store: Backend,
+    /// Location whose local database the audit and drift commands read, when LOCATION lists several
+    #[arg(long, global = true)]
+    location: Option<String>,
+    /// SQLite database file, used with --store sqlite; with several locations each gets one next to it
+    #[arg(long, global = true, env = "LOCALDB_PATH", default_value = "hc-nb-client.db")]
+    sqlite_path: PathBuf,
+    /// Probe every node from this datacenter following its config's health check
+    #[arg(long)]
+    probe: bool,
+    /// Maximum number of node probes in flight
+    #[arg(long, default_value_t = 64)]
+    probe_concurrency: usize,
+    /// Node ports of tcp configs that are probed with a TLS handshake
+    #[arg(long, value_delimiter = ',', default_value = "443")]
+    probe_tls_ports: Vec<u16>,
+    /// Consecutive probes Linode and the probe must disagree, or agree again, before it is reported
+    #[arg(long, default_value_t = 3)]
+    disagreement_probes: u32,
+    /// YAML file of the desired config settings each synced config is checked against
+    #[arg(long, env = "DRIFT_POLICY")]
+    drift_policy: Option<PathBuf>,
+    /// URL that disagreement and drift alerts are POSTed to as JSON
+    #[arg(long, env = "ALERT_WEBHOOK")]
+    alert_webhook: Option<String>,
+    /// How long every single probe is kept, e.g. 1h
+    #[arg(long, value_parser = duration::parse, default_value = "1h")]
+    probe_raw_retention: Duration,
+    /// Period older probes are averaged over
+    #[arg(long, value_parser = duration::parse, default_value = "5m")]
+    probe_resolution: Duration,
+    /// How long averaged probes are kept
+    #[arg(long, value_parser = duration::parse, default_value = "7d")]
+    probe_retention: Duration,
+    /// Pull every nodebalancer's connections and traffic from the Linode stats endpoint into the local database
+    #[arg(long)]
+    traffic_stats: bool,
+    /// How often traffic stats are pulled; Linode reports 5 minute averages
+    #[arg(long, value_parser = duration::parse, default_value = "5m")]
+    traffic_interval: Duration,
+    /// How long traffic stats are kept
+    #[arg(long, value_parser = duration::parse, default_value = "7d")]
+    traffic_retention: Duration,
+    /// Switch nodes that keep failing probes to this mode through the Linode API, and back once they pass (requires --probe)
+    #[arg(long, value_enum)]
+    remediate: Option<RemediationMode>,
+    /// How long a node must fail, or pass, every probe before it is switched
+    #[arg(long, value_parser = duration::parse, default_value = "5m")]
+    remediation_window: Duration,
+    /// Largest share of a config's nodes that may be out of rotation
+    #[arg(long, default_value_t = 0.34)]
+    remediation_max_fraction: f64,
+    /// Log remediation switches without making them
+    #[arg(long)]
+    remediation_dry_run: bool,
+    /// Write a summary of every cycle to the main DB for the central hc-nb-api side
+    #[arg(long)]
+    report_upstream: bool,
+    /// Register this instance in the main DB and update its heartbeat every cycle
+    #[arg(long)]
+    heartbeat: bool,
+    /// Name this instance registers under, by default the host name
+    #[arg(long, env = "INSTANCE_ID")]
+    instance_id: Option<String>,
+    /// Run as one of several replicas sharing the local Postgres database: only the one holding its advisory lock syncs and probes, the others serve the HTTP API
+    #[arg(long)]
+    leader_election: bool,
+    /// How often a standby tries for the lock and the leader checks it still holds it
+    #[arg(long, value_parser = duration::parse, default_value = "5s")]
+    leader_check_interval: Duration,
+    /// Address to serve the HTTP API and /metrics on, e.g. 0.0.0.0:8080
+    #[arg(long, env = "LISTEN_ADDRESS")]
+    listen: Option<SocketAddr>,
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-struct NodeBalancerListData {
-    data: Vec<NodeBalancerListObject>,
-    page: u64,
-    pages: u64,
-    results: u64,
+async fn print_nodes(store: &dyn Store, status: Option<&NodeStatus>) {
+    match store.node_details().await {
+        Ok(nodes) => {
+            // Print header
+            println!("{:<10} {:<23} {:<6} {:<6} {:<10} {:<6} {:<20} {:<15} {:<15} {:<10} {:<5} {:<3} {:<3}", "ID", "Address", "Status", "Probe", "Config ID", "NB ID", "NB Label", "IPv4 VIP", "Region", "Algorithm", "Port", "Up", "Down");
+            println!("---------------------------------------------------------------------------------------------------------------------------");
+
+            for n in nodes.into_iter().filter(|n| status.is_none_or(|s| *s == n.status)) {
+                let probe = n.probe.as_ref().map_or("-".to_string(), |p| p.status.to_string());
+                println!("{:<10} {:<23} {:<6} {:<6} {:<10} {:<6} {:<20} {:<15} {:<15} {:<10} {:<5} {:<3} {:<3}", n.id, n.address, n.status, probe, n.config_id, n.nodebalancer_id, n.nodebalancer_label, n.ipv4, n.region, n.algorithm, n.port, n.up, n.down);
+            }
+        }
+        Err(e) => println!("{:?}", e),
+    }
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-struct NodeBalancerConfigData {
-    data: Vec<NodeBalancerConfigObject>,
-    page: u64,
-    pages: u64,
-    results: u64,
+async fn print_audit(store: &dyn Store, since: Duration, nb_id: Option<i32>, json: bool) -> Result<(), Error> {
+    let events = store.audit_events(ago(Utc::now(), since), nb_id).await?;
+    if json {
+        audit::log(&events);
+        return Ok(());
+    }
+    println!("{:<20} {:<10} {:<13} {:<10} {:<11} Change", "Time", "NB ID", "Object", "ID", "Action");
+    println!("---------------------------------------------------------------------------------------------------------------------------");
+    for e in &events {
+        println!("{:<20} {:<10} {:<13} {:<10} {:<11} {}", e.at.format("%Y-%m-%d %H:%M:%S"), e.nodebalancer_id, e.object, e.object_id, e.action, audit::describe(e));
+    }
+
+    Ok(())
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
-struct NodeListData {
-    data: Vec<NodeObject>,
-    page: u64,
-    pages: u64,
-    results: u64,
+async fn print_drift(store: &dyn Store, nb_id: Option<i32>) -> Result<(), Error> {
+    let violations = store.config_drift().await?;
+    println!("{:<10} {:<10} {:<5} {:<20} {:<15} {:<20} {:<25} Since", "NB ID", "Config ID", "Port", "Rule", "Field", "Actual", "Expected");
+    println!("---------------------------------------------------------------------------------------------------------------------------");
+    for v in violations.iter().filter(|v| nb_id.is_none_or(|id| id == v.nodebalancer_id)) {
+        println!("{:<10} {:<10} {:<5} {:<20} {:<15} {:<20} {:<25} {}", v.nodebalancer_id, v.config_id, v.port, v.rule, v.field, v.actual, v.expected, v.since.format("%Y-%m-%d %H:%M:%S"));
+    }
+
+    Ok(())
+}
+
+async fn print_instances(maindb: &MainDb, stale_after: Duration, expect: &[String], all: bool) -> Result<(), Error> {
+    let statuses = upstream::locations(maindb.instances().await?, expect, Utc::now(), stale_after);
+    println!("{:<15} {:<8} {:<30} {:<10} {:<20} Last heartbeat", "Location", "Status", "Instance", "Version", "Started");
+    println!("---------------------------------------------------------------------------------------------------------------------------");
+    for s in statuses.iter().filter(|s| all || s.liveness != Liveness::Live) {
+        match &s.instance {
+            Some(i) => println!("{:<15} {:<8} {:<30} {:<10} {:<20} {}", s.location, s.liveness, i.instance_id, i.version, i.started_at.format("%Y-%m-%d %H:%M:%S"), i.last_heartbeat.format("%Y-%m-%d %H:%M:%S")),
+            None => println!("{:<15} {:<8} {:<30} {:<10} {:<20} -", s.location, s.liveness, "-", "-", "-"),
+        }
+    }
+
+    Ok(())
 }
 
-fn epoch_to_dt(e: &String) -> String {
-    let timestamp = e.parse::<i64>().unwrap();
-    let naive = NaiveDateTime::from_timestamp(timestamp, 0);
-    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-    let newdate = datetime.format("%Y-%m-%d %H:%M:%S");
+fn location_settings(args: &Args, locations: &[String]) -> Result<Vec<LocationSettings>, Error> {
+    let defaults = Defaults {
+        sqlite_path: args.sqlite_path.clone(),
+        db_concurrency: args.db_concurrency,
+        fetch_concurrency: args.fetch_concurrency,
+    };
 
-    newdate.to_string()
+    LocationSettings::resolve(locations, &defaults, |name| env::var(name).ok())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let _ = localdb_init().await;
+async fn open_store(backend: Backend, settings: &LocationSettings) -> Result<Arc<dyn Store>, Error> {
+    let store: Arc<dyn Store> = match backend {
+        Backend::Postgres => Arc::new(PgStore::new(PgTarget::from_env(&settings.localdb_prefix)?)),
+        Backend::Sqlite => Arc::new(SqliteStore::open(&settings.sqlite_path)?),
+    };
+    store.init().await?;
+
+    Ok(store)
+}
+
+// The store the `audit` and `drift` commands read: the one of `--location`,
+// or of the only location, or the shared one when LOCATION is not set.
+async fn command_store(args: &Args) -> Result<Arc<dyn Store>, Error> {
+    let locations = match env::var("LOCATION") {
+        Ok(list) => location::parse_list(&list),
+        Err(_) => vec![args.location.clone().unwrap_or_default()],
+    };
+    let settings = location_settings(args, &locations)?;
+    let chosen = match (&args.location, settings.as_slice()) {
+        (None, [only]) => only,
+        (None, _) => return Err(Error::Config("LOCATION lists several locations, pick one with --location".to_string())),
+        (Some(location), _) => settings.iter().find(|s| &s.location == location)
+            .ok_or_else(|| Error::Config(format!("{} is not in LOCATION", location)))?,
+    };
+
+    open_store(args.store, chosen).await
+}
+
+// Syncs one location for good, while this replica leads it.
+async fn sync_location(syncer: Syncer, mut leadership: Leadership, data: bool, status: Option<NodeStatus>) {
+    let location = syncer.location().to_string();
     loop {
-        let args = Args::parse();
-        let auth_header = format!("Bearer {}", token.to_string());
-        let mut headers = HeaderMap::new();
-        headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header).unwrap());
-        headers.insert("accept", HeaderValue::from_static("application/json"));
-
-        let client = Client::builder()
-            .default_headers(headers)
-            .build()?;
-
-        let loc = env::var("LOCATION").expect("LOCATION not set!");
-        let mut connection = create_maindb_client().await;
-        let nb_ids = get_nb_by_loc(loc.to_string()).await;
-        match nb_ids {
-            Ok(ref s) => (),
-            Err(ref e) => println!("{:?}", e),
+        if !leadership.is_leader() {
+            println!("Standing by until this replica leads {}", location);
+            leadership.acquired().await;
+            syncer.resume();
         }
-        let unwraped_nb_ids = &nb_ids.unwrap();
-        let sem = Arc::new(Semaphore::new(100));
-        println!("Processing NBs");
-        for x in unwraped_nb_ids.clone() {
-            let nbid: i32 = x.get(0);
-            let ipv4: String = x.get(1);
-            let region: String = x.get(2);
-            let lke_id: i32 = x.get(3);
-            let nb_payload = LocalNodeBalancerListObject {
-                nb_id: nbid,
-                ipv4: ipv4,
-                region: region,
-                lke_id: lke_id,
-            };
-            let permit = Arc::clone(&sem).acquire_owned().await;
-            task::spawn(async move {
-                let _permit = permit;
-                let _ = update_db_nb(nb_payload).await;
-            });
-            //let _ = tokio::spawn(
-            //    async move {
-            //        let _ = update_db_nb(nb_payload).await;
-            //    }
-            //);
+        match syncer.run_cycle().await {
+            Ok(report) => println!("Cycle complete for {}: {}", location, report),
+            Err(e) => {
+                println!("Cycle failed for {}: {}", location, e);
+                tokio::time::sleep(DISCOVERY_RETRY_DELAY).await;
+                continue;
+            }
         }
 
-        println!("Processing configs");
-        for n in unwraped_nb_ids {
-            let nbid: i32 = n.get(0);
-            let config_url = format!("https://api.linode.com/{}/nodebalancers/{}/configs", api_version.to_string(), nbid);
-            let config_response = client.get(config_url)
-                .send()
-                .await?;
-
-            //println!("{:#?}", config_response.status());
-
-
-            if config_response.status().is_success() {
-                let json: serde_json::Value = config_response.json().await?;
-                let nbconfigdata: NodeBalancerConfigData = serde_json::from_value(json.clone()).unwrap();
-
-                if nbconfigdata.pages == 1 {
-                    for d in nbconfigdata.data {
-                        let configobj: database::NodeBalancerConfigObject = d;
-                        let borrow_configobj = &configobj;
-                        let cfgid = borrow_configobj.id;
-                        let nbid = borrow_configobj.nodebalancer_id;
-                        let _ = tokio::spawn(
-                            async move {
-                                let _ = update_db_config(configobj).await;
-                            }
-                        );
-                    }
-                } else {
-                    let mut page = 1;
-                    let cfgsem = Arc::new(Semaphore::new(100));
-                    while page <= nbconfigdata.pages {
-                        println!("Processing page {}", page);
-                        let config_url = format!("https://api.linode.com/{}/nodebalancers/{}/configs?page={}", api_version.to_string(), nbid, &page);
-
-                        let config_response = client.get(config_url)
-                            .send()
-                            .await?;
-
-                        if config_response.status().is_success() {
-                            let json: serde_json::Value = config_response.json().await?;
-                            let nbconfigdata: NodeBalancerConfigData = serde_json::from_value(json.clone()).unwrap();
-                            for d in nbconfigdata.data {
-                                let configobj: database::NodeBalancerConfigObject = d;
-                                let borrow_configobj = &configobj;
-                                let cfgid = borrow_configobj.id;
-                                let nbid = borrow_configobj.nodebalancer_id;
-                                let permit = Arc::clone(&cfgsem).acquire_owned().await;
-                                task::spawn(async move {
-                                    let _permit = permit;
-                                    let _ = update_db_config(configobj).await;
-                                });
-                                //let _ = tokio::spawn(
-                                //    async move {
-                                //        let _ = update_db_config(configobj).await;
-                                //    }
-                                //);
-                            }
-                        }
-                        page += 1;
-                    }
-                }
-            }
+        if data {
+            print_nodes(syncer.store().as_ref(), status.as_ref()).await;
         }
-        let nbcfg_ids = get_nbcfg_ids().await;
-        println!("Processing nodes");
-        for n in nbcfg_ids.unwrap() {
-            let cfgid: i32 = n.get(0);
-            let nbid: i32 = n.get(1);
-            let node_url = format!("https://api.linode.com/{}/nodebalancers/{}/configs/{}/nodes", api_version.to_string(), nbid, cfgid);
-            let node_response = client.get(node_url)
-                .send()
-                .await?;
-
-            if node_response.status().is_success() {
-                let json: serde_json::Value = node_response.json().await?;
-                let nodedata: NodeListData = serde_json::from_value(json.clone()).unwrap();
-                if nodedata.pages == 1 {
-                    for d in nodedata.data {
-                        let nodeobj: database::NodeObject = d;
-                        let _ = tokio::spawn(
-                            async move {
-                                let _ = update_db_node(nodeobj).await;
-                            }
-                        );
-                    }
-                } else {
-                    let mut page = 1;
-                    let nodesem = Arc::new(Semaphore::new(200));
-                    while page <= nodedata.pages {
-                        println!("Processing node page {}", page);
-                        let node_url = format!("https://api.linode.com/{}/nodebalancers/{}/configs/{}/nodes", api_version.to_string(), nbid, cfgid);
-                        let node_response = client.get(node_url)
-                            .send()
-                            .await?;
-
-                        if node_response.status().is_success() {
-                            let json: serde_json::Value = node_response.json().await?;
-                            let nodedata: NodeListData = serde_json::from_value(json.clone()).unwrap();
-                            for d in nodedata.data {
-                                let nodeobj: database::NodeObject = d;
-                                let permit = Arc::clone(&nodesem).acquire_owned().await;
-                                task::spawn(async move {
-                                    let _permit = permit;
-                                    let _ = update_db_node(nodeobj).await;
-                                });
-                                //let _ = tokio::spawn(
-                                //    async move {
-                                //        let _ = update_db_node(nodeobj).await;
-                                //    }
-                                //);
-                            }
-                        }
-                        page += 1;
-                    }
-                }
-            }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args = Args::parse();
+    match &args.command {
+        Some(Command::Instances { stale_after, expect, all }) => {
+            let maindb = MainDb::new(PgTarget::from_env("MAINDB")?);
+            return print_instances(&maindb, *stale_after, expect, *all).await;
         }
+        Some(Command::Audit { since, nb_id, json }) => {
+            return print_audit(command_store(&args).await?.as_ref(), *since, *nb_id, *json).await;
+        }
+        Some(Command::Drift { nb_id }) => return print_drift(command_store(&args).await?.as_ref(), *nb_id).await,
+        None => {}
+    }
+
+    let api_version = env_var("APIVERSION")?;
+    let locations = location_settings(&args, &location::parse_list(&env_var("LOCATION")?))?;
+    if let Backend::Postgres = args.store {
+        LocationSettings::check_postgres(&locations)?;
+    }
+    // One request budget per account, shared by every location.
+    let client_settings = ClientSettings {
+        api_version,
+        requests_per_minute: args.api_rate_limit,
+        concurrency: args.fetch_concurrency,
+    };
+    let accounts = Accounts::from_vars(&client_settings, |name| env::var(name).ok())?;
+
+    // The main DB is optional; without it NBs are discovered from the API.
+    let maindb = match env::var("MAINDB_HOSTPORT") {
+        Ok(_) => Some(MainDb::new(PgTarget::from_env("MAINDB")?)),
+        Err(_) => None,
+    };
+
+    let drift = match &args.drift_policy {
+        Some(path) => Some(DriftDetector::new(Policy::load(path)?).webhook(args.alert_webhook.clone())),
+        None => None,
+    };
+
+    if args.report_upstream && maindb.is_none() {
+        return Err(Error::Config("--report-upstream needs MAINDB_HOSTPORT".to_string()));
+    }
+    if args.heartbeat && maindb.is_none() {
+        return Err(Error::Config("--heartbeat needs MAINDB_HOSTPORT".to_string()));
+    }
+    if args.leader_election && matches!(args.store, Backend::Sqlite) {
+        return Err(Error::Config("--leader-election needs --store postgres".to_string()));
+    }
+    if args.remediate.is_some() && !args.probe {
+        return Err(Error::Config("--remediate needs --probe".to_string()));
+    }
+    let incremental = args.incremental.then_some(Incremental {
+        full_resync: args.full_resync_interval,
+        node_refresh: args.node_refresh_interval,
+    });
+    let started_at = Utc::now();
+    let instance_id = args.instance_id.clone()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| format!("{}-{}", locations[0].location, std::process::id()));
 
-        if args.data {
-            let mut connection = create_localdb_client().await;
-            let rows = connection.query("SELECT * FROM node JOIN nodebalancer ON node.nodebalancer_id = nodebalancer.nb_id JOIN nodebalancer_config ON nodebalancer_config.nodebalancer_id = nodebalancer.nb_id;", &[]).await?;
-                // Print header
-            println!("{:<10} {:<23} {:<6} {:<10} {:<6} {:<15} {:<15} {:<10} {:<5} {:<3} {:<3}", "ID", "Address", "Status", "Config ID", "NB ID", "IPv4 VIP", "Region", "Algorithm", "Port", "Up", "Down");
-            println!("--------------------------------------------------------------------------------------------------------------------");
-            //println!("{:#?}", rows);
-
-            // Iterate over the rows and print data
-            for row in rows {
-                let id: i32 = row.get(0);
-                let address: String = row.get(1);
-                let status: String = row.get(2);
-                let config_id: i32 = row.get(3);
-                let nb_id: i32 = row.get(4);
-                let nb_id_none: i32 = row.get(5);
-                let vip: String = row.get(6);
-                let nbregion: String = row.get(7);
-                let lke_id: i32 = row.get(8);
-                let c_id: i32 = row.get(9);
-                let algorithm: String = row.get(10);
-                let port: i32 = row.get(11);
-                let up: i32 = row.get(12);
-                let down: i32 = row.get(13);
-                let n_id: i32 = row.get(14);
-                println!("{:<10} {:<23} {:<6} {:<10} {:<6} {:<15} {:<15} {:<10} {:<5} {:<3} {:<3}", id, address, status, config_id, nb_id, vip, nbregion, algorithm, port, up, down);
+    let mut stores = Vec::new();
+    let mut syncs = JoinSet::new();
+    for settings in &locations {
+        let loc = &settings.location;
+        let store = open_store(args.store, settings).await?;
+        let api = accounts.concurrency(settings.fetch_concurrency);
+        let leadership = match args.leader_election {
+            false => Leadership::always(),
+            true => {
+                let election = Election::new(PgTarget::from_env(&settings.localdb_prefix)?, loc);
+                let leadership = election.leadership();
+                tokio::spawn(election.run(args.leader_check_interval));
+                leadership
             }
+        };
+
+        if args.probe {
+            let checker = Checker::new()?.tls_ports(args.probe_tls_ports.clone());
+            let detector = DisagreementDetector::new(args.disagreement_probes).webhook(args.alert_webhook.clone());
+            let retention = Retention {
+                raw: args.probe_raw_retention,
+                resolution: args.probe_resolution,
+                keep: args.probe_retention,
+            };
+            let remediator = args.remediate.map(|mode| {
+                Remediator::new(api.clone(), Arc::clone(&store), mode)
+                    .window(args.remediation_window)
+                    .max_fraction(args.remediation_max_fraction)
+                    .dry_run(args.remediation_dry_run)
+                    .leadership(leadership.clone())
+            });
+            let prober = Prober::new(Arc::clone(&store), checker)
+                .detector(Some(detector))
+                .remediator(remediator)
+                .leadership(leadership.clone())
+                .concurrency(args.probe_concurrency)
+                .retention(retention);
+            tokio::spawn(prober.run(PROBE_TICK));
         }
 
+        let instance = args.heartbeat.then(|| ClientInstance {
+            instance_id: instance_id.clone(),
+            location: loc.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at,
+            last_heartbeat: started_at,
+        });
+        let syncer = Syncer::new(api.clone(), Arc::clone(&store), loc)
+            .maindb(maindb.clone())
+            .discovery(args.discovery)
+            .db_concurrency(settings.db_concurrency)
+            .drift(drift.clone())
+            .incremental(incremental)
+            .report_upstream(args.report_upstream)
+            .heartbeat(instance)
+            .leadership(leadership.clone());
+        if args.traffic_stats {
+            let collector = TrafficCollector::new(api, Arc::clone(&store))
+                .leadership(leadership.clone())
+                .listing(syncer.listing())
+                .retention(args.traffic_retention);
+            tokio::spawn(collector.run(args.traffic_interval));
+        }
+        syncs.spawn(sync_location(syncer, leadership, args.data, args.status.clone()));
+        stores.push((loc.clone(), store));
     }
+
+    if let Some(address) = args.listen {
+        let listener = tokio::net::TcpListener::bind(address).await
+            .map_err(|e| Error::Config(format!("unable to listen on {}: {}", address, e)))?;
+        let router = api::router(stores);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router).await {
+                println!("HTTP API stopped: {}", e);
+            }
+        });
+        println!("Serving the HTTP API on {}", address);
+    }
+
+    // Location loops only end by panicking.
+    while let Some(ended) = syncs.join_next().await {
+        if let Err(e) = ended {
+            println!("A location stopped syncing: {}", e);
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,409 @@
+//! A [`Store`] that keeps everything in process memory, for tests and dry
+//! runs. It follows the SQL stores' rules: unknown enum values are stored as
+//! their default, and node details only include nodes whose config and
+//! nodebalancer are present.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use crate::error::Error;
+use crate::latency;
+use crate::models::{
+    AuditAction,
+    AuditEvent,
+    DriftViolation,
+    LocalNodeBalancerConfigObject,
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeDetailObject,
+    NodeHealth,
+    NodeMode,
+    NodeObject,
+    NodeProbe,
+    NodeState,
+    NodeStatus,
+    Probe,
+    ProbeSample,
+    ProbeTarget,
+    TrafficSample,
+};
+use crate::store::{Store, WriteCounts};
+
+/// One row of the `state` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateRow {
+    pub nodebalancer_id: i32,
+    pub nodebalancer_config_id: i32,
+    pub node_id: i32,
+    pub port: i32,
+    pub lastmode: String,
+    pub current: String,
+}
+
+#[derive(Debug, Clone)]
+struct NodeRow {
+    address: String,
+    status: NodeStatus,
+    mode: NodeMode,
+    config_id: i32,
+    probe: Option<Probe>,
+}
+
+#[derive(Debug, Clone)]
+struct ConfigRow {
+    config: LocalNodeBalancerConfigObject,
+    // Health check settings, see `ProbeTarget`.
+    check: ProbeTarget,
+}
+
+// Keyed like the SQL primary keys, nodebalancer id first.
+#[derive(Debug, Default)]
+struct Tables {
+    nodebalancers: BTreeMap<i32, LocalNodeBalancerListObject>,
+    // Ids of nodebalancers marked removed, which keep their row.
+    removed: BTreeSet<i32>,
+    configs: BTreeMap<(i32, i32), ConfigRow>,
+    nodes: BTreeMap<(i32, i32), NodeRow>,
+    health: BTreeMap<(i32, i32), NodeHealth>,
+    samples: BTreeMap<(i32, i32, DateTime<Utc>, i32), ProbeSample>,
+    traffic: BTreeMap<(i32, DateTime<Utc>), TrafficSample>,
+    state: Vec<StateRow>,
+    audit: Vec<AuditEvent>,
+    drift: BTreeMap<(i32, i32, String, String), DriftViolation>,
+}
+
+// What an enum column reads back as once written.
+fn stored<T: for<'a> From<&'a str> + Default>(known: Option<&str>) -> T {
+    known.map(T::from).unwrap_or_default()
+}
+
+fn upsert<K: Ord, V>(table: &mut BTreeMap<K, V>, key: K, value: V, counts: &mut WriteCounts) {
+    if table.insert(key, value).is_some() {
+        counts.updated += 1;
+    } else {
+        counts.inserted += 1;
+    }
+}
+
+// Deletes the rows of `table` that `keep` rejects, counting them.
+fn delete_unless<K: Ord, V>(table: &mut BTreeMap<K, V>, keep: impl Fn(&K) -> bool, counts: &mut WriteCounts) {
+    let before = table.len();
+    table.retain(|key, _| keep(key));
+    counts.deleted += (before - table.len()) as u64;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Every `update_state` call so far, oldest first.
+    pub fn state(&self) -> Vec<StateRow> {
+        self.tables().state.clone()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn init(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn write_nodebalancer(
+        &self,
+        nodebalancer: LocalNodeBalancerListObject,
+        configs: Vec<NodeBalancerConfigObject>,
+        nodes: Vec<NodeObject>,
+    ) -> Result<WriteCounts, Error> {
+        let mut tables = self.tables();
+        let mut counts = WriteCounts::default();
+
+        // Like the SQL stores, the account is not kept, and rows without
+        // metadata keep what is stored.
+        let row = match tables.nodebalancers.get(&nodebalancer.nb_id) {
+            Some(stored) if !nodebalancer.has_metadata() => LocalNodeBalancerListObject {
+                nb_id: nodebalancer.nb_id,
+                ipv4: nodebalancer.ipv4,
+                region: nodebalancer.region,
+                lke_id: nodebalancer.lke_id,
+                ..stored.clone()
+            },
+            _ => LocalNodeBalancerListObject { account: None, ..nodebalancer },
+        };
+        let nbid = row.nb_id;
+        upsert(&mut tables.nodebalancers, nbid, row, &mut counts);
+        tables.removed.remove(&nbid);
+
+        let config_ids: BTreeSet<i32> = configs.iter().map(|c| c.id).collect();
+        let node_ids: BTreeSet<i32> = nodes.iter().map(|n| n.id).collect();
+        delete_unless(&mut tables.configs, |&(nb, id)| nb != nbid || config_ids.contains(&id), &mut counts);
+        delete_unless(&mut tables.nodes, |&(nb, id)| nb != nbid || node_ids.contains(&id), &mut counts);
+        tables.health.retain(|&(nb, id), _| nb != nbid || node_ids.contains(&id));
+
+        for c in configs {
+            let row = ConfigRow {
+                config: LocalNodeBalancerConfigObject {
+                    id: c.id,
+                    nodebalancer_id: c.nodebalancer_id,
+                    algorithm: stored(c.algorithm.known()),
+                    port: c.port,
+                    up: c.nodes_status.up,
+                    down: c.nodes_status.down,
+                },
+                check: ProbeTarget {
+                    nodebalancer_id: c.nodebalancer_id,
+                    config_id: c.id,
+                    node_id: 0,
+                    address: String::new(),
+                    protocol: stored(c.protocol.known()),
+                    check: stored(c.check.known()),
+                    check_path: c.check_path,
+                    check_body: c.check_body,
+                    check_interval: c.check_interval,
+                    check_timeout: c.check_timeout,
+                },
+            };
+            upsert(&mut tables.configs, (c.nodebalancer_id, c.id), row, &mut counts);
+        }
+
+        for n in nodes {
+            // An upsert leaves the last probe alone, as the SQL stores do.
+            let probe = tables.nodes.get(&(n.nodebalancer_id, n.id)).and_then(|old| old.probe.clone());
+            let row = NodeRow {
+                address: n.address,
+                status: stored(n.status.known()),
+                mode: stored(n.mode.known()),
+                config_id: n.config_id,
+                probe,
+            };
+            upsert(&mut tables.nodes, (n.nodebalancer_id, n.id), row, &mut counts);
+        }
+
+        Ok(counts)
+    }
+
+    async fn remove_nodebalancers(&self, nodebalancer_ids: Vec<i32>) -> Result<WriteCounts, Error> {
+        let mut tables = self.tables();
+        let mut counts = WriteCounts::default();
+        let gone: BTreeSet<i32> = nodebalancer_ids.into_iter()
+            .filter(|id| tables.nodebalancers.contains_key(id) && !tables.removed.contains(id))
+            .collect();
+        delete_unless(&mut tables.configs, |(nb, _)| !gone.contains(nb), &mut counts);
+        delete_unless(&mut tables.nodes, |(nb, _)| !gone.contains(nb), &mut counts);
+        tables.health.retain(|(nb, _), _| !gone.contains(nb));
+        tables.drift.retain(|(nb, ..), _| !gone.contains(nb));
+        counts.deleted += gone.len() as u64;
+        tables.removed.extend(gone);
+
+        Ok(counts)
+    }
+
+    async fn nodebalancer_ids(&self) -> Result<Vec<i32>, Error> {
+        let tables = self.tables();
+        Ok(tables.nodebalancers.keys().filter(|id| !tables.removed.contains(id)).copied().collect())
+    }
+
+    async fn nodebalancers(&self) -> Result<Vec<LocalNodeBalancerListObject>, Error> {
+        let tables = self.tables();
+        Ok(tables.nodebalancers.values().filter(|nb| !tables.removed.contains(&nb.nb_id)).cloned().collect())
+    }
+
+    async fn configs(&self) -> Result<Vec<LocalNodeBalancerConfigObject>, Error> {
+        Ok(self.tables().configs.values().map(|row| row.config.clone()).collect())
+    }
+
+    async fn node_details(&self) -> Result<Vec<NodeDetailObject>, Error> {
+        let tables = self.tables();
+        let mut details: Vec<NodeDetailObject> = tables.nodes.iter().filter_map(|(&(nbid, id), node)| {
+            let nb = tables.nodebalancers.get(&nbid)?;
+            let config = &tables.configs.get(&(nbid, node.config_id))?.config;
+            Some(NodeDetailObject {
+                id,
+                address: node.address.clone(),
+                status: node.status.clone(),
+                mode: node.mode.clone(),
+                config_id: node.config_id,
+                nodebalancer_id: nbid,
+                ipv4: nb.ipv4.clone(),
+                region: nb.region.clone(),
+                nodebalancer_label: nb.label.clone(),
+                algorithm: config.algorithm.clone(),
+                port: config.port,
+                up: config.up,
+                down: config.down,
+                probe: node.probe.clone(),
+            })
+        }).collect();
+        details.sort_by_key(|n| (n.nodebalancer_id, n.config_id, n.id));
+
+        Ok(details)
+    }
+
+    async fn nodes_by_address(&self, ip: &str) -> Result<Vec<NodeDetailObject>, Error> {
+        let mut details = self.node_details().await?;
+        details.retain(|n| n.address.contains(ip));
+
+        Ok(details)
+    }
+
+    async fn probe_targets(&self) -> Result<Vec<ProbeTarget>, Error> {
+        let tables = self.tables();
+        Ok(tables.nodes.iter().filter_map(|(&(nbid, id), node)| {
+            let config = tables.configs.get(&(nbid, node.config_id))?;
+            Some(ProbeTarget { node_id: id, address: node.address.clone(), ..config.check.clone() })
+        }).collect())
+    }
+
+    async fn record_probes(&self, probes: Vec<NodeProbe>) -> Result<(), Error> {
+        let mut tables = self.tables();
+        for p in probes {
+            let Some(node) = tables.nodes.get_mut(&(p.nodebalancer_id, p.node_id)) else { continue };
+            node.probe = Some(Probe { status: stored(p.probe.status.known()), ..p.probe.clone() });
+            let sample = ProbeSample::from(&p);
+            let key = (sample.nodebalancer_id, sample.node_id, sample.probed_at, sample.resolution);
+            tables.samples.entry(key).or_insert(sample);
+        }
+
+        Ok(())
+    }
+
+    async fn probe_samples(&self, since: DateTime<Utc>) -> Result<Vec<ProbeSample>, Error> {
+        Ok(self.tables().samples.values().filter(|s| s.probed_at >= since).cloned().collect())
+    }
+
+    async fn compact_probe_samples(
+        &self,
+        raw_before: DateTime<Utc>,
+        resolution: i32,
+        expire_before: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut tables = self.tables();
+        let (old, kept): (Vec<ProbeSample>, Vec<ProbeSample>) = std::mem::take(&mut tables.samples).into_values()
+            .filter(|s| s.probed_at >= expire_before)
+            .partition(|s| s.resolution == 0 && s.probed_at < raw_before);
+        for s in kept.into_iter().chain(latency::downsample(&old, resolution)) {
+            tables.samples.insert((s.nodebalancer_id, s.node_id, s.probed_at, s.resolution), s);
+        }
+
+        Ok(())
+    }
+
+    async fn record_traffic(&self, samples: Vec<TrafficSample>, expire_before: DateTime<Utc>) -> Result<(), Error> {
+        let mut tables = self.tables();
+        for s in samples {
+            tables.traffic.insert((s.nodebalancer_id, s.at), s);
+        }
+        tables.traffic.retain(|(_, at), _| *at >= expire_before);
+
+        Ok(())
+    }
+
+    async fn traffic_samples(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<TrafficSample>, Error> {
+        Ok(self.tables().traffic.values()
+            .filter(|s| s.at >= since && nodebalancer_id.is_none_or(|id| s.nodebalancer_id == id))
+            .cloned()
+            .collect())
+    }
+
+    async fn node_health(&self) -> Result<Vec<NodeHealth>, Error> {
+        Ok(self.tables().health.values().cloned().collect())
+    }
+
+    async fn record_node_health(&self, rows: Vec<NodeHealth>) -> Result<(), Error> {
+        let mut tables = self.tables();
+        for h in rows {
+            let row = NodeHealth {
+                lastverdict: stored(h.lastverdict.known()),
+                current: stored(h.current.known()),
+                observed: stored(h.observed.known()),
+                ..h
+            };
+            tables.health.insert((row.nodebalancer_id, row.node_id), row);
+        }
+
+        Ok(())
+    }
+
+    async fn node_states(&self) -> Result<Vec<NodeState>, Error> {
+        let mut latest = BTreeMap::new();
+        for row in &self.tables().state {
+            latest.insert((row.nodebalancer_id, row.node_id), NodeState {
+                nodebalancer_id: row.nodebalancer_id,
+                nodebalancer_config_id: row.nodebalancer_config_id,
+                node_id: row.node_id,
+                port: row.port,
+                lastmode: NodeMode::from(row.lastmode.as_str()),
+                current: NodeMode::from(row.current.as_str()),
+            });
+        }
+
+        Ok(latest.into_values().collect())
+    }
+
+    async fn update_state(&self, nbid: i32, nbcfgid: i32, nodeid: i32, port: i32, lastmode: &str, current: &str) -> Result<(), Error> {
+        self.tables().state.push(StateRow {
+            nodebalancer_id: nbid,
+            nodebalancer_config_id: nbcfgid,
+            node_id: nodeid,
+            port,
+            lastmode: lastmode.to_string(),
+            current: current.to_string(),
+        });
+
+        Ok(())
+    }
+
+    async fn record_audit(&self, events: Vec<AuditEvent>) -> Result<(), Error> {
+        let mut tables = self.tables();
+        for e in events {
+            tables.audit.push(AuditEvent { object: stored(e.object.known()), action: stored(e.action.known()), ..e });
+        }
+
+        Ok(())
+    }
+
+    async fn audit_events(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<AuditEvent>, Error> {
+        Ok(self.tables().audit.iter()
+            .filter(|e| e.at >= since && nodebalancer_id.is_none_or(|id| e.nodebalancer_id == id))
+            .cloned()
+            .collect())
+    }
+
+    async fn audited_objects(&self) -> Result<Vec<AuditEvent>, Error> {
+        let tables = self.tables();
+        let mut latest = HashMap::new();
+        for (i, e) in tables.audit.iter().enumerate() {
+            if e.action != AuditAction::Remediated {
+                latest.insert((e.object.clone(), e.nodebalancer_id, e.object_id), i);
+            }
+        }
+        let mut indices: Vec<usize> = latest.into_values().collect();
+        indices.sort();
+
+        Ok(indices.into_iter().map(|i| &tables.audit[i]).filter(|e| e.after.is_some()).cloned().collect())
+    }
+
+    async fn config_drift(&self) -> Result<Vec<DriftViolation>, Error> {
+        Ok(self.tables().drift.values().cloned().collect())
+    }
+
+    async fn record_config_drift(&self, nodebalancer_ids: Vec<i32>, violations: Vec<DriftViolation>) -> Result<(), Error> {
+        let mut tables = self.tables();
+        tables.drift.retain(|key, _| !nodebalancer_ids.contains(&key.0));
+        for v in violations {
+            tables.drift.insert((v.nodebalancer_id, v.config_id, v.rule.clone(), v.field.clone()), v);
+        }
+
+        Ok(())
+    }
+}
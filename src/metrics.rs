@@ -0,0 +1,166 @@
+//! Prometheus metrics, rendered from the store on every scrape so that any
+//! instance sharing the local DB reports the same values.
+
+use chrono::Utc;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::error::Error;
+use crate::latency::{self, ago, NodeLatency};
+use crate::models::{DriftViolation, NodeDetailObject, NodeStatus, TrafficSample};
+use crate::store::Store;
+use crate::traffic;
+
+/// Window the latency percentiles and availability are computed over.
+pub const LATENCY_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// How old the latest traffic sample of a nodebalancer may be to be
+/// exported. Linode's 5 minute averages come in late.
+pub const TRAFFIC_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Metrics in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Exposition {
+    /// Starts a metric family. Its samples must follow before the next one.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, String)], value: f64) {
+        let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+        let _ = writeln!(self.text, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+// What one location's store holds, read once per scrape.
+struct Snapshot<'a> {
+    location: &'a str,
+    nodes: Vec<NodeDetailObject>,
+    latencies: Vec<NodeLatency>,
+    drift: Vec<DriftViolation>,
+    traffic: Vec<TrafficSample>,
+}
+
+/// Node status as Linode and the local probe see it, probe latency, config
+/// drift and nodebalancer traffic, of every location, labelled by
+/// `location`.
+pub async fn render(stores: &[(String, Arc<dyn Store>)]) -> Result<String, Error> {
+    let mut snapshots = Vec::new();
+    for (location, store) in stores {
+        snapshots.push(Snapshot {
+            location,
+            nodes: store.node_details().await?,
+            latencies: latency::summarize(&store.probe_samples(ago(Utc::now(), LATENCY_WINDOW)).await?),
+            drift: store.config_drift().await?,
+            traffic: store.traffic_samples(ago(Utc::now(), TRAFFIC_WINDOW), None).await?,
+        });
+    }
+    let mut out = Exposition::default();
+
+    out.family("hc_nb_node_up", "gauge", "Whether the node is UP, as reported by Linode and by the local probe.");
+    for snapshot in &snapshots {
+        for n in &snapshot.nodes {
+            let labels = |source: &str| vec![
+                ("location", snapshot.location.to_string()),
+                ("nodebalancer_id", n.nodebalancer_id.to_string()),
+                ("config_id", n.config_id.to_string()),
+                ("node_id", n.id.to_string()),
+                ("address", n.address.clone()),
+                ("source", source.to_string()),
+            ];
+            out.sample("hc_nb_node_up", &labels("linode"), f64::from(n.status == NodeStatus::Up));
+            if let Some(probe) = &n.probe {
+                out.sample("hc_nb_node_up", &labels("probe"), f64::from(probe.status == NodeStatus::Up));
+            }
+        }
+    }
+
+    let node_labels = |location: &str, l: &NodeLatency| vec![
+        ("location", location.to_string()),
+        ("nodebalancer_id", l.nodebalancer_id.to_string()),
+        ("node_id", l.node_id.to_string()),
+    ];
+    out.family("hc_nb_node_probe_latency_ms", "gauge", "Latency percentiles of passing probes over the last 15 minutes.");
+    for snapshot in &snapshots {
+        for l in &snapshot.latencies {
+            for (quantile, value) in [("0.5", l.p50_ms), ("0.95", l.p95_ms)] {
+                if let Some(value) = value {
+                    let mut labels = node_labels(snapshot.location, l);
+                    labels.push(("quantile", quantile.to_string()));
+                    out.sample("hc_nb_node_probe_latency_ms", &labels, value);
+                }
+            }
+        }
+    }
+    out.family("hc_nb_node_probe_ttfb_ms", "gauge", "95th percentile time to first byte of HTTP probes over the last 15 minutes.");
+    for snapshot in &snapshots {
+        for l in &snapshot.latencies {
+            if let Some(value) = l.ttfb_p95_ms {
+                let mut labels = node_labels(snapshot.location, l);
+                labels.push(("quantile", "0.95".to_string()));
+                out.sample("hc_nb_node_probe_ttfb_ms", &labels, value);
+            }
+        }
+    }
+    out.family("hc_nb_node_probe_availability", "gauge", "Share of probes that passed over the last 15 minutes.");
+    for snapshot in &snapshots {
+        for l in &snapshot.latencies {
+            out.sample("hc_nb_node_probe_availability", &node_labels(snapshot.location, l), l.availability);
+        }
+    }
+
+    out.family("hc_nb_config_drift", "gauge", "Config fields that do not meet a rule of the drift policy.");
+    for snapshot in &snapshots {
+        for v in &snapshot.drift {
+            let labels = vec![
+                ("location", snapshot.location.to_string()),
+                ("nodebalancer_id", v.nodebalancer_id.to_string()),
+                ("config_id", v.config_id.to_string()),
+                ("port", v.port.to_string()),
+                ("rule", v.rule.clone()),
+                ("field", v.field.clone()),
+            ];
+            out.sample("hc_nb_config_drift", &labels, 1.0);
+        }
+    }
+
+    let nb_labels = |location: &str, s: &TrafficSample| vec![
+        ("location", location.to_string()),
+        ("nodebalancer_id", s.nodebalancer_id.to_string()),
+    ];
+    out.family("hc_nb_nodebalancer_connections", "gauge", "Connections of the nodebalancer in its latest 5 minute average from Linode.");
+    for snapshot in &snapshots {
+        for s in traffic::latest(&snapshot.traffic) {
+            if let Some(connections) = s.connections {
+                out.sample("hc_nb_nodebalancer_connections", &nb_labels(snapshot.location, s), connections);
+            }
+        }
+    }
+    out.family("hc_nb_nodebalancer_traffic_bits_per_second", "gauge", "Traffic of the nodebalancer in its latest 5 minute average from Linode.");
+    for snapshot in &snapshots {
+        for s in traffic::latest(&snapshot.traffic) {
+            for (direction, value) in [("in", s.traffic_in), ("out", s.traffic_out)] {
+                if let Some(value) = value {
+                    let mut labels = nb_labels(snapshot.location, s);
+                    labels.push(("direction", direction.to_string()));
+                    out.sample("hc_nb_nodebalancer_traffic_bits_per_second", &labels, value);
+                }
+            }
+        }
+    }
+
+    Ok(out.into_text())
+}
@@ -0,0 +1,655 @@
+//! Linode API objects and the rows derived from them.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+// Treats an explicit `null` like a missing field. Paired with
+// `#[serde(default)]`, so API objects only need their ids to deserialize.
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A NodeBalancer from `GET /nodebalancers`.
+#[derive(serde::Deserialize, Serialize, Debug)]
+pub struct NodeBalancerListObject {
+    #[serde(default, deserialize_with = "nullable")]
+    pub client_conn_throttle: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub created: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub hostname: String,
+    pub id: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub ipv4: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub ipv6: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub label: String,
+    #[serde(default)]
+    pub lke_cluster: Option<LkeCluster>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub region: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub r#type: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub updated: String,
+    /// Fields the API sends that are not modelled above.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// A nodebalancer row as stored in the local DB and in the main DB.
+///
+/// Main DB rows only have the id, addresses and LKE cluster id; the rest is
+/// only known when the nodebalancer is listed from the API.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct LocalNodeBalancerListObject {
+    pub nb_id: i32,
+    pub ipv4: String,
+    pub region: String,
+    pub lke_id: Option<i32>,
+    #[serde(default)]
+    pub label: String,
+    /// The Linode account the nodebalancer belongs to, when the main DB row
+    /// or discovery tells. Not kept in the local DB.
+    #[serde(default)]
+    pub account: Option<String>,
+    /// When the nodebalancer last changed, as the API lists it.
+    #[serde(default)]
+    pub updated: String,
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub ipv6: String,
+    #[serde(default)]
+    pub client_conn_throttle: i32,
+    #[serde(default, rename = "type")]
+    pub nb_type: String,
+    #[serde(default)]
+    pub created: String,
+    #[serde(default)]
+    pub lke_label: Option<String>,
+    #[serde(default)]
+    pub lke_type: Option<String>,
+    #[serde(default)]
+    pub lke_url: Option<String>,
+}
+
+impl LocalNodeBalancerListObject {
+    /// Whether the row was listed from the API with its label and the rest
+    /// of its metadata. Every nodebalancer the API lists has a `created`.
+    pub fn has_metadata(&self) -> bool {
+        !self.created.is_empty()
+    }
+}
+
+impl From<NodeBalancerListObject> for LocalNodeBalancerListObject {
+    fn from(nb: NodeBalancerListObject) -> Self {
+        LocalNodeBalancerListObject {
+            nb_id: nb.id,
+            ipv4: nb.ipv4,
+            region: nb.region,
+            lke_id: nb.lke_cluster.as_ref().map(|lke| lke.id),
+            label: nb.label,
+            account: None,
+            updated: nb.updated,
+            hostname: nb.hostname,
+            ipv6: nb.ipv6,
+            client_conn_throttle: nb.client_conn_throttle,
+            nb_type: nb.r#type,
+            created: nb.created,
+            lke_label: nb.lke_cluster.as_ref().map(|lke| lke.label.clone()),
+            lke_type: nb.lke_cluster.as_ref().map(|lke| lke.r#type.clone()),
+            lke_url: nb.lke_cluster.map(|lke| lke.url),
+        }
+    }
+}
+
+/// A backend node from `GET /nodebalancers/{id}/configs/{id}/nodes`.
+#[derive(serde::Deserialize, Serialize, Debug)]
+pub struct NodeObject {
+    #[serde(default, deserialize_with = "nullable")]
+    pub address: String,
+    pub config_id: i32,
+    pub id: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub label: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub mode: NodeMode,
+    pub nodebalancer_id: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub status: NodeStatus,
+    #[serde(default, deserialize_with = "nullable")]
+    pub weight: i32,
+    /// Fields the API sends that are not modelled above.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// The LKE cluster a NodeBalancer was created for.
+#[derive(serde::Deserialize, Serialize, Debug, Default)]
+pub struct LkeCluster{
+    pub id: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub label: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub r#type: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub url: String,
+    /// Fields the API sends that are not modelled above.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// A port configuration from `GET /nodebalancers/{id}/configs`.
+#[derive(serde::Deserialize, Serialize, Debug, Clone)]
+pub struct NodeBalancerConfigObject {
+    #[serde(default, deserialize_with = "nullable")]
+    pub algorithm: Algorithm,
+    #[serde(default, deserialize_with = "nullable")]
+    pub check: CheckType,
+    #[serde(default, deserialize_with = "nullable")]
+    pub check_attempts: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub check_body: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub check_interval: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub check_passive: bool,
+    #[serde(default, deserialize_with = "nullable")]
+    pub check_path: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub check_timeout: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub cipher_suite: CipherSuite,
+    pub id: i32,
+    pub nodebalancer_id: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub nodes_status: NodesStatus,
+    #[serde(default, deserialize_with = "nullable")]
+    pub port: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub protocol: Protocol,
+    #[serde(default, deserialize_with = "nullable")]
+    pub proxy_protocol: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub stickiness: Stickiness,
+    #[serde(default, deserialize_with = "nullable")]
+    pub udp_check_port: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub udp_session_timeout: i32,
+    /// Fields the API sends that are not modelled above.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// Node counts by status, as reported on a config.
+#[derive(serde::Deserialize, Serialize, Debug, Default, Clone)]
+pub struct NodesStatus {
+    #[serde(default, deserialize_with = "nullable")]
+    pub down: i32,
+    #[serde(default, deserialize_with = "nullable")]
+    pub up: i32,
+}
+
+/// A config row as stored in the local DB.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LocalNodeBalancerConfigObject {
+    pub id: i32,
+    pub nodebalancer_id: i32,
+    pub algorithm: Algorithm,
+    pub port: i32,
+    pub up: i32,
+    pub down: i32,
+}
+
+/// A local node row together with its config and nodebalancer.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct NodeDetailObject {
+    pub id: i32,
+    pub address: String,
+    pub status: NodeStatus,
+    pub mode: NodeMode,
+    pub config_id: i32,
+    pub nodebalancer_id: i32,
+    pub ipv4: String,
+    pub region: String,
+    /// The label of its nodebalancer, empty until it is listed from the API.
+    pub nodebalancer_label: String,
+    pub algorithm: Algorithm,
+    pub port: i32,
+    pub up: i32,
+    pub down: i32,
+    /// The last local probe, `None` until the node has been probed.
+    pub probe: Option<Probe>,
+}
+
+/// The outcome of probing a node from inside the datacenter, as opposed to
+/// the `status` Linode's health checker reports for it.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Probe {
+    /// `Up` when the check passed, `Down` otherwise.
+    pub status: NodeStatus,
+    /// Time taken by a passing check.
+    pub latency_ms: Option<f64>,
+    /// Why a failing check failed.
+    pub error: Option<String>,
+    pub probed_at: DateTime<Utc>,
+}
+
+/// Where the time of a probe went. Phases the check did not reach, or that
+/// do not apply to it, are `None`.
+#[derive(serde::Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ProbeTimings {
+    pub connect_ms: Option<f64>,
+    /// TLS handshake, for nodes probed with [`Tls`](crate::probe::ProbeKind::Tls).
+    pub tls_ms: Option<f64>,
+    /// From sending an HTTP check's request to the first byte of the response.
+    pub ttfb_ms: Option<f64>,
+    pub http_status: Option<i32>,
+}
+
+/// A probe of one node, to be recorded in the store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeProbe {
+    pub nodebalancer_id: i32,
+    pub node_id: i32,
+    pub probe: Probe,
+    pub timings: ProbeTimings,
+}
+
+/// A point of a node's probe time series. Raw points hold a single probe;
+/// older ones are downsampled into one point per node and `resolution`
+/// seconds, averaging the timings of the probes that have them.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ProbeSample {
+    pub nodebalancer_id: i32,
+    pub node_id: i32,
+    /// When the probe was taken, or where the downsampled period starts.
+    pub probed_at: DateTime<Utc>,
+    /// Seconds covered by the point, 0 for a raw probe.
+    pub resolution: i32,
+    pub probes: i32,
+    /// Probes that passed.
+    pub up: i32,
+    /// Time taken by passing probes.
+    pub latency_ms: Option<f64>,
+    pub connect_ms: Option<f64>,
+    pub tls_ms: Option<f64>,
+    pub ttfb_ms: Option<f64>,
+    /// The HTTP status, the highest one once downsampled.
+    pub http_status: Option<i32>,
+}
+
+impl From<&NodeProbe> for ProbeSample {
+    fn from(p: &NodeProbe) -> Self {
+        ProbeSample {
+            nodebalancer_id: p.nodebalancer_id,
+            node_id: p.node_id,
+            probed_at: p.probe.probed_at,
+            resolution: 0,
+            probes: 1,
+            up: (p.probe.status == NodeStatus::Up) as i32,
+            latency_ms: p.probe.latency_ms,
+            connect_ms: p.timings.connect_ms,
+            tls_ms: p.timings.tls_ms,
+            ttfb_ms: p.timings.ttfb_ms,
+            http_status: p.timings.http_status,
+        }
+    }
+}
+
+/// A `[milliseconds since the epoch, value]` point of a stats series.
+pub type StatsPoint = (f64, Option<f64>);
+
+/// A NodeBalancer's stats from `GET /nodebalancers/{id}/stats`: 5 minute
+/// averages over the last day.
+#[derive(serde::Deserialize, Serialize, Debug, Default)]
+pub struct NodeBalancerStats {
+    #[serde(default)]
+    pub data: NodeBalancerStatsData,
+    #[serde(default, deserialize_with = "nullable")]
+    pub title: String,
+}
+
+#[derive(serde::Deserialize, Serialize, Debug, Default)]
+pub struct NodeBalancerStatsData {
+    #[serde(default, deserialize_with = "nullable")]
+    pub connections: Vec<StatsPoint>,
+    #[serde(default)]
+    pub traffic: TrafficStats,
+}
+
+/// Traffic into and out of a NodeBalancer, in bits per second.
+#[derive(serde::Deserialize, Serialize, Debug, Default)]
+pub struct TrafficStats {
+    #[serde(rename = "in", default, deserialize_with = "nullable")]
+    pub traffic_in: Vec<StatsPoint>,
+    #[serde(rename = "out", default, deserialize_with = "nullable")]
+    pub traffic_out: Vec<StatsPoint>,
+}
+
+/// A point of a nodebalancer's traffic time series, as kept in the
+/// `nodebalancer_traffic` table: the 5 minute averages Linode reports at
+/// `at`. A series Linode has no point for is `None`.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TrafficSample {
+    pub nodebalancer_id: i32,
+    pub at: DateTime<Utc>,
+    pub connections: Option<f64>,
+    /// Bits per second.
+    pub traffic_in: Option<f64>,
+    pub traffic_out: Option<f64>,
+}
+
+/// A node together with the health check settings of its config.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ProbeTarget {
+    pub nodebalancer_id: i32,
+    pub config_id: i32,
+    pub node_id: i32,
+    pub address: String,
+    pub protocol: Protocol,
+    pub check: CheckType,
+    pub check_path: String,
+    pub check_body: String,
+    /// Seconds between checks, 0 when unset.
+    pub check_interval: i32,
+    /// Seconds before a check times out, 0 when unset.
+    pub check_timeout: i32,
+}
+
+/// The latest `state` row of a node: the mode it was switched to by the
+/// client (`current`) and the one it had before (`lastmode`).
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct NodeState {
+    pub nodebalancer_id: i32,
+    pub nodebalancer_config_id: i32,
+    pub node_id: i32,
+    pub port: i32,
+    pub lastmode: NodeMode,
+    pub current: NodeMode,
+}
+
+/// How Linode's view of a node compares with the local probe, tracked per
+/// node like the `state` table tracks modes: the verdict in force
+/// (`current`), the one before it, and the run of identical observations
+/// that may replace it.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct NodeHealth {
+    pub nodebalancer_id: i32,
+    pub config_id: i32,
+    pub node_id: i32,
+    pub port: i32,
+    pub address: String,
+    pub lastverdict: Verdict,
+    pub current: Verdict,
+    /// The verdict of the latest probe.
+    pub observed: Verdict,
+    /// Consecutive probes that observed `observed`.
+    pub streak: i32,
+    /// When `current` took effect.
+    pub since: DateTime<Utc>,
+    /// The latest probe taken into account.
+    pub probed_at: DateTime<Utc>,
+}
+
+/// A config setting that breaks a rule of the drift policy, as kept in the
+/// `config_drift` table.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DriftViolation {
+    pub nodebalancer_id: i32,
+    pub config_id: i32,
+    pub port: i32,
+    /// The name of the rule.
+    pub rule: String,
+    /// The config field, e.g. `check_attempts`.
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+    /// When the violation was first seen.
+    pub since: DateTime<Utc>,
+}
+
+/// A node that Linode or the local probe sees DOWN, as reported upstream.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct UnhealthyNode {
+    pub nodebalancer_id: i32,
+    pub config_id: i32,
+    pub node_id: i32,
+    pub address: String,
+    pub status: NodeStatus,
+    /// `None` until the node has been probed.
+    pub probe_status: Option<NodeStatus>,
+    pub mode: NodeMode,
+}
+
+/// What one datacenter saw in one sync cycle, as written to the
+/// `datacenter_report` table of the main DB.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DatacenterReport {
+    pub location: String,
+    /// When the cycle started, which together with `location` names it.
+    pub synced_at: DateTime<Utc>,
+    pub nodebalancers: i32,
+    pub configs: i32,
+    pub nodes: i32,
+    /// Nodes Linode reports UP and DOWN.
+    pub up: i32,
+    pub down: i32,
+    /// Failed fetches and writes in the cycle.
+    pub errors: i32,
+    pub unhealthy: Vec<UnhealthyNode>,
+}
+
+/// One running client, as registered in the `client_instance` table of the
+/// main DB.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ClientInstance {
+    pub instance_id: String,
+    pub location: String,
+    pub version: String,
+    pub started_at: DateTime<Utc>,
+    /// The end of its last sync cycle.
+    pub last_heartbeat: DateTime<Utc>,
+}
+
+/// One entry of the append-only audit log: a nodebalancer, config or node
+/// the sync saw appear, change or disappear, or a mode switch the client
+/// made itself. `before` and `after` hold the audited fields of the object
+/// and are `None` on the side where it did not exist.
+#[derive(serde::Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub at: DateTime<Utc>,
+    pub object: AuditObject,
+    pub action: AuditAction,
+    pub nodebalancer_id: i32,
+    /// The id of the nodebalancer, config or node.
+    pub object_id: i32,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+// Declares a string-valued API enum. Values this build does not know about
+// are kept in `Unknown` instead of failing to deserialize, and are stored as
+// NULL so the column's CHECK constraint only ever sees known values.
+macro_rules! api_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident => $value:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant,)+
+            Unknown(String),
+        }
+
+        impl $name {
+            pub const KNOWN: &'static [&'static str] = &[$($value),+];
+
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $($name::$variant => $value,)+
+                    $name::Unknown(value) => value,
+                }
+            }
+
+            /// The value to write to the DB, `None` when it is not a known one.
+            pub fn known(&self) -> Option<&'static str> {
+                match self {
+                    $($name::$variant => Some($value),)+
+                    $name::Unknown(_) => None,
+                }
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(value: &str) -> Self {
+                $(if value.eq_ignore_ascii_case($value) {
+                    return $name::$variant;
+                })+
+                $name::Unknown(value.to_string())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Infallible;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                Ok($name::from(value))
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name::Unknown(String::new())
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                Ok($name::from(String::deserialize(deserializer)?.as_str()))
+            }
+        }
+    };
+}
+
+api_enum! {
+    /// Health of a backend node as reported by the NodeBalancer. Linode
+    /// reports `unknown` until the node has been checked.
+    NodeStatus {
+        Up => "UP",
+        Down => "DOWN",
+        Unchecked => "unknown",
+    }
+}
+
+api_enum! {
+    /// Whether a node receives traffic.
+    NodeMode {
+        Accept => "accept",
+        Reject => "reject",
+        Drain => "drain",
+        Backup => "backup",
+    }
+}
+
+api_enum! {
+    Algorithm {
+        Roundrobin => "roundrobin",
+        Leastconn => "leastconn",
+        Source => "source",
+        RingHash => "ring_hash",
+        Random => "random",
+    }
+}
+
+api_enum! {
+    Protocol {
+        Http => "http",
+        Https => "https",
+        Tcp => "tcp",
+        Udp => "udp",
+    }
+}
+
+api_enum! {
+    /// Active health check performed by the NodeBalancer.
+    CheckType {
+        None => "none",
+        Connection => "connection",
+        Http => "http",
+        HttpBody => "http_body",
+    }
+}
+
+api_enum! {
+    Stickiness {
+        None => "none",
+        Table => "table",
+        HttpCookie => "http_cookie",
+        Session => "session",
+        SourceIp => "source_ip",
+    }
+}
+
+api_enum! {
+    CipherSuite {
+        Recommended => "recommended",
+        Legacy => "legacy",
+        None => "none",
+    }
+}
+
+api_enum! {
+    /// Whether Linode's health checker and the local probe agree on a node.
+    Verdict {
+        Agree => "agree",
+        LinodeUpProbeDown => "linode_up_probe_down",
+        LinodeDownProbeUp => "linode_down_probe_up",
+    }
+}
+
+api_enum! {
+    /// What an audit event is about.
+    AuditObject {
+        Nodebalancer => "nodebalancer",
+        Config => "config",
+        Node => "node",
+    }
+}
+
+api_enum! {
+    /// What happened to the object of an audit event. `Remediated` is a
+    /// switch made by the client; the others were observed by the sync.
+    AuditAction {
+        Added => "added",
+        Removed => "removed",
+        Changed => "changed",
+        Remediated => "remediated",
+    }
+}
+
+/// `CHECK` constraint body limiting `column` to the known values of an enum.
+pub fn check_known(column: &str, known: &[&str]) -> String {
+    let values: Vec<String> = known.iter().map(|v| format!("'{}'", v)).collect();
+    format!("CHECK (\"{}\" IN ({}))", column, values.join(", "))
+}
@@ -0,0 +1,379 @@
+//! Active health probing of backend nodes from inside the datacenter, to
+//! compare with the status Linode's own health checker reports.
+
+use chrono::Utc;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_openssl::SslStream;
+use crate::disagreement::{Alert, DisagreementDetector};
+use crate::error::Error;
+use crate::latency::Retention;
+use crate::leader::Leadership;
+use crate::models::{CheckType, NodeProbe, NodeStatus, Probe, ProbeTarget, ProbeTimings, Protocol};
+use crate::remediation::{RemediationReport, Remediator};
+use crate::store::Store;
+
+// Linode's defaults for configs that leave the check settings unset.
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+// How often the probe time series is downsampled and expired.
+const COMPACT_INTERVAL: Duration = Duration::from_secs(60);
+// How often nodes are considered for remediation.
+const REMEDIATION_INTERVAL: Duration = Duration::from_secs(30);
+// HTTP responses are read up to this size when looking for `check_body`.
+const MAX_RESPONSE_BYTES: usize = 1 << 20;
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+fn seconds_or(seconds: i32, default: Duration) -> Duration {
+    if seconds > 0 {
+        Duration::from_secs(seconds as u64)
+    } else {
+        default
+    }
+}
+
+/// How a node is checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeKind {
+    /// Open a TCP connection.
+    Tcp,
+    /// Complete a TLS handshake. The certificate is not verified.
+    Tls,
+    /// `GET` the path over plain HTTP, as the NodeBalancer does, expecting
+    /// a 2xx or 3xx and, when set, a body containing `body`.
+    Http { path: String, body: Option<String> },
+}
+
+impl ProbeKind {
+    /// Follows the config's `check`. Connection checks on `tcp` configs
+    /// become a TLS handshake when the node listens on one of `tls_ports`,
+    /// since the NodeBalancer passes TLS straight through to it. UDP nodes
+    /// are not probed.
+    pub fn for_target(target: &ProbeTarget, tls_ports: &[u16]) -> Option<Self> {
+        if target.protocol == Protocol::Udp {
+            return None;
+        }
+        let path = if target.check_path.is_empty() { "/".to_string() } else { target.check_path.clone() };
+        match target.check {
+            CheckType::Http => Some(ProbeKind::Http { path, body: None }),
+            CheckType::HttpBody => {
+                let body = Some(target.check_body.clone()).filter(|b| !b.is_empty());
+                Some(ProbeKind::Http { path, body })
+            }
+            _ => {
+                let port = target.address.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok());
+                let passthrough = target.protocol == Protocol::Tcp;
+                if passthrough && port.is_some_and(|p| tls_ports.contains(&p)) {
+                    Some(ProbeKind::Tls)
+                } else {
+                    Some(ProbeKind::Tcp)
+                }
+            }
+        }
+    }
+}
+
+/// Runs single checks against nodes.
+#[derive(Clone)]
+pub struct Checker {
+    tls: SslConnector,
+    tls_ports: Vec<u16>,
+}
+
+impl Checker {
+    pub fn new() -> Result<Self, Error> {
+        let tls_error = |e: openssl::error::ErrorStack| Error::Config(format!("unable to set up probe TLS: {}", e));
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+        builder.set_verify(SslVerifyMode::NONE);
+
+        Ok(Checker { tls: builder.build(), tls_ports: vec![443] })
+    }
+
+    /// Node ports that get a TLS handshake, see [`ProbeKind::for_target`].
+    pub fn tls_ports(mut self, tls_ports: Vec<u16>) -> Self {
+        self.tls_ports = tls_ports;
+        self
+    }
+
+    pub fn kind(&self, target: &ProbeTarget) -> Option<ProbeKind> {
+        ProbeKind::for_target(target, &self.tls_ports)
+    }
+
+    /// Checks one node within its config's `check_timeout`, or `None` when
+    /// the node is not probed at all.
+    pub async fn probe(&self, target: &ProbeTarget) -> Option<Probe> {
+        self.measure(target).await.map(|(probe, _)| probe)
+    }
+
+    /// Like [`probe`](Self::probe), along with how long each phase of the
+    /// check took, failing or not.
+    pub async fn measure(&self, target: &ProbeTarget) -> Option<(Probe, ProbeTimings)> {
+        let kind = self.kind(target)?;
+        let timeout = seconds_or(target.check_timeout, DEFAULT_CHECK_TIMEOUT);
+        let probed_at = Utc::now();
+        let started = Instant::now();
+        let mut timings = ProbeTimings::default();
+        let outcome = match tokio::time::timeout(timeout, self.check(&kind, &target.address, &mut timings)).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(format!("timed out after {:?}", timeout)),
+        };
+
+        let probe = match outcome {
+            Ok(()) => Probe {
+                status: NodeStatus::Up,
+                latency_ms: Some(elapsed_ms(started)),
+                error: None,
+                probed_at,
+            },
+            Err(error) => Probe { status: NodeStatus::Down, latency_ms: None, error: Some(error), probed_at },
+        };
+        Some((probe, timings))
+    }
+
+    async fn check(&self, kind: &ProbeKind, address: &str, timings: &mut ProbeTimings) -> Result<(), String> {
+        let started = Instant::now();
+        let tcp = TcpStream::connect(address).await.map_err(|e| e.to_string())?;
+        timings.connect_ms = Some(elapsed_ms(started));
+
+        match kind {
+            ProbeKind::Tcp => Ok(()),
+            ProbeKind::Tls => {
+                let started = Instant::now();
+                let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+                let ssl = self.tls.configure()
+                    .and_then(|c| c.verify_hostname(false).into_ssl(host))
+                    .map_err(|e| e.to_string())?;
+                let mut stream = SslStream::new(ssl, tcp).map_err(|e| e.to_string())?;
+                Pin::new(&mut stream).connect().await.map_err(|e| format!("TLS handshake failed: {}", e))?;
+                timings.tls_ms = Some(elapsed_ms(started));
+                Ok(())
+            }
+            ProbeKind::Http { path, body } => http_check(tcp, address, path, body.as_deref(), timings).await,
+        }
+    }
+}
+
+// A bare HTTP/1.0 `GET` on the connection that was just timed, so the time
+// to first byte is not mixed up with connecting. Redirects are not followed.
+async fn http_check(
+    mut tcp: TcpStream,
+    address: &str,
+    path: &str,
+    expected: Option<&str>,
+    timings: &mut ProbeTimings,
+) -> Result<(), String> {
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: hc-nb-api-client\r\nConnection: close\r\n\r\n", path, address);
+    let started = Instant::now();
+    tcp.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let read = tcp.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        if response.is_empty() {
+            timings.ttfb_ms = Some(elapsed_ms(started));
+        }
+        response.extend_from_slice(&buffer[..read]);
+        let status_line_read = response.windows(2).any(|w| w == b"\r\n");
+        if (expected.is_none() && status_line_read) || response.len() >= MAX_RESPONSE_BYTES {
+            break;
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status: i32 = response.split_whitespace().nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or("malformed HTTP response")?;
+    timings.http_status = Some(status);
+    if !(200..400).contains(&status) {
+        return Err(format!("HTTP {}", status));
+    }
+    if let Some(expected) = expected {
+        let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+        if !body.contains(expected) {
+            return Err(format!("response body does not contain {:?}", expected));
+        }
+    }
+
+    Ok(())
+}
+
+/// Totals for one probing round. `disagreeing` and `alerts` stay empty
+/// without a [`DisagreementDetector`], and `remediation` is only set on the
+/// rounds a [`Remediator`] ran in.
+#[derive(Debug, Default, Clone)]
+pub struct ProbeReport {
+    pub probed: u64,
+    pub up: u64,
+    pub down: u64,
+    pub disagreeing: u64,
+    pub alerts: Vec<Alert>,
+    pub remediation: Option<RemediationReport>,
+    pub duration: Duration,
+}
+
+impl std::fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} probed, {} up, {} down, {} disagreeing with Linode in {:.2?}",
+            self.probed, self.up, self.down, self.disagreeing, self.duration
+        )
+    }
+}
+
+/// Probes every stored node on its config's `check_interval` and records the
+/// results in the store next to Linode's view.
+pub struct Prober {
+    store: Arc<dyn Store>,
+    checker: Checker,
+    detector: Option<DisagreementDetector>,
+    remediator: Option<Remediator>,
+    leadership: Leadership,
+    concurrency: usize,
+    retention: Retention,
+    // When each (nodebalancer, node) is next due.
+    next_due: HashMap<(i32, i32), Instant>,
+    compacted: Option<Instant>,
+    remediated: Option<Instant>,
+}
+
+impl Prober {
+    pub fn new(store: Arc<dyn Store>, checker: Checker) -> Self {
+        Prober {
+            store,
+            checker,
+            detector: None,
+            remediator: None,
+            leadership: Leadership::always(),
+            concurrency: 64,
+            retention: Retention::default(),
+            next_due: HashMap::new(),
+            compacted: None,
+            remediated: None,
+        }
+    }
+
+    /// Compares each round's probes with Linode's status.
+    pub fn detector(mut self, detector: Option<DisagreementDetector>) -> Self {
+        self.detector = detector;
+        self
+    }
+
+    /// Switches nodes that keep failing probes out of rotation.
+    pub fn remediator(mut self, remediator: Option<Remediator>) -> Self {
+        self.remediator = remediator;
+        self
+    }
+
+    /// Only probes while this replica leads, and only records a round's
+    /// results once it made sure it still does.
+    pub fn leadership(mut self, leadership: Leadership) -> Self {
+        self.leadership = leadership;
+        self
+    }
+
+    /// Maximum number of probes in flight.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// How long the probe time series is kept.
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Probes the nodes that are due and records the results. Nodes are
+    /// re-read from the store each round, so it follows the sync loop.
+    pub async fn run_round(&mut self) -> Result<ProbeReport, Error> {
+        let started = Instant::now();
+        let targets = self.store.probe_targets().await?;
+        let keys: HashSet<(i32, i32)> = targets.iter().map(|t| (t.nodebalancer_id, t.node_id)).collect();
+        self.next_due.retain(|key, _| keys.contains(key));
+
+        let permits = Arc::new(Semaphore::new(self.concurrency.max(1)));
+        let mut probes = JoinSet::new();
+        for target in targets {
+            let key = (target.nodebalancer_id, target.node_id);
+            if self.next_due.get(&key).is_some_and(|due| *due > started) {
+                continue;
+            }
+            self.next_due.insert(key, started + seconds_or(target.check_interval, DEFAULT_CHECK_INTERVAL));
+            let permit = Arc::clone(&permits).acquire_owned().await.expect("probe semaphore closed");
+            let checker = self.checker.clone();
+            probes.spawn(async move {
+                let _permit = permit;
+                let (probe, timings) = checker.measure(&target).await?;
+                Some(NodeProbe { nodebalancer_id: target.nodebalancer_id, node_id: target.node_id, probe, timings })
+            });
+        }
+
+        let mut report = ProbeReport::default();
+        let mut results = Vec::new();
+        while let Some(result) = probes.join_next().await {
+            match result {
+                Ok(Some(probe)) => {
+                    report.probed += 1;
+                    match probe.probe.status {
+                        NodeStatus::Up => report.up += 1,
+                        _ => report.down += 1,
+                    }
+                    results.push(probe);
+                }
+                Ok(None) => {}
+                Err(e) => println!("Probe task failed: {}", e),
+            }
+        }
+        // A replica that lost the lock while probing leaves the results,
+        // the verdicts and the alerts to the new leader.
+        self.leadership.confirm().await?;
+        self.store.record_probes(results).await?;
+        if let Some(detector) = &self.detector {
+            let detection = detector.run(self.store.as_ref()).await?;
+            report.disagreeing = detection.disagreeing;
+            report.alerts = detection.alerts;
+        }
+        if let Some(remediator) = &self.remediator
+            && self.remediated.is_none_or(|at| at.elapsed() >= REMEDIATION_INTERVAL)
+        {
+            report.remediation = Some(remediator.run().await?);
+            self.remediated = Some(Instant::now());
+        }
+        if self.compacted.is_none_or(|at| at.elapsed() >= COMPACT_INTERVAL) {
+            self.retention.apply(self.store.as_ref(), Utc::now()).await?;
+            self.compacted = Some(Instant::now());
+        }
+        report.duration = started.elapsed();
+
+        Ok(report)
+    }
+
+    /// Runs a round every `tick`, forever. Check intervals shorter than the
+    /// tick are stretched to it.
+    pub async fn run(mut self, tick: Duration) {
+        loop {
+            self.leadership.acquired().await;
+            match self.run_round().await {
+                Ok(report) if report.probed > 0 => println!("Probe round complete: {}", report),
+                Ok(_) => {}
+                Err(e) => println!("Probe round failed: {}", e),
+            }
+            tokio::time::sleep(tick).await;
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Request budgeting for the Linode API.
+
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Token bucket shared by every request made against one API budget.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    capacity: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn per_minute(requests: u32) -> Self {
+        let per_second = f64::from(requests.max(1)) / 60.0;
+        // Allow a second's worth of burst, but always at least one request.
+        let capacity = per_second.max(1.0);
+        RateLimiter {
+            per_second,
+            capacity,
+            bucket: Mutex::new(Bucket { tokens: capacity, refilled: Instant::now() }),
+        }
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.capacity);
+                bucket.refilled = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
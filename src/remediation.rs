@@ -0,0 +1,312 @@
+//! Takes nodes that keep failing local probes out of rotation through the
+//! Linode API, and puts them back in their previous mode once they pass
+//! again. Every switch is recorded in the `state` table.
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::audit;
+use crate::error::Error;
+use crate::latency::ago;
+use crate::leader::Leadership;
+use crate::accounts::Accounts;
+use crate::models::{AuditAction, AuditEvent, AuditObject, NodeDetailObject, NodeMode, NodeState, ProbeSample};
+use crate::store::Store;
+
+/// The mode failing nodes are switched to.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemediationMode {
+    /// Finish open connections, send no new ones
+    Drain,
+    /// Send no traffic at all
+    Reject,
+}
+
+impl From<RemediationMode> for NodeMode {
+    fn from(mode: RemediationMode) -> Self {
+        match mode {
+            RemediationMode::Drain => NodeMode::Drain,
+            RemediationMode::Reject => NodeMode::Reject,
+        }
+    }
+}
+
+/// What the probes say about a node over the remediation window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trend {
+    /// Every probe in the window failed.
+    Failing,
+    /// Every probe in the window passed.
+    Passing,
+    /// Mixed results, or the node has not been probed for a whole window.
+    Unsettled,
+}
+
+/// Classifies one node's points, given where the window starts. A point
+/// from before the window is required, so a node that was only just
+/// probed for the first time is never acted on.
+pub fn trend(points: &[&ProbeSample], window_start: DateTime<Utc>) -> Trend {
+    if !points.iter().any(|p| p.probed_at < window_start) {
+        return Trend::Unsettled;
+    }
+    let recent: Vec<_> = points.iter().filter(|p| p.probed_at >= window_start).collect();
+    if recent.is_empty() {
+        Trend::Unsettled
+    } else if recent.iter().all(|p| p.up == 0) {
+        Trend::Failing
+    } else if recent.iter().all(|p| p.up == p.probes) {
+        Trend::Passing
+    } else {
+        Trend::Unsettled
+    }
+}
+
+/// One mode switch, planned or made.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Action {
+    pub nodebalancer_id: i32,
+    pub config_id: i32,
+    pub node_id: i32,
+    pub port: i32,
+    pub address: String,
+    pub from: NodeMode,
+    pub to: NodeMode,
+}
+
+impl Action {
+    fn new(node: &NodeDetailObject, from: NodeMode, to: NodeMode) -> Self {
+        Action {
+            nodebalancer_id: node.nodebalancer_id,
+            config_id: node.config_id,
+            node_id: node.id,
+            port: node.port,
+            address: node.address.clone(),
+            from,
+            to,
+        }
+    }
+
+    /// The audit log entry of the switch, once made.
+    pub fn audit_event(&self, at: DateTime<Utc>) -> AuditEvent {
+        let fields = |mode: &NodeMode| json!({ "config_id": self.config_id, "address": self.address, "mode": mode });
+        AuditEvent {
+            at,
+            object: AuditObject::Node,
+            action: AuditAction::Remediated,
+            nodebalancer_id: self.nodebalancer_id,
+            object_id: self.node_id,
+            before: Some(fields(&self.from)),
+            after: Some(fields(&self.to)),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "NB {} config {} port {} node {} ({}): {} -> {}",
+            self.nodebalancer_id, self.config_id, self.port, self.node_id, self.address, self.from, self.to
+        )
+    }
+}
+
+/// The switches one pass decided on.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+    /// Failing nodes left in rotation because taking them out would exceed
+    /// the per-config limit.
+    pub held_back: Vec<Action>,
+}
+
+/// Outcome of one remediation pass. With `dry_run` nothing was changed.
+#[derive(Debug, Default, Clone)]
+pub struct RemediationReport {
+    pub applied: Vec<Action>,
+    pub held_back: Vec<Action>,
+    pub errors: Vec<String>,
+    pub dry_run: bool,
+}
+
+/// Switches nodes that failed every local probe for `window` to `mode`,
+/// never taking more than `max_fraction` of a config's nodes out of
+/// rotation, and restores them once they passed every probe for `window`.
+///
+/// Only nodes in `accept` mode are taken out, and only nodes the client
+/// took out itself are restored: a node whose mode was changed by hand
+/// since is left alone.
+pub struct Remediator {
+    accounts: Accounts,
+    store: Arc<dyn Store>,
+    mode: NodeMode,
+    window: Duration,
+    max_fraction: f64,
+    dry_run: bool,
+    leadership: Leadership,
+}
+
+impl Remediator {
+    /// Switches nodes through the account of their nodebalancer, see
+    /// [`Accounts`]; a single client also does.
+    pub fn new(accounts: impl Into<Accounts>, store: Arc<dyn Store>, mode: RemediationMode) -> Self {
+        Remediator {
+            accounts: accounts.into(),
+            store,
+            mode: mode.into(),
+            window: Duration::from_secs(5 * 60),
+            max_fraction: 0.34,
+            dry_run: false,
+            leadership: Leadership::always(),
+        }
+    }
+
+    /// How long a node must keep failing, or passing, before it is switched.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Largest share of a config's nodes that may be out of rotation,
+    /// counting nodes that were taken out by hand.
+    pub fn max_fraction(mut self, max_fraction: f64) -> Self {
+        self.max_fraction = max_fraction;
+        self
+    }
+
+    /// Logs what would be switched without calling the API.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Makes sure this replica still leads before every switch, and stops
+    /// switching once it does not.
+    pub fn leadership(mut self, leadership: Leadership) -> Self {
+        self.leadership = leadership;
+        self
+    }
+
+    // Whether the node is out of rotation because of an earlier pass.
+    fn remediated(&self, node: &NodeDetailObject, state: Option<&NodeState>) -> bool {
+        state.is_some_and(|s| {
+            s.current == self.mode && s.lastmode != self.mode && (node.mode == s.current || node.mode == s.lastmode)
+        })
+    }
+
+    /// Decides what to switch from every node, its latest `state` row and
+    /// its probe points since at least two windows ago.
+    pub fn plan(&self, nodes: &[NodeDetailObject], states: &[NodeState], samples: &[ProbeSample], now: DateTime<Utc>) -> Plan {
+        let window_start = ago(now, self.window);
+        let states: HashMap<(i32, i32), &NodeState> = states.iter().map(|s| ((s.nodebalancer_id, s.node_id), s)).collect();
+        let mut points: HashMap<(i32, i32), Vec<&ProbeSample>> = HashMap::new();
+        for s in samples {
+            points.entry((s.nodebalancer_id, s.node_id)).or_default().push(s);
+        }
+        let mut configs: BTreeMap<(i32, i32), Vec<&NodeDetailObject>> = BTreeMap::new();
+        for n in nodes {
+            configs.entry((n.nodebalancer_id, n.config_id)).or_default().push(n);
+        }
+
+        let mut plan = Plan::default();
+        for config_nodes in configs.values() {
+            let trend_of = |n: &NodeDetailObject| {
+                points.get(&(n.nodebalancer_id, n.id)).map_or(Trend::Unsettled, |p| trend(p, window_start))
+            };
+            let mut out = config_nodes.iter().filter(|n| {
+                let state = states.get(&(n.nodebalancer_id, n.id)).copied();
+                let mode = if self.remediated(n, state) { &self.mode } else { &n.mode };
+                matches!(mode, NodeMode::Drain | NodeMode::Reject)
+            }).count();
+
+            // Restores first, so they make room for new failures.
+            for n in config_nodes {
+                let Some(state) = states.get(&(n.nodebalancer_id, n.id)).copied() else { continue };
+                if self.remediated(n, Some(state)) && trend_of(n) == Trend::Passing {
+                    plan.actions.push(Action::new(n, self.mode.clone(), state.lastmode.clone()));
+                    out -= 1;
+                }
+            }
+            for n in config_nodes {
+                let state = states.get(&(n.nodebalancer_id, n.id)).copied();
+                if n.mode != NodeMode::Accept || self.remediated(n, state) || trend_of(n) != Trend::Failing {
+                    continue;
+                }
+                let action = Action::new(n, NodeMode::Accept, self.mode.clone());
+                if (out + 1) as f64 <= self.max_fraction * config_nodes.len() as f64 {
+                    plan.actions.push(action);
+                    out += 1;
+                } else {
+                    plan.held_back.push(action);
+                }
+            }
+        }
+
+        plan
+    }
+
+    /// Plans from the store and makes the switches, one `PUT`, one `state`
+    /// row and one audit event each. A failed switch is reported and
+    /// retried next pass; the pass stops at the first switch this replica
+    /// no longer leads for.
+    pub async fn run(&self) -> Result<RemediationReport, Error> {
+        let now = Utc::now();
+        let nodes = self.store.node_details().await?;
+        let states = self.store.node_states().await?;
+        let samples = self.store.probe_samples(ago(now, self.window * 2)).await?;
+        let plan = self.plan(&nodes, &states, &samples, now);
+
+        let mut report = RemediationReport { held_back: plan.held_back, dry_run: self.dry_run, ..Default::default() };
+        for action in &report.held_back {
+            println!("REMEDIATION held back, too many nodes of the config out of rotation: {}", action);
+        }
+        for action in plan.actions {
+            if self.dry_run {
+                println!("REMEDIATION (dry run): {}", action);
+                report.applied.push(action);
+                continue;
+            }
+            if let Err(e) = self.leadership.confirm().await {
+                let line = format!("REMEDIATION stopped: {}: {}", action, e);
+                println!("{}", line);
+                report.errors.push(line);
+                break;
+            }
+            let switched = match self.accounts.for_nodebalancer(action.nodebalancer_id) {
+                Ok(api) => api.update_node_mode(action.nodebalancer_id, action.config_id, action.node_id, &action.to).await,
+                Err(e) => Err(e),
+            };
+            let recorded = match switched {
+                Ok(_) => self.store.update_state(
+                    action.nodebalancer_id, action.config_id, action.node_id, action.port, action.from.as_str(), action.to.as_str(),
+                ).await,
+                Err(e) => Err(e),
+            };
+            match recorded {
+                Ok(()) => {
+                    println!("REMEDIATION: {}", action);
+                    let events = vec![action.audit_event(Utc::now())];
+                    audit::log(&events);
+                    if let Err(e) = self.store.record_audit(events).await {
+                        let line = format!("REMEDIATION not audited: {}: {}", action, e);
+                        println!("{}", line);
+                        report.errors.push(line);
+                    }
+                    report.applied.push(action);
+                }
+                Err(e) => {
+                    let line = format!("REMEDIATION failed: {}: {}", action, e);
+                    println!("{}", line);
+                    report.errors.push(line);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
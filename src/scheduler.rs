@@ -0,0 +1,158 @@
+//! Cycle bookkeeping: bounded, joined DB writes and the report they add up to.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::{Id, JoinError, JoinSet};
+use crate::error::Error;
+use crate::store::WriteCounts;
+
+/// Totals for one sync cycle, printed once every write has been joined.
+/// `deleted` counts rows of configs, nodes and nodebalancers the API no
+/// longer lists, and `failed` rows that could not be written; `errors`
+/// holds one line per failed fetch or write so the cycle can carry on past
+/// them, and `refused` counts the fetches each account's token was refused
+/// for. `unchanged` counts the nodebalancers an incremental sync did not
+/// fetch the configs of.
+#[derive(Debug, Default, Clone)]
+pub struct CycleReport {
+    pub inserted: u64,
+    pub updated: u64,
+    pub deleted: u64,
+    pub failed: u64,
+    pub errors: Vec<String>,
+    pub refused: BTreeMap<String, u64>,
+    pub unchanged: u64,
+    pub duration: Duration,
+}
+
+impl CycleReport {
+    pub fn record_error(&mut self, context: &str, error: impl std::fmt::Display) {
+        let line = format!("{}: {}", context, error);
+        println!("{}", line);
+        self.errors.push(line);
+    }
+
+    /// Records a failed fetch, counting it against its account if the
+    /// account's token was refused.
+    pub fn record_failure(&mut self, context: &str, error: Error) {
+        if let Error::Unauthorized { account, .. } = &error {
+            *self.refused.entry(account.clone()).or_default() += 1;
+        }
+        self.record_error(context, error);
+    }
+
+    fn record(&mut self, context: &str, rows: u64, result: Result<Result<WriteCounts, Error>, JoinError>) {
+        match result {
+            Ok(Ok(counts)) => {
+                self.inserted += counts.inserted;
+                self.updated += counts.updated;
+                self.deleted += counts.deleted;
+            }
+            Ok(Err(e)) => {
+                self.record_error(context, e);
+                self.failed += rows;
+            }
+            Err(e) => {
+                self.record_error(context, e);
+                self.failed += rows;
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for CycleReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} inserted, {} updated, {} failed, {} errors in {:.2?}",
+            self.inserted, self.updated, self.failed, self.errors.len(), self.duration
+        )?;
+        if self.deleted > 0 {
+            write!(f, ", {} deleted", self.deleted)?;
+        }
+        if self.unchanged > 0 {
+            write!(f, ", {} NBs unchanged", self.unchanged)?;
+        }
+        for (account, refused) in &self.refused {
+            write!(f, ", {} refused for account {}", refused, account)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs DB writes on a `JoinSet` with one concurrency limit shared by every
+/// phase of the cycle, so nothing is left running when the cycle ends.
+pub struct WriteScheduler {
+    tasks: JoinSet<Result<WriteCounts, Error>>,
+    // What each running task is writing and how many rows, charged as
+    // failed if it errors.
+    pending: HashMap<Id, (String, u64)>,
+    permits: Arc<Semaphore>,
+    started: Instant,
+    report: CycleReport,
+}
+
+impl WriteScheduler {
+    pub fn new(concurrency: usize) -> Self {
+        WriteScheduler {
+            tasks: JoinSet::new(),
+            pending: HashMap::new(),
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            started: Instant::now(),
+            report: CycleReport::default(),
+        }
+    }
+
+    fn record(&mut self, result: Result<(Id, Result<WriteCounts, Error>), JoinError>) {
+        let (id, result) = match result {
+            Ok((id, written)) => (id, Ok(written)),
+            Err(e) => (e.id(), Err(e)),
+        };
+        let (context, rows) = self.pending.remove(&id).unwrap_or_default();
+        self.report.record(&context, rows, result);
+    }
+
+    /// The report being built, for failures that happen outside a write.
+    pub fn report(&mut self) -> &mut CycleReport {
+        &mut self.report
+    }
+
+    /// Waits for a free slot, then spawns `write`, which covers `rows` rows
+    /// and is named by `context` in any error. Finished tasks are reaped
+    /// along the way so the set does not grow with the size of the region.
+    pub async fn spawn<F>(&mut self, context: String, rows: u64, write: F)
+    where
+        F: Future<Output = Result<WriteCounts, Error>> + Send + 'static,
+    {
+        while let Some(result) = self.tasks.try_join_next_with_id() {
+            self.record(result);
+        }
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("write semaphore closed");
+        let handle = self.tasks.spawn(async move {
+            let _permit = permit;
+            write.await
+        });
+        self.pending.insert(handle.id(), (context, rows));
+    }
+
+    /// Waits for every spawned write.
+    pub async fn join(&mut self) {
+        while let Some(result) = self.tasks.join_next_with_id().await {
+            self.record(result);
+        }
+    }
+
+    /// Joins outstanding writes and returns the report for the cycle.
+    pub async fn finish(mut self) -> CycleReport {
+        self.join().await;
+        self.report.duration = self.started.elapsed();
+        self.report
+    }
+}
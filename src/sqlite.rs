@@ -0,0 +1,810 @@
+//! Embedded SQLite storage, for small sites and local development that do
+//! not have a managed Postgres.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::error::Error;
+use crate::latency;
+use crate::models::{
+    AuditEvent,
+    DriftViolation,
+    LocalNodeBalancerConfigObject,
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeDetailObject,
+    NodeHealth,
+    NodeObject,
+    NodeProbe,
+    NodeState,
+    Probe,
+    ProbeSample,
+    ProbeTarget,
+    TrafficSample,
+};
+use crate::store::{Store, WriteCounts};
+
+/// Maps a row onto a type by column name, like [`crate::database::FromRow`].
+trait FromRow: Sized {
+    fn from_row(row: &Row) -> rusqlite::Result<Self>;
+}
+
+// Enum columns are NULL when the API sent a value this build did not know.
+fn get_enum<T: for<'a> From<&'a str> + Default>(row: &Row, column: &str) -> rusqlite::Result<T> {
+    let value: Option<String> = row.get(column)?;
+    Ok(value.as_deref().map(T::from).unwrap_or_default())
+}
+
+impl FromRow for LocalNodeBalancerListObject {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(LocalNodeBalancerListObject {
+            nb_id: row.get("id")?,
+            ipv4: row.get("ipv4")?,
+            region: row.get("region")?,
+            lke_id: row.get("lke_id")?,
+            label: row.get("label")?,
+            account: None,
+            updated: row.get("updated")?,
+            hostname: row.get("hostname")?,
+            ipv6: row.get("ipv6")?,
+            client_conn_throttle: row.get("client_conn_throttle")?,
+            nb_type: row.get("type")?,
+            created: row.get("created")?,
+            lke_label: row.get("lke_label")?,
+            lke_type: row.get("lke_type")?,
+            lke_url: row.get("lke_url")?,
+        })
+    }
+}
+
+impl FromRow for LocalNodeBalancerConfigObject {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(LocalNodeBalancerConfigObject {
+            id: row.get("id")?,
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            algorithm: get_enum(row, "algorithm")?,
+            port: row.get("port")?,
+            up: row.get("up")?,
+            down: row.get("down")?,
+        })
+    }
+}
+
+impl FromRow for NodeDetailObject {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(NodeDetailObject {
+            id: row.get("id")?,
+            address: row.get("address")?,
+            status: get_enum(row, "status")?,
+            mode: get_enum(row, "mode")?,
+            config_id: row.get("config_id")?,
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            ipv4: row.get("ipv4")?,
+            region: row.get("region")?,
+            nodebalancer_label: row.get("nodebalancer_label")?,
+            algorithm: get_enum(row, "algorithm")?,
+            port: row.get("port")?,
+            up: row.get("up")?,
+            down: row.get("down")?,
+            probe: get_probe(row)?,
+        })
+    }
+}
+
+fn get_probe(row: &Row) -> rusqlite::Result<Option<Probe>> {
+    let Some(probed_at) = row.get("probed_at")? else {
+        return Ok(None);
+    };
+    Ok(Some(Probe {
+        status: get_enum(row, "probe_status")?,
+        latency_ms: row.get("probe_latency_ms")?,
+        error: row.get("probe_error")?,
+        probed_at,
+    }))
+}
+
+impl FromRow for ProbeTarget {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(ProbeTarget {
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            config_id: row.get("config_id")?,
+            node_id: row.get("node_id")?,
+            address: row.get("address")?,
+            protocol: get_enum(row, "protocol")?,
+            check: get_enum(row, "check")?,
+            check_path: row.get::<_, Option<String>>("check_path")?.unwrap_or_default(),
+            check_body: row.get::<_, Option<String>>("check_body")?.unwrap_or_default(),
+            check_interval: row.get::<_, Option<i32>>("check_interval")?.unwrap_or_default(),
+            check_timeout: row.get::<_, Option<i32>>("check_timeout")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromRow for NodeHealth {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(NodeHealth {
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            config_id: row.get("config_id")?,
+            node_id: row.get("node_id")?,
+            port: row.get("port")?,
+            address: row.get("address")?,
+            lastverdict: get_enum(row, "lastverdict")?,
+            current: get_enum(row, "current")?,
+            observed: get_enum(row, "observed")?,
+            streak: row.get("streak")?,
+            since: row.get("since")?,
+            probed_at: row.get("probed_at")?,
+        })
+    }
+}
+
+impl FromRow for NodeState {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(NodeState {
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            nodebalancer_config_id: row.get("nodebalancer_config_id")?,
+            node_id: row.get("node_id")?,
+            port: row.get("port")?,
+            lastmode: get_enum(row, "lastmode")?,
+            current: get_enum(row, "current")?,
+        })
+    }
+}
+
+impl FromRow for ProbeSample {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(ProbeSample {
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            node_id: row.get("node_id")?,
+            probed_at: row.get("probed_at")?,
+            resolution: row.get("resolution")?,
+            probes: row.get("probes")?,
+            up: row.get("up")?,
+            latency_ms: row.get("latency_ms")?,
+            connect_ms: row.get("connect_ms")?,
+            tls_ms: row.get("tls_ms")?,
+            ttfb_ms: row.get("ttfb_ms")?,
+            http_status: row.get("http_status")?,
+        })
+    }
+}
+
+impl FromRow for AuditEvent {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(AuditEvent {
+            at: row.get("at")?,
+            object: get_enum(row, "object")?,
+            action: get_enum(row, "action")?,
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            object_id: row.get("object_id")?,
+            before: row.get("before")?,
+            after: row.get("after")?,
+        })
+    }
+}
+
+impl FromRow for TrafficSample {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(TrafficSample {
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            at: row.get("at")?,
+            connections: row.get("connections")?,
+            traffic_in: row.get("traffic_in")?,
+            traffic_out: row.get("traffic_out")?,
+        })
+    }
+}
+
+impl FromRow for DriftViolation {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(DriftViolation {
+            nodebalancer_id: row.get("nodebalancer_id")?,
+            config_id: row.get("config_id")?,
+            port: row.get("port")?,
+            rule: row.get("rule")?,
+            field: row.get("field")?,
+            expected: row.get("expected")?,
+            actual: row.get("actual")?,
+            since: row.get("since")?,
+        })
+    }
+}
+
+fn query_all<T: FromRow>(conn: &Connection, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<Vec<T>> {
+    let mut statement = conn.prepare_cached(sql)?;
+    let rows = statement.query_map(params, T::from_row)?;
+    rows.collect()
+}
+
+// Unlike Postgres, SQLite cannot swap a CHECK constraint on an existing
+// table, so enum columns are plain nullable text. Writers only ever store
+// `known()` values or NULL.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS nodebalancer (
+        id INTEGER NOT NULL,
+        ipv4 TEXT NOT NULL,
+        region TEXT NOT NULL,
+        lke_id INTEGER,
+        PRIMARY KEY (id)
+        );
+
+    CREATE TABLE IF NOT EXISTS nodebalancer_config (
+        id INTEGER NOT NULL,
+        algorithm TEXT,
+        port INTEGER NOT NULL,
+        up INTEGER NOT NULL,
+        down INTEGER NOT NULL,
+        nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+        protocol TEXT,
+        \"check\" TEXT,
+        stickiness TEXT,
+        cipher_suite TEXT,
+        PRIMARY KEY (id, nodebalancer_id)
+        );
+
+    CREATE TABLE IF NOT EXISTS node (
+        id INTEGER NOT NULL,
+        address TEXT NOT NULL,
+        status TEXT,
+        config_id INTEGER NOT NULL,
+        nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+        mode TEXT,
+        PRIMARY KEY (id, nodebalancer_id)
+        );
+
+    CREATE TABLE IF NOT EXISTS state (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+        nodebalancer_config_id INTEGER NOT NULL,
+        node_id INTEGER NOT NULL,
+        port INTEGER NOT NULL,
+        lastmode TEXT,
+        current TEXT
+        );
+
+    CREATE TABLE IF NOT EXISTS node_health (
+        nodebalancer_id INTEGER NOT NULL REFERENCES nodebalancer,
+        node_id INTEGER NOT NULL,
+        config_id INTEGER NOT NULL,
+        port INTEGER NOT NULL,
+        address TEXT NOT NULL,
+        lastverdict TEXT,
+        current TEXT,
+        observed TEXT,
+        streak INTEGER NOT NULL,
+        since TEXT NOT NULL,
+        probed_at TEXT NOT NULL,
+        PRIMARY KEY (nodebalancer_id, node_id)
+        );
+
+    CREATE TABLE IF NOT EXISTS probe_sample (
+        nodebalancer_id INTEGER NOT NULL,
+        node_id INTEGER NOT NULL,
+        probed_at TEXT NOT NULL,
+        resolution INTEGER NOT NULL,
+        probes INTEGER NOT NULL,
+        up INTEGER NOT NULL,
+        latency_ms REAL,
+        connect_ms REAL,
+        tls_ms REAL,
+        ttfb_ms REAL,
+        http_status INTEGER,
+        PRIMARY KEY (nodebalancer_id, node_id, probed_at, resolution)
+        );
+
+    CREATE TABLE IF NOT EXISTS nodebalancer_traffic (
+        nodebalancer_id INTEGER NOT NULL,
+        at TEXT NOT NULL,
+        connections REAL,
+        traffic_in REAL,
+        traffic_out REAL,
+        PRIMARY KEY (nodebalancer_id, at)
+        );
+
+    CREATE TABLE IF NOT EXISTS audit_event (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        at TEXT NOT NULL,
+        object TEXT,
+        action TEXT,
+        nodebalancer_id INTEGER NOT NULL,
+        object_id INTEGER NOT NULL,
+        \"before\" TEXT,
+        \"after\" TEXT
+        );
+    CREATE INDEX IF NOT EXISTS audit_event_at ON audit_event (at);
+
+    CREATE TABLE IF NOT EXISTS config_drift (
+        nodebalancer_id INTEGER NOT NULL,
+        config_id INTEGER NOT NULL,
+        port INTEGER NOT NULL,
+        rule TEXT NOT NULL,
+        field TEXT NOT NULL,
+        expected TEXT NOT NULL,
+        actual TEXT NOT NULL,
+        since TEXT NOT NULL,
+        PRIMARY KEY (nodebalancer_id, config_id, rule, field)
+        );
+";
+
+const NODEBALANCER_COLUMNS: &str = "id, ipv4, region, lke_id, label, hostname, ipv6, client_conn_throttle, \"type\",
+    created, updated, lke_label, lke_type, lke_url";
+
+// Columns added after the tables were first created, as (table, column,
+// declaration). SQLite has no ADD COLUMN IF NOT EXISTS, so `init` checks
+// each against the table's current columns.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("nodebalancer", "label", "TEXT NOT NULL DEFAULT ''"),
+    ("nodebalancer", "hostname", "TEXT NOT NULL DEFAULT ''"),
+    ("nodebalancer", "ipv6", "TEXT NOT NULL DEFAULT ''"),
+    ("nodebalancer", "client_conn_throttle", "INTEGER NOT NULL DEFAULT 0"),
+    ("nodebalancer", "type", "TEXT NOT NULL DEFAULT ''"),
+    ("nodebalancer", "created", "TEXT NOT NULL DEFAULT ''"),
+    ("nodebalancer", "updated", "TEXT NOT NULL DEFAULT ''"),
+    ("nodebalancer", "lke_label", "TEXT"),
+    ("nodebalancer", "lke_type", "TEXT"),
+    ("nodebalancer", "lke_url", "TEXT"),
+    ("nodebalancer", "removed_at", "TEXT"),
+    ("nodebalancer_config", "check_path", "TEXT"),
+    ("nodebalancer_config", "check_body", "TEXT"),
+    ("nodebalancer_config", "check_interval", "INTEGER"),
+    ("nodebalancer_config", "check_timeout", "INTEGER"),
+    ("node", "probe_status", "TEXT"),
+    ("node", "probe_latency_ms", "REAL"),
+    ("node", "probe_error", "TEXT"),
+    ("node", "probed_at", "TEXT"),
+];
+
+fn add_missing_columns(conn: &Connection) -> rusqlite::Result<()> {
+    for (table, column, declaration) in ADDED_COLUMNS {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+            [table, column],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN \"{}\" {};", table, column, declaration))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Nodes joined with the config and nodebalancer they belong to.
+const NODE_DETAIL_SELECT: &str = "
+    SELECT node.id, node.address, node.status, node.mode, node.config_id, node.nodebalancer_id,
+           node.probe_status, node.probe_latency_ms, node.probe_error, node.probed_at,
+           nodebalancer.ipv4, nodebalancer.region, nodebalancer.label AS nodebalancer_label,
+           nodebalancer_config.algorithm, nodebalancer_config.port, nodebalancer_config.up, nodebalancer_config.down
+    FROM node
+    JOIN nodebalancer ON node.nodebalancer_id = nodebalancer.id
+    JOIN nodebalancer_config ON nodebalancer_config.id = node.config_id
+        AND nodebalancer_config.nodebalancer_id = node.nodebalancer_id";
+
+// Nodes with the health check settings of their config.
+const PROBE_TARGET_SELECT: &str = "
+    SELECT node.nodebalancer_id, node.config_id, node.id AS node_id, node.address,
+           nodebalancer_config.protocol, nodebalancer_config.\"check\", nodebalancer_config.check_path, nodebalancer_config.check_body,
+           nodebalancer_config.check_interval, nodebalancer_config.check_timeout
+    FROM node
+    JOIN nodebalancer_config ON nodebalancer_config.id = node.config_id
+        AND nodebalancer_config.nodebalancer_id = node.nodebalancer_id";
+
+const PROBE_SAMPLE_COLUMNS: &str =
+    "nodebalancer_id, node_id, probed_at, resolution, probes, up, latency_ms, connect_ms, tls_ms, ttfb_ms, http_status";
+
+fn insert_sample(transaction: &Transaction, s: &ProbeSample) -> rusqlite::Result<()> {
+    transaction.prepare_cached(&format!(
+        "INSERT INTO probe_sample ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11) ON CONFLICT DO NOTHING",
+        PROBE_SAMPLE_COLUMNS,
+    ))?.execute(params![
+        s.nodebalancer_id, s.node_id, s.probed_at, s.resolution, s.probes, s.up,
+        s.latency_ms, s.connect_ms, s.tls_ms, s.ttfb_ms, s.http_status,
+    ])?;
+
+    Ok(())
+}
+
+// The latest row of each node in the append-only `state` table.
+const NODE_STATE_SELECT: &str = "
+    SELECT nodebalancer_id, nodebalancer_config_id, node_id, port, lastmode, current
+    FROM state
+    WHERE id IN (SELECT max(id) FROM state GROUP BY nodebalancer_id, node_id)
+    ORDER BY nodebalancer_id, node_id";
+
+const AUDIT_EVENT_COLUMNS: &str = "at, object, action, nodebalancer_id, object_id, \"before\", \"after\"";
+
+// SQLite has no `xmax`, so whether an upsert inserted is looked up first.
+// Both statements run in the caller's transaction.
+fn count_upsert(counts: &mut WriteCounts, existed: bool) {
+    if existed {
+        counts.updated += 1;
+    } else {
+        counts.inserted += 1;
+    }
+}
+
+// Deletes the rows of `table` of one nodebalancer whose `id_column` is not
+// in `keep`, returning how many.
+fn delete_unlisted(transaction: &Transaction, table: &str, id_column: &str, nodebalancer_id: i32, keep: &HashSet<i32>) -> rusqlite::Result<u64> {
+    let stored = transaction
+        .prepare_cached(&format!("SELECT {} FROM {} WHERE nodebalancer_id = ?1", id_column, table))?
+        .query_map([nodebalancer_id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i32>>>()?;
+    let mut deleted = 0;
+    for id in stored.into_iter().filter(|id| !keep.contains(id)) {
+        deleted += transaction
+            .prepare_cached(&format!("DELETE FROM {} WHERE nodebalancer_id = ?1 AND {} = ?2", table, id_column))?
+            .execute([nodebalancer_id, id])? as u64;
+    }
+
+    Ok(deleted)
+}
+
+fn exists(transaction: &Transaction, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<bool> {
+    Ok(transaction.prepare_cached(sql)?.query_row(params, |_| Ok(())).optional()?.is_some())
+}
+
+/// [`Store`] backed by a single SQLite file, or by memory.
+///
+/// The connection is shared behind a mutex and used from the blocking
+/// thread pool, so writes are serialized whatever `--db-concurrency` is.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::from_connection(conn)
+    }
+
+    /// A private database that lives as long as the store.
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self, Error> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        Ok(SqliteStore { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            f(&mut conn)
+        }).await;
+        match result {
+            Ok(result) => Ok(result?),
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn init(&self) -> Result<(), Error> {
+        self.run(|conn| {
+            conn.execute_batch(SCHEMA)?;
+            add_missing_columns(conn)
+        }).await?;
+        println!("SQLite tables available");
+
+        Ok(())
+    }
+
+    async fn write_nodebalancer(
+        &self,
+        nodebalancer: LocalNodeBalancerListObject,
+        configs: Vec<NodeBalancerConfigObject>,
+        nodes: Vec<NodeObject>,
+    ) -> Result<WriteCounts, Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            let mut counts = WriteCounts::default();
+
+            let existed = exists(&transaction, "SELECT 1 FROM nodebalancer WHERE id = ?1", [nodebalancer.nb_id])?;
+            // Rows without metadata, from the main DB, keep what is stored.
+            let metadata = match nodebalancer.has_metadata() {
+                true => ", label = excluded.label, hostname = excluded.hostname, ipv6 = excluded.ipv6,
+                     client_conn_throttle = excluded.client_conn_throttle, \"type\" = excluded.\"type\", created = excluded.created,
+                     updated = excluded.updated, lke_label = excluded.lke_label, lke_type = excluded.lke_type, lke_url = excluded.lke_url",
+                false => "",
+            };
+            transaction.prepare_cached(&format!(
+                "INSERT INTO nodebalancer ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+                 ON CONFLICT (id) DO UPDATE SET ipv4 = excluded.ipv4, region = excluded.region, lke_id = excluded.lke_id, removed_at = NULL{}",
+                NODEBALANCER_COLUMNS, metadata,
+            ))?.execute(params![
+                nodebalancer.nb_id, nodebalancer.ipv4, nodebalancer.region, nodebalancer.lke_id,
+                nodebalancer.label, nodebalancer.hostname, nodebalancer.ipv6, nodebalancer.client_conn_throttle, nodebalancer.nb_type,
+                nodebalancer.created, nodebalancer.updated, nodebalancer.lke_label, nodebalancer.lke_type, nodebalancer.lke_url,
+            ])?;
+            count_upsert(&mut counts, existed);
+
+            for c in &configs {
+                let existed = exists(
+                    &transaction,
+                    "SELECT 1 FROM nodebalancer_config WHERE id = ?1 AND nodebalancer_id = ?2",
+                    [c.id, c.nodebalancer_id],
+                )?;
+                transaction.prepare_cached(
+                    "INSERT INTO nodebalancer_config (id, algorithm, port, up, down, nodebalancer_id, protocol, \"check\", stickiness, cipher_suite,
+                         check_path, check_body, check_interval, check_timeout)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+                     ON CONFLICT (id, nodebalancer_id) DO UPDATE SET algorithm = excluded.algorithm, port = excluded.port, up = excluded.up, down = excluded.down,
+                         protocol = excluded.protocol, \"check\" = excluded.\"check\", stickiness = excluded.stickiness, cipher_suite = excluded.cipher_suite,
+                         check_path = excluded.check_path, check_body = excluded.check_body, check_interval = excluded.check_interval, check_timeout = excluded.check_timeout",
+                )?.execute(params![
+                    c.id, c.algorithm.known(), c.port, c.nodes_status.up, c.nodes_status.down, c.nodebalancer_id,
+                    c.protocol.known(), c.check.known(), c.stickiness.known(), c.cipher_suite.known(),
+                    c.check_path, c.check_body, c.check_interval, c.check_timeout,
+                ])?;
+                count_upsert(&mut counts, existed);
+            }
+
+            for n in &nodes {
+                let existed = exists(
+                    &transaction,
+                    "SELECT 1 FROM node WHERE id = ?1 AND nodebalancer_id = ?2",
+                    [n.id, n.nodebalancer_id],
+                )?;
+                transaction.prepare_cached(
+                    "INSERT INTO node (id, address, status, config_id, nodebalancer_id, mode) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (id, nodebalancer_id) DO UPDATE SET address = excluded.address, status = excluded.status, config_id = excluded.config_id, mode = excluded.mode",
+                )?.execute(params![n.id, n.address, n.status.known(), n.config_id, n.nodebalancer_id, n.mode.known()])?;
+                count_upsert(&mut counts, existed);
+            }
+
+            let config_ids: HashSet<i32> = configs.iter().map(|c| c.id).collect();
+            let node_ids: HashSet<i32> = nodes.iter().map(|n| n.id).collect();
+            counts.deleted += delete_unlisted(&transaction, "node", "id", nodebalancer.nb_id, &node_ids)?;
+            counts.deleted += delete_unlisted(&transaction, "nodebalancer_config", "id", nodebalancer.nb_id, &config_ids)?;
+            delete_unlisted(&transaction, "node_health", "node_id", nodebalancer.nb_id, &node_ids)?;
+
+            transaction.commit()?;
+
+            Ok(counts)
+        }).await
+    }
+
+    async fn remove_nodebalancers(&self, nodebalancer_ids: Vec<i32>) -> Result<WriteCounts, Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            let mut counts = WriteCounts::default();
+            let removed_at = Utc::now();
+            for id in &nodebalancer_ids {
+                let marked = transaction
+                    .prepare_cached("UPDATE nodebalancer SET removed_at = ?2 WHERE id = ?1 AND removed_at IS NULL")?
+                    .execute(params![id, removed_at])?;
+                if marked == 0 {
+                    continue;
+                }
+                counts.deleted += 1;
+                for table in ["node", "nodebalancer_config"] {
+                    counts.deleted += transaction.prepare_cached(&format!("DELETE FROM {} WHERE nodebalancer_id = ?1", table))?.execute([id])? as u64;
+                }
+                for table in ["node_health", "config_drift"] {
+                    transaction.prepare_cached(&format!("DELETE FROM {} WHERE nodebalancer_id = ?1", table))?.execute([id])?;
+                }
+            }
+            transaction.commit()?;
+
+            Ok(counts)
+        }).await
+    }
+
+    async fn nodebalancer_ids(&self) -> Result<Vec<i32>, Error> {
+        self.run(|conn| {
+            let mut statement = conn.prepare_cached("SELECT id FROM nodebalancer WHERE removed_at IS NULL")?;
+            let ids = statement.query_map([], |row| row.get("id"))?;
+            ids.collect()
+        }).await
+    }
+
+    async fn nodebalancers(&self) -> Result<Vec<LocalNodeBalancerListObject>, Error> {
+        self.run(|conn| {
+            query_all(conn, &format!("SELECT {} FROM nodebalancer WHERE removed_at IS NULL ORDER BY id", NODEBALANCER_COLUMNS), [])
+        }).await
+    }
+
+    async fn configs(&self) -> Result<Vec<LocalNodeBalancerConfigObject>, Error> {
+        self.run(|conn| {
+            query_all(conn, "SELECT id, nodebalancer_id, algorithm, port, up, down FROM nodebalancer_config", [])
+        }).await
+    }
+
+    async fn node_details(&self) -> Result<Vec<NodeDetailObject>, Error> {
+        self.run(|conn| {
+            query_all(conn, &format!("{} ORDER BY node.nodebalancer_id, node.config_id, node.id", NODE_DETAIL_SELECT), [])
+        }).await
+    }
+
+    async fn nodes_by_address(&self, ip: &str) -> Result<Vec<NodeDetailObject>, Error> {
+        let ip = ip.to_string();
+        self.run(move |conn| {
+            query_all(conn, &format!("{} WHERE node.address LIKE '%' || ?1 || '%'", NODE_DETAIL_SELECT), [ip])
+        }).await
+    }
+
+    async fn probe_targets(&self) -> Result<Vec<ProbeTarget>, Error> {
+        self.run(|conn| query_all(conn, PROBE_TARGET_SELECT, [])).await
+    }
+
+    async fn record_probes(&self, probes: Vec<NodeProbe>) -> Result<(), Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            for p in &probes {
+                let stored = transaction.prepare_cached(
+                    "UPDATE node SET probe_status = ?3, probe_latency_ms = ?4, probe_error = ?5, probed_at = ?6
+                     WHERE nodebalancer_id = ?1 AND id = ?2",
+                )?.execute(params![
+                    p.nodebalancer_id, p.node_id, p.probe.status.known(), p.probe.latency_ms, p.probe.error, p.probe.probed_at,
+                ])?;
+                if stored > 0 {
+                    insert_sample(&transaction, &ProbeSample::from(p))?;
+                }
+            }
+            transaction.commit()
+        }).await
+    }
+
+    async fn probe_samples(&self, since: DateTime<Utc>) -> Result<Vec<ProbeSample>, Error> {
+        self.run(move |conn| {
+            query_all(conn, &format!(
+                "SELECT {} FROM probe_sample WHERE probed_at >= ?1 ORDER BY nodebalancer_id, node_id, probed_at, resolution",
+                PROBE_SAMPLE_COLUMNS,
+            ), [since])
+        }).await
+    }
+
+    /// Downsampling happens in Rust, inside one transaction, so SQLite does
+    /// not have to bucket its text timestamps.
+    async fn compact_probe_samples(
+        &self,
+        raw_before: DateTime<Utc>,
+        resolution: i32,
+        expire_before: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            transaction.execute("DELETE FROM probe_sample WHERE probed_at < ?1", [expire_before])?;
+            let old: Vec<ProbeSample> = query_all(&transaction, &format!(
+                "SELECT {} FROM probe_sample WHERE resolution = 0 AND probed_at < ?1", PROBE_SAMPLE_COLUMNS,
+            ), [raw_before])?;
+            transaction.execute("DELETE FROM probe_sample WHERE resolution = 0 AND probed_at < ?1", [raw_before])?;
+            for s in latency::downsample(&old, resolution) {
+                insert_sample(&transaction, &s)?;
+            }
+            transaction.commit()
+        }).await
+    }
+
+    async fn record_traffic(&self, samples: Vec<TrafficSample>, expire_before: DateTime<Utc>) -> Result<(), Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            for s in &samples {
+                transaction.prepare_cached(
+                    "INSERT INTO nodebalancer_traffic (nodebalancer_id, at, connections, traffic_in, traffic_out) VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (nodebalancer_id, at) DO UPDATE SET connections = excluded.connections,
+                         traffic_in = excluded.traffic_in, traffic_out = excluded.traffic_out",
+                )?.execute(params![s.nodebalancer_id, s.at, s.connections, s.traffic_in, s.traffic_out])?;
+            }
+            transaction.execute("DELETE FROM nodebalancer_traffic WHERE at < ?1", [expire_before])?;
+            transaction.commit()
+        }).await
+    }
+
+    async fn traffic_samples(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<TrafficSample>, Error> {
+        self.run(move |conn| {
+            query_all(conn, "SELECT nodebalancer_id, at, connections, traffic_in, traffic_out FROM nodebalancer_traffic
+                             WHERE at >= ?1 AND (?2 IS NULL OR nodebalancer_id = ?2) ORDER BY nodebalancer_id, at",
+                      params![since, nodebalancer_id])
+        }).await
+    }
+
+    async fn node_health(&self) -> Result<Vec<NodeHealth>, Error> {
+        self.run(|conn| {
+            query_all(conn, "SELECT nodebalancer_id, config_id, node_id, port, address, lastverdict, current, observed, streak, since, probed_at FROM node_health", [])
+        }).await
+    }
+
+    async fn record_node_health(&self, rows: Vec<NodeHealth>) -> Result<(), Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            for h in &rows {
+                transaction.prepare_cached(
+                    "INSERT INTO node_health (nodebalancer_id, node_id, config_id, port, address, lastverdict, current, observed, streak, since, probed_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+                     ON CONFLICT (nodebalancer_id, node_id) DO UPDATE SET config_id = excluded.config_id, port = excluded.port, address = excluded.address,
+                         lastverdict = excluded.lastverdict, current = excluded.current, observed = excluded.observed, streak = excluded.streak,
+                         since = excluded.since, probed_at = excluded.probed_at",
+                )?.execute(params![
+                    h.nodebalancer_id, h.node_id, h.config_id, h.port, h.address, h.lastverdict.known(), h.current.known(), h.observed.known(),
+                    h.streak, h.since, h.probed_at,
+                ])?;
+            }
+            transaction.commit()
+        }).await
+    }
+
+    async fn node_states(&self) -> Result<Vec<NodeState>, Error> {
+        self.run(|conn| query_all(conn, NODE_STATE_SELECT, [])).await
+    }
+
+    async fn update_state(&self, nbid: i32, nbcfgid: i32, nodeid: i32, port: i32, lastmode: &str, current: &str) -> Result<(), Error> {
+        let (lastmode, current) = (lastmode.to_string(), current.to_string());
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO state (nodebalancer_id, nodebalancer_config_id, node_id, port, lastmode, current) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![nbid, nbcfgid, nodeid, port, lastmode, current],
+            )?;
+
+            Ok(())
+        }).await
+    }
+
+    async fn record_audit(&self, events: Vec<AuditEvent>) -> Result<(), Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            for e in &events {
+                transaction.prepare_cached(&format!(
+                    "INSERT INTO audit_event ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", AUDIT_EVENT_COLUMNS,
+                ))?.execute(params![e.at, e.object.known(), e.action.known(), e.nodebalancer_id, e.object_id, e.before, e.after])?;
+            }
+            transaction.commit()
+        }).await
+    }
+
+    async fn audit_events(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<AuditEvent>, Error> {
+        self.run(move |conn| {
+            query_all(conn, &format!(
+                "SELECT {} FROM audit_event WHERE at >= ?1 AND (?2 IS NULL OR nodebalancer_id = ?2) ORDER BY id", AUDIT_EVENT_COLUMNS,
+            ), params![since, nodebalancer_id])
+        }).await
+    }
+
+    async fn audited_objects(&self) -> Result<Vec<AuditEvent>, Error> {
+        self.run(|conn| {
+            query_all(conn, &format!(
+                "SELECT {} FROM audit_event
+                 WHERE id IN (SELECT max(id) FROM audit_event WHERE action <> 'remediated' GROUP BY object, nodebalancer_id, object_id)
+                     AND \"after\" IS NOT NULL
+                 ORDER BY id", AUDIT_EVENT_COLUMNS,
+            ), [])
+        }).await
+    }
+
+    async fn config_drift(&self) -> Result<Vec<DriftViolation>, Error> {
+        self.run(|conn| {
+            query_all(conn, "SELECT nodebalancer_id, config_id, port, rule, field, expected, actual, since FROM config_drift
+                             ORDER BY nodebalancer_id, config_id, rule, field", [])
+        }).await
+    }
+
+    async fn record_config_drift(&self, nodebalancer_ids: Vec<i32>, violations: Vec<DriftViolation>) -> Result<(), Error> {
+        self.run(move |conn| {
+            let transaction = conn.transaction()?;
+            for id in &nodebalancer_ids {
+                transaction.prepare_cached("DELETE FROM config_drift WHERE nodebalancer_id = ?1")?.execute([id])?;
+            }
+            for v in &violations {
+                transaction.prepare_cached(
+                    "INSERT INTO config_drift (nodebalancer_id, config_id, port, rule, field, expected, actual, since)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                )?.execute(params![v.nodebalancer_id, v.config_id, v.port, v.rule, v.field, v.expected, v.actual, v.since])?;
+            }
+            transaction.commit()
+        }).await
+    }
+}
@@ -0,0 +1,139 @@
+//! The local database the sync loop writes into.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use crate::error::Error;
+use crate::models::{
+    AuditEvent,
+    DriftViolation,
+    LocalNodeBalancerConfigObject,
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeDetailObject,
+    NodeHealth,
+    NodeObject,
+    NodeProbe,
+    NodeState,
+    ProbeSample,
+    ProbeTarget,
+    TrafficSample,
+};
+
+/// Rows touched by one write against the local DB.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteCounts {
+    pub inserted: u64,
+    pub updated: u64,
+    pub deleted: u64,
+}
+
+/// Storage for synced nodebalancers, configs, nodes and node state.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Creates any missing tables and columns.
+    async fn init(&self) -> Result<(), Error>;
+
+    /// Upserts a nodebalancer together with all of its configs and nodes so
+    /// that readers never see it half-updated. `configs` and `nodes` are all
+    /// the nodebalancer has: stored ones that are not among them are
+    /// deleted, with the health of those nodes.
+    async fn write_nodebalancer(
+        &self,
+        nodebalancer: LocalNodeBalancerListObject,
+        configs: Vec<NodeBalancerConfigObject>,
+        nodes: Vec<NodeObject>,
+    ) -> Result<WriteCounts, Error>;
+
+    /// Deletes the configs, nodes, node health and config drift of
+    /// nodebalancers that are no longer listed, and marks them removed so
+    /// they are not read back until written again. Their state history
+    /// and time series stay.
+    async fn remove_nodebalancers(&self, nodebalancer_ids: Vec<i32>) -> Result<WriteCounts, Error>;
+
+    /// Every nodebalancer that has not been removed.
+    async fn nodebalancer_ids(&self) -> Result<Vec<i32>, Error>;
+
+    /// Every nodebalancer that has not been removed, with its metadata, by
+    /// id.
+    async fn nodebalancers(&self) -> Result<Vec<LocalNodeBalancerListObject>, Error>;
+
+    async fn configs(&self) -> Result<Vec<LocalNodeBalancerConfigObject>, Error>;
+
+    /// Every node joined with its config and nodebalancer.
+    async fn node_details(&self) -> Result<Vec<NodeDetailObject>, Error>;
+
+    /// Nodes whose address contains `ip`.
+    async fn nodes_by_address(&self, ip: &str) -> Result<Vec<NodeDetailObject>, Error>;
+
+    /// Every node with the health check settings of its config.
+    async fn probe_targets(&self) -> Result<Vec<ProbeTarget>, Error>;
+
+    /// Stores the latest probe of each node next to Linode's status and
+    /// appends every probe to the node's time series. Probes of nodes that
+    /// are no longer stored are ignored.
+    async fn record_probes(&self, probes: Vec<NodeProbe>) -> Result<(), Error>;
+
+    /// Time series points taken at or after `since`, by node and time.
+    async fn probe_samples(&self, since: DateTime<Utc>) -> Result<Vec<ProbeSample>, Error>;
+
+    /// Replaces raw points older than `raw_before` with one point per node
+    /// and `resolution` seconds, see [`latency::downsample`], and deletes
+    /// every point older than `expire_before`. `raw_before` must fall on a
+    /// period boundary.
+    ///
+    /// [`latency::downsample`]: crate::latency::downsample
+    async fn compact_probe_samples(
+        &self,
+        raw_before: DateTime<Utc>,
+        resolution: i32,
+        expire_before: DateTime<Utc>,
+    ) -> Result<(), Error>;
+
+    /// Upserts traffic samples by nodebalancer and time, and deletes every
+    /// one taken before `expire_before`.
+    async fn record_traffic(&self, samples: Vec<TrafficSample>, expire_before: DateTime<Utc>) -> Result<(), Error>;
+
+    /// Traffic samples taken at or after `since`, of one nodebalancer or of
+    /// all, by nodebalancer and time.
+    async fn traffic_samples(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<TrafficSample>, Error>;
+
+    /// The disagreement tracking row of every node probed so far.
+    async fn node_health(&self) -> Result<Vec<NodeHealth>, Error>;
+
+    /// Upserts disagreement tracking rows.
+    async fn record_node_health(&self, rows: Vec<NodeHealth>) -> Result<(), Error>;
+
+    /// The latest [`update_state`](Store::update_state) row of every node
+    /// that has one.
+    async fn node_states(&self) -> Result<Vec<NodeState>, Error>;
+
+    /// Records a node's previous and current mode. Rows are appended, so
+    /// the table keeps the history of every change.
+    async fn update_state(
+        &self,
+        nodebalancer_id: i32,
+        config_id: i32,
+        node_id: i32,
+        port: i32,
+        lastmode: &str,
+        current: &str,
+    ) -> Result<(), Error>;
+
+    /// Appends to the audit log.
+    async fn record_audit(&self, events: Vec<AuditEvent>) -> Result<(), Error>;
+
+    /// Audit events from `since` on, of one nodebalancer or of all, in the
+    /// order they were recorded.
+    async fn audit_events(&self, since: DateTime<Utc>, nodebalancer_id: Option<i32>) -> Result<Vec<AuditEvent>, Error>;
+
+    /// The latest event the sync recorded for every object that has not
+    /// been removed since, which holds the fields it was last seen with.
+    async fn audited_objects(&self) -> Result<Vec<AuditEvent>, Error>;
+
+    /// Every config drift violation, by nodebalancer and config.
+    async fn config_drift(&self) -> Result<Vec<DriftViolation>, Error>;
+
+    /// Replaces the config drift violations of the given nodebalancers
+    /// with `violations`.
+    async fn record_config_drift(&self, nodebalancer_ids: Vec<i32>, violations: Vec<DriftViolation>) -> Result<(), Error>;
+}
@@ -0,0 +1,456 @@
+//! One full sync of a location from the Linode API into a [`Store`].
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::future::{join_all, try_join_all};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use crate::accounts::Accounts;
+use crate::audit::Auditor;
+use crate::database::MainDb;
+use crate::drift::DriftDetector;
+use crate::error::Error;
+use crate::incremental::{recount, Fetch, Incremental, Tracker};
+use crate::leader::Leadership;
+use crate::models::{ClientInstance, LocalNodeBalancerListObject};
+use crate::scheduler::{CycleReport, WriteScheduler};
+use crate::store::Store;
+use crate::upstream;
+
+/// Where the list of nodebalancers for a location comes from.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum Discovery {
+    /// The main hc-nb-api database, falling back to the Linode API when it
+    /// is not configured or cannot be reached
+    #[default]
+    Auto,
+    /// The main hc-nb-api database only
+    Maindb,
+    /// The Linode API only
+    Api,
+}
+
+/// What discovery found of a location.
+#[derive(Debug, Default)]
+pub struct Discovered {
+    pub nodebalancers: Vec<LocalNodeBalancerListObject>,
+    /// The accounts that could not list their nodebalancers, with why.
+    pub failed: Vec<(String, Error)>,
+}
+
+/// The nodebalancers the last successful cycle of a location listed, as
+/// followed by the work that polls them between cycles.
+#[derive(Debug, Clone)]
+pub struct Listing {
+    listed: watch::Receiver<Option<HashSet<i32>>>,
+}
+
+impl Listing {
+    /// Waits until a cycle has completed. Returns at once if one has.
+    pub async fn first_cycle(&mut self) {
+        // The sender only goes away with the syncer; a location that is no
+        // longer synced waits for good.
+        if self.listed.wait_for(Option::is_some).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// What the last cycle listed, in order; nothing before the first.
+    pub fn nodebalancer_ids(&self) -> Vec<i32> {
+        let mut ids: Vec<i32> = self.listed.borrow().iter().flatten().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+}
+
+/// Mirrors every nodebalancer of one location, with its configs and nodes,
+/// into a store, and records what changed since the last cycle in the
+/// audit log.
+pub struct Syncer {
+    accounts: Accounts,
+    store: Arc<dyn Store>,
+    maindb: Option<MainDb>,
+    location: String,
+    discovery: Discovery,
+    db_concurrency: usize,
+    audit: Auditor,
+    drift: Option<DriftDetector>,
+    report_upstream: bool,
+    instance: Option<ClientInstance>,
+    incremental: Option<Incremental>,
+    tracker: Tracker,
+    leadership: Leadership,
+    // What the last cycle listed, whose routes go once no longer listed.
+    // Other locations route their own nodebalancers through the same
+    // accounts.
+    listed: Mutex<HashSet<i32>>,
+    listing: watch::Sender<Option<HashSet<i32>>>,
+}
+
+impl Syncer {
+    /// Syncs through `accounts`, each nodebalancer through its own; a single
+    /// client also does.
+    pub fn new(accounts: impl Into<Accounts>, store: Arc<dyn Store>, location: &str) -> Self {
+        Syncer {
+            accounts: accounts.into(),
+            store,
+            maindb: None,
+            location: location.to_string(),
+            discovery: Discovery::default(),
+            db_concurrency: 100,
+            audit: Auditor::new(),
+            drift: None,
+            report_upstream: false,
+            instance: None,
+            incremental: None,
+            tracker: Tracker::new(),
+            leadership: Leadership::always(),
+            listed: Mutex::default(),
+            listing: watch::Sender::new(None),
+        }
+    }
+
+    /// Main DB to list nodebalancers from, see [`Discovery`].
+    pub fn maindb(mut self, maindb: Option<MainDb>) -> Self {
+        self.maindb = maindb;
+        self
+    }
+
+    pub fn discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Maximum number of nodebalancer transactions in flight.
+    pub fn db_concurrency(mut self, db_concurrency: usize) -> Self {
+        self.db_concurrency = db_concurrency;
+        self
+    }
+
+    /// Checks every synced config against a drift policy.
+    pub fn drift(mut self, drift: Option<DriftDetector>) -> Self {
+        self.drift = drift;
+        self
+    }
+
+    /// Writes a [`DatacenterReport`](crate::models::DatacenterReport) of
+    /// every cycle to the main DB.
+    pub fn report_upstream(mut self, report_upstream: bool) -> Self {
+        self.report_upstream = report_upstream;
+        self
+    }
+
+    /// Registers this instance in the main DB and sends a heartbeat at the
+    /// end of every cycle that listed the location.
+    pub fn heartbeat(mut self, instance: Option<ClientInstance>) -> Self {
+        self.instance = instance;
+        self
+    }
+
+    /// Fetches the configs of a nodebalancer again only when its `updated`
+    /// changed or a full resync is due, and its nodes at their own cadence.
+    pub fn incremental(mut self, incremental: Option<Incremental>) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Makes sure this replica still leads before writing, so a leader that
+    /// lost the lock during a cycle does not write over its successor.
+    pub fn leadership(mut self, leadership: Leadership) -> Self {
+        self.leadership = leadership;
+        self
+    }
+
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn store(&self) -> &Arc<dyn Store> {
+        &self.store
+    }
+
+    /// Follows what each cycle listed, once it completes.
+    pub fn listing(&self) -> Listing {
+        Listing { listed: self.listing.subscribe() }
+    }
+
+    /// Drops what was kept between cycles, for when another replica may
+    /// have synced in the meantime.
+    pub fn resume(&self) {
+        self.audit.unload();
+        self.tracker.clear();
+    }
+
+    // Lists the location with every account, each nodebalancer tagged with
+    // the account that listed it. An account that cannot list its own does
+    // not keep the others from being listed.
+    async fn api_nodebalancers(&self) -> Discovered {
+        let listings = join_all(self.accounts.clients().map(|api| async move {
+            (api.account_name().to_string(), api.nodebalancers_in_region(&self.location).await)
+        })).await;
+        let mut discovered = Discovered::default();
+        for (account, listing) in listings {
+            match listing {
+                Ok(nbs) => discovered.nodebalancers.extend(nbs.into_iter().map(|nb| LocalNodeBalancerListObject {
+                    account: Some(account.clone()),
+                    ..nb.into()
+                })),
+                Err(e) => discovered.failed.push((account, e)),
+            }
+        }
+
+        discovered
+    }
+
+    // Only fails when no account could list its nodebalancers.
+    async fn listed_from_api(&self) -> Result<Discovered, Error> {
+        let mut discovered = self.api_nodebalancers().await;
+        if discovered.failed.len() == self.accounts.clients().count() {
+            return Err(discovered.failed.remove(0).1);
+        }
+
+        Ok(discovered)
+    }
+
+    // Main DB rows come without a label, `updated` or the rest of the
+    // metadata the local DB keeps, and may not name their account. They are
+    // completed from the API; the main DB's addresses and LKE cluster stand.
+    // Rows no account could list are fetched in full, with the default
+    // account unless routed before.
+    async fn maindb_nodebalancers(&self, maindb: &MainDb) -> Result<Discovered, Error> {
+        let mut nbs = maindb.nodebalancers_in_region(&self.location).await?;
+        let Discovered { nodebalancers: listed, failed } = self.api_nodebalancers().await;
+        let mut listed: HashMap<i32, LocalNodeBalancerListObject> = listed.into_iter().map(|nb| (nb.nb_id, nb)).collect();
+        for nb in &mut nbs {
+            let Some(from_api) = listed.remove(&nb.nb_id) else { continue };
+            *nb = LocalNodeBalancerListObject {
+                nb_id: nb.nb_id,
+                ipv4: std::mem::take(&mut nb.ipv4),
+                region: std::mem::take(&mut nb.region),
+                lke_id: nb.lke_id,
+                account: nb.account.take().or(from_api.account.clone()),
+                ..from_api
+            };
+        }
+
+        Ok(Discovered { nodebalancers: nbs, failed })
+    }
+
+    /// Lists the nodebalancers of this location.
+    pub async fn discover(&self) -> Result<Discovered, Error> {
+        match (self.discovery, &self.maindb) {
+            (Discovery::Api, _) | (Discovery::Auto, None) => self.listed_from_api().await,
+            (Discovery::Maindb, None) => Err(Error::Config("main DB discovery needs MAINDB_HOSTPORT".to_string())),
+            (Discovery::Maindb, Some(maindb)) => self.maindb_nodebalancers(maindb).await,
+            (Discovery::Auto, Some(maindb)) => {
+                match self.maindb_nodebalancers(maindb).await {
+                    Ok(discovered) => Ok(discovered),
+                    Err(e) => {
+                        println!("Main DB unavailable, discovering NBs from the API: {}", e);
+                        self.listed_from_api().await
+                    }
+                }
+            }
+        }
+    }
+
+    fn last_listed(&self) -> HashSet<i32> {
+        self.listed.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    // Drops the routes of what the last cycle listed and this one did not.
+    fn unroute_unlisted(&self, listed: &HashSet<i32>) {
+        let mut last = self.listed.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.accounts.unroute(&last.difference(listed).copied().collect());
+        *last = listed.clone();
+    }
+
+    /// Runs one cycle. Only failing to list the location's nodebalancers,
+    /// or no longer leading it once the writes are due, is an error;
+    /// anything that goes wrong with a single nodebalancer is recorded in
+    /// the report and the cycle carries on.
+    pub async fn run_cycle(&self) -> Result<CycleReport, Error> {
+        let mut writes = WriteScheduler::new(self.db_concurrency);
+        let started_at = Utc::now();
+        let Discovered { nodebalancers, failed } = self.discover().await?;
+        let mut listed: HashSet<i32> = nodebalancers.iter().map(|nb| nb.nb_id).collect();
+        for nb in &nodebalancers {
+            if let Some(account) = &nb.account {
+                self.accounts.route(nb.nb_id, account);
+            }
+        }
+        // The nodebalancers of an account that could not list them are not
+        // synced this cycle, but are not gone either. Only the ones this
+        // location listed last cycle are its own: the routes are shared by
+        // every location.
+        let complete = failed.is_empty();
+        let last = self.last_listed();
+        for (account, e) in failed {
+            listed.extend(self.accounts.routed_to(&account).intersection(&last));
+            writes.report().record_failure(&format!("NBs of account {}", account), e);
+        }
+        self.tracker.retain(&listed);
+        self.unroute_unlisted(&listed);
+        if let Err(e) = self.audit.load(self.store.as_ref()).await {
+            writes.report().record_error("audit log", e);
+        }
+
+        // Each nodebalancer is fetched in full, or only its nodes or nothing
+        // when syncing incrementally, and then written in one transaction.
+        // Fetches are fanned out up front, each through the account of its
+        // nodebalancer; the clients bound how many requests run at once.
+        println!("Processing NBs");
+        let needs_label = self.drift.as_ref().is_some_and(|d| d.policy().uses_labels());
+        let mut fetches = JoinSet::new();
+        for nb_payload in nodebalancers {
+            let nbid = nb_payload.nb_id;
+            let fetch = match &self.incremental {
+                Some(incremental) => self.tracker.plan(&nb_payload, incremental, started_at),
+                None => Fetch::Full,
+            };
+            match fetch {
+                Fetch::Full => {}
+                Fetch::Nodes(_) => writes.report().unchanged += 1,
+                Fetch::Skip => {
+                    writes.report().unchanged += 1;
+                    continue;
+                }
+            }
+            let api = match self.accounts.for_nodebalancer(nbid) {
+                Ok(api) => api.clone(),
+                Err(e) => {
+                    writes.report().record_error(&format!("NB {}", nbid), e);
+                    continue;
+                }
+            };
+            fetches.spawn(async move {
+                let fetched = async {
+                    // NBs listed from the main DB come without a label.
+                    let label = match needs_label && nb_payload.label.is_empty() {
+                        true => Some(api.nodebalancer(nbid).await?.label),
+                        false => None,
+                    };
+                    let (full, configs) = match fetch {
+                        Fetch::Nodes(configs) => (false, configs),
+                        _ => (true, api.nodebalancer_configs(nbid).await?),
+                    };
+                    let nodes = try_join_all(configs.iter().map(|c| api.config_nodes(nbid, c.id))).await?;
+                    let nodes: Vec<_> = nodes.into_iter().flatten().collect();
+                    let configs = match full {
+                        true => configs,
+                        false => recount(configs, &nodes),
+                    };
+                    Ok::<_, Error>((label, full, configs, nodes))
+                };
+                let fetched = fetched.await;
+                (nb_payload, fetched)
+            });
+        }
+
+        self.leadership.confirm().await?;
+        let mut checked = HashSet::new();
+        let mut drifted = Vec::new();
+        while let Some(fetched) = fetches.join_next().await {
+            let (nb_payload, full, configs, nodes) = match fetched {
+                Ok((mut nb_payload, Ok((label, full, configs, nodes)))) => {
+                    if let Some(label) = label {
+                        nb_payload.label = label;
+                    }
+                    (nb_payload, full, configs, nodes)
+                }
+                Ok((nb_payload, Err(e))) => {
+                    // A config it was last seen with may be gone.
+                    self.tracker.forget(nb_payload.nb_id);
+                    writes.report().record_failure(&format!("NB {}", nb_payload.nb_id), e);
+                    continue;
+                }
+                Err(e) => {
+                    writes.report().record_error("NB fetch task", e);
+                    continue;
+                }
+            };
+            let context = format!("NB {}", nb_payload.nb_id);
+            let rows = 1 + configs.len() as u64 + nodes.len() as u64;
+            if let Some(drift) = &self.drift {
+                checked.insert(nb_payload.nb_id);
+                drifted.extend(drift.policy().check(&nb_payload, &configs, Utc::now()));
+            }
+            // Changes are recorded before the write, which is skipped if
+            // they cannot be.
+            let events = self.audit.observe(&nb_payload, &configs, &nodes, Utc::now());
+            // What was written is tracked once the write succeeds.
+            let tracker = self.incremental.is_some().then(|| self.tracker.clone());
+            let (nbid, updated) = (nb_payload.nb_id, nb_payload.updated.clone());
+            let full_configs = (tracker.is_some() && full).then(|| configs.clone());
+            let store = Arc::clone(&self.store);
+            let audit = self.audit.clone();
+            writes.spawn(context, rows, async move {
+                audit.record(store.as_ref(), events).await?;
+                let counts = store.write_nodebalancer(nb_payload, configs, nodes).await?;
+                if let Some(tracker) = tracker {
+                    match full_configs {
+                        Some(configs) => tracker.written_full(nbid, updated, configs, started_at),
+                        None => tracker.written_nodes(nbid, started_at),
+                    }
+                }
+                Ok(counts)
+            }).await;
+        }
+
+        // Nodebalancers that are no longer listed go from the store, once
+        // their removal is in the audit log. While an account cannot list
+        // its own, e.g. on the first cycle after a restart when none are
+        // routed to it yet, which are gone is not known and none go.
+        self.leadership.confirm().await?;
+        if complete {
+            let removed = self.audit.observe_listed(&listed, Utc::now());
+            let store = Arc::clone(&self.store);
+            let audit = self.audit.clone();
+            let still_listed = listed.clone();
+            writes.spawn("removed NBs".to_string(), 0, async move {
+                audit.record(store.as_ref(), removed).await?;
+                let gone = store.nodebalancer_ids().await?.into_iter().filter(|id| !still_listed.contains(id)).collect();
+                store.remove_nodebalancers(gone).await
+            }).await;
+        }
+
+        if let Some(drift) = &self.drift
+            && let Err(e) = drift.run(self.store.as_ref(), &checked, &listed, drifted).await
+        {
+            writes.report().record_error("config drift", e);
+        }
+
+        let mut report = writes.finish().await;
+        if self.report_upstream
+            && let Err(e) = self.send_report(started_at, &report, &listed).await
+        {
+            report.record_error("upstream report", e);
+        }
+        if let Some(instance) = &self.instance
+            && let Err(e) = self.send_heartbeat(instance).await
+        {
+            report.record_error("heartbeat", e);
+        }
+        self.listing.send_replace(Some(listed));
+
+        Ok(report)
+    }
+
+    fn upstream(&self, what: &str) -> Result<&MainDb, Error> {
+        self.maindb.as_ref().ok_or_else(|| Error::Config(format!("{} needs MAINDB_HOSTPORT", what)))
+    }
+
+    async fn send_report(&self, started_at: DateTime<Utc>, cycle: &CycleReport, listed: &HashSet<i32>) -> Result<(), Error> {
+        let maindb = self.upstream("upstream reporting")?;
+        let report = upstream::summarize(self.store.as_ref(), &self.location, started_at, cycle, listed).await?;
+        maindb.record_report(&report).await
+    }
+
+    async fn send_heartbeat(&self, instance: &ClientInstance) -> Result<(), Error> {
+        let maindb = self.upstream("the heartbeat")?;
+        let instance = ClientInstance { last_heartbeat: Utc::now(), ..instance.clone() };
+        maindb.heartbeat(&instance).await
+    }
+}
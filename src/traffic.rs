@@ -0,0 +1,182 @@
+//! NodeBalancer traffic: connections and bits in and out from the Linode
+//! stats endpoint, kept as a time series next to the health data so traffic
+//! drops can be lined up with nodes going down.
+
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use crate::accounts::Accounts;
+use crate::error::Error;
+use crate::latency::ago;
+use crate::leader::Leadership;
+use crate::models::{NodeBalancerStats, StatsPoint, TrafficSample};
+use crate::store::Store;
+use crate::sync::Listing;
+
+/// Window `GET /traffic` returns when not given one, the day Linode keeps.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Sets one series of the samples in `by_time`, adding the ones it is the
+// first to have a point at.
+fn merge(
+    by_time: &mut BTreeMap<DateTime<Utc>, TrafficSample>,
+    nodebalancer_id: i32,
+    points: &[StatsPoint],
+    set: impl Fn(&mut TrafficSample, Option<f64>),
+) {
+    for &(millis, value) in points {
+        let Some(at) = DateTime::from_timestamp_millis(millis as i64) else { continue };
+        let sample = by_time.entry(at).or_insert_with(|| TrafficSample {
+            nodebalancer_id,
+            at,
+            connections: None,
+            traffic_in: None,
+            traffic_out: None,
+        });
+        set(sample, value);
+    }
+}
+
+/// The points of a nodebalancer's stats, one sample per time with each
+/// series it has a point in.
+pub fn samples(nodebalancer_id: i32, stats: &NodeBalancerStats) -> Vec<TrafficSample> {
+    let mut by_time = BTreeMap::new();
+    merge(&mut by_time, nodebalancer_id, &stats.data.connections, |s, v| s.connections = v);
+    merge(&mut by_time, nodebalancer_id, &stats.data.traffic.traffic_in, |s, v| s.traffic_in = v);
+    merge(&mut by_time, nodebalancer_id, &stats.data.traffic.traffic_out, |s, v| s.traffic_out = v);
+
+    by_time.into_values().collect()
+}
+
+/// The latest sample of every nodebalancer in `samples`, which are ordered
+/// by nodebalancer and time.
+pub fn latest(samples: &[TrafficSample]) -> Vec<&TrafficSample> {
+    let mut latest: BTreeMap<i32, &TrafficSample> = BTreeMap::new();
+    for s in samples {
+        latest.insert(s.nodebalancer_id, s);
+    }
+
+    latest.into_values().collect()
+}
+
+/// Totals for one collection round.
+#[derive(Debug, Default, Clone)]
+pub struct TrafficReport {
+    pub nodebalancers: u64,
+    pub samples: u64,
+    pub errors: Vec<String>,
+    pub duration: Duration,
+}
+
+impl std::fmt::Display for TrafficReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} samples of {} NBs, {} errors in {:.2?}",
+            self.samples, self.nodebalancers, self.errors.len(), self.duration
+        )
+    }
+}
+
+/// Pulls the stats of every nodebalancer of a location, each through its
+/// account, into the store's traffic time series.
+pub struct TrafficCollector {
+    accounts: Accounts,
+    store: Arc<dyn Store>,
+    leadership: Leadership,
+    listing: Option<Listing>,
+    retention: Duration,
+}
+
+impl TrafficCollector {
+    pub fn new(accounts: impl Into<Accounts>, store: Arc<dyn Store>) -> Self {
+        TrafficCollector {
+            accounts: accounts.into(),
+            store,
+            leadership: Leadership::always(),
+            listing: None,
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+
+    /// Only collects while this replica leads.
+    pub fn leadership(mut self, leadership: Leadership) -> Self {
+        self.leadership = leadership;
+        self
+    }
+
+    /// Only collects for what the last cycle of the location listed, and
+    /// not before its first cycle routed each nodebalancer to its account.
+    pub fn listing(mut self, listing: Listing) -> Self {
+        self.listing = Some(listing);
+        self
+    }
+
+    /// How long samples are kept.
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Fetches and records the stats of every nodebalancer the last sync
+    /// listed, or of every one in the store when there is no [`Listing`].
+    /// A nodebalancer whose stats cannot be fetched is reported and
+    /// skipped; only failing to read or write the store is an error.
+    pub async fn run_round(&self) -> Result<TrafficReport, Error> {
+        let started = Instant::now();
+        let mut fetches = JoinSet::new();
+        let mut report = TrafficReport::default();
+        let nodebalancer_ids = match &self.listing {
+            Some(listing) => listing.nodebalancer_ids(),
+            None => self.store.nodebalancer_ids().await?,
+        };
+        for nbid in nodebalancer_ids {
+            let api = match self.accounts.for_nodebalancer(nbid) {
+                Ok(api) => api.clone(),
+                Err(e) => {
+                    report.errors.push(format!("NB {}: {}", nbid, e));
+                    continue;
+                }
+            };
+            fetches.spawn(async move { (nbid, api.nodebalancer_stats(nbid).await) });
+        }
+
+        let mut collected = Vec::new();
+        while let Some(fetched) = fetches.join_next().await {
+            match fetched {
+                Ok((nbid, Ok(stats))) => {
+                    report.nodebalancers += 1;
+                    collected.extend(samples(nbid, &stats));
+                }
+                Ok((nbid, Err(e))) => report.errors.push(format!("NB {}: {}", nbid, e)),
+                Err(e) => report.errors.push(format!("stats fetch task: {}", e)),
+            }
+        }
+        for error in &report.errors {
+            println!("{}", error);
+        }
+        report.samples = collected.len() as u64;
+        self.store.record_traffic(collected, ago(Utc::now(), self.retention)).await?;
+        report.duration = started.elapsed();
+
+        Ok(report)
+    }
+
+    /// Runs a round every `interval`, forever, starting once the location
+    /// was first synced.
+    pub async fn run(mut self, interval: Duration) {
+        if let Some(listing) = &mut self.listing {
+            listing.first_cycle().await;
+        }
+        loop {
+            self.leadership.acquired().await;
+            match self.run_round().await {
+                Ok(report) => println!("Traffic round complete: {}", report),
+                Err(e) => println!("Traffic round failed: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! What each datacenter reports back to the central hc-nb-api database: a
+//! summary of every cycle and a heartbeat of the instance that ran it.
+
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+use crate::error::Error;
+use crate::latency::ago;
+use crate::models::{ClientInstance, DatacenterReport, NodeStatus, UnhealthyNode};
+use crate::scheduler::CycleReport;
+use crate::store::Store;
+
+/// The report of the cycle that started at `synced_at`, from what the
+/// store holds of the nodebalancers it `listed` once its writes are done.
+pub async fn summarize(
+    store: &dyn Store,
+    location: &str,
+    synced_at: DateTime<Utc>,
+    cycle: &CycleReport,
+    listed: &HashSet<i32>,
+) -> Result<DatacenterReport, Error> {
+    let nodebalancers = store.nodebalancer_ids().await?.into_iter().filter(|id| listed.contains(id)).count();
+    let configs = store.configs().await?.into_iter().filter(|c| listed.contains(&c.nodebalancer_id)).count();
+    let mut nodes = store.node_details().await?;
+    nodes.retain(|n| listed.contains(&n.nodebalancer_id));
+
+    let up = nodes.iter().filter(|n| n.status == NodeStatus::Up).count();
+    let down = nodes.iter().filter(|n| n.status == NodeStatus::Down).count();
+    let unhealthy = nodes.iter()
+        .filter(|n| n.status == NodeStatus::Down || n.probe.as_ref().is_some_and(|p| p.status == NodeStatus::Down))
+        .map(|n| UnhealthyNode {
+            nodebalancer_id: n.nodebalancer_id,
+            config_id: n.config_id,
+            node_id: n.id,
+            address: n.address.clone(),
+            status: n.status.clone(),
+            probe_status: n.probe.as_ref().map(|p| p.status.clone()),
+            mode: n.mode.clone(),
+        })
+        .collect();
+
+    Ok(DatacenterReport {
+        location: location.to_string(),
+        synced_at,
+        nodebalancers: nodebalancers as i32,
+        configs: configs as i32,
+        nodes: nodes.len() as i32,
+        up: up as i32,
+        down: down as i32,
+        errors: cycle.errors.len() as i32,
+        unhealthy,
+    })
+}
+
+/// Whether a location has a client that keeps syncing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liveness {
+    Live,
+    /// Its last heartbeat is too old.
+    Stale,
+    /// No instance ever registered for it.
+    Missing,
+}
+
+impl fmt::Display for Liveness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Liveness::Live => "live",
+            Liveness::Stale => "stale",
+            Liveness::Missing => "missing",
+        })
+    }
+}
+
+/// A location and the instance that last sent a heartbeat for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocationStatus {
+    pub location: String,
+    pub liveness: Liveness,
+    pub instance: Option<ClientInstance>,
+}
+
+/// The status of every location that has registered `instances` or is
+/// `expected` to, by location. A location is live when any of its instances
+/// sent a heartbeat within `stale_after` of `now`.
+pub fn locations(instances: Vec<ClientInstance>, expected: &[String], now: DateTime<Utc>, stale_after: Duration) -> Vec<LocationStatus> {
+    let mut latest: BTreeMap<String, Option<ClientInstance>> = expected.iter().map(|l| (l.clone(), None)).collect();
+    for instance in instances {
+        let slot = latest.entry(instance.location.clone()).or_default();
+        if slot.as_ref().is_none_or(|l| l.last_heartbeat < instance.last_heartbeat) {
+            *slot = Some(instance);
+        }
+    }
+
+    let cutoff = ago(now, stale_after);
+    latest.into_iter().map(|(location, instance)| {
+        let liveness = match &instance {
+            None => Liveness::Missing,
+            Some(i) if i.last_heartbeat < cutoff => Liveness::Stale,
+            Some(_) => Liveness::Live,
+        };
+        LocationStatus { location, liveness, instance }
+    }).collect()
+}
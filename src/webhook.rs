@@ -0,0 +1,57 @@
+//! Posting alerts to a chat webhook.
+
+use serde::Serialize;
+use std::fmt::Display;
+use std::time::Duration;
+
+/// How long a webhook has to answer unless told otherwise. Alerts are sent
+/// in the background, so a slow webhook only delays the ones after it.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where alerts are `POST`ed as JSON: the alert itself under `alert`, and
+/// its description under a Slack-style `text`.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    http: reqwest::Client,
+    timeout: Duration,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Self {
+        Webhook { url: url.to_string(), http: reqwest::Client::new(), timeout: DEFAULT_TIMEOUT }
+    }
+
+    /// How long a send waits for the webhook before giving up.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// A webhook that cannot be reached must not stop the caller; the alert
+    /// is already logged and recorded.
+    pub async fn send<A: Serialize + Display>(&self, alert: &A) {
+        let body = serde_json::json!({ "text": alert.to_string(), "alert": alert });
+        match self.http.post(&self.url).timeout(self.timeout).json(&body).send().await {
+            Ok(response) if !response.status().is_success() => {
+                println!("Alert webhook returned {}", response.status());
+            }
+            Ok(_) => {}
+            Err(e) => println!("Alert webhook failed: {}", e),
+        }
+    }
+
+    /// Sends `alerts` one after another on a task of their own, so neither
+    /// the probe round nor the sync cycle waits for the webhook.
+    pub fn send_in_background<A: Serialize + Display + Send + Sync + 'static>(&self, alerts: Vec<A>) {
+        if alerts.is_empty() {
+            return;
+        }
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            for alert in &alerts {
+                webhook.send(alert).await;
+            }
+        });
+    }
+}
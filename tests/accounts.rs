@@ -0,0 +1,260 @@
+//! Several Linode accounts: their tokens, and which one each nodebalancer
+//! is synced through.
+
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use hc_nb_api_client::accounts::{Accounts, ClientSettings};
+use chrono::{TimeZone, Utc};
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::{AuditAction, NodeStatus};
+use hc_nb_api_client::sync::Discovery;
+use hc_nb_api_client::{LinodeClient, Store, Syncer};
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn settings() -> ClientSettings {
+    ClientSettings { api_version: "v4".to_string(), requests_per_minute: 800, concurrency: 16 }
+}
+
+fn from_vars(vars: &[(&str, &str)]) -> Result<Accounts, hc_nb_api_client::Error> {
+    let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    Accounts::from_vars(&settings(), |name| vars.get(name).cloned())
+}
+
+fn names(accounts: &Accounts) -> Vec<&str> {
+    accounts.clients().map(LinodeClient::account_name).collect()
+}
+
+fn client(mock: &MockLinode, account: &str) -> LinodeClient {
+    LinodeClient::new(&format!("{}-token", account), "v4", 60_000, 8)
+        .unwrap()
+        .base_url(&mock.server.uri())
+        .account(account)
+}
+
+// Serves `items` from `endpoint` only to the token of `account`.
+async fn serve_to(mock: &MockLinode, account: &str, endpoint: &str, items: Vec<Value>) {
+    Mock::given(method("GET"))
+        .and(path(endpoint))
+        .and(header("Authorization", format!("Bearer {}-token", account).as_str()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": items,
+            "page": 1,
+            "pages": 1,
+            "results": items.len(),
+        })))
+        .mount(&mock.server)
+        .await;
+}
+
+// NB 1 belongs to the default account, NB 2 to `legacy`.
+async fn serve_accounts(mock: &MockLinode) {
+    serve_to(mock, "default", "/nodebalancers", vec![nodebalancer(1)]).await;
+    serve_to(mock, "default", &configs_path(1), vec![config(1, 10, 80, 1, 0)]).await;
+    serve_to(mock, "default", &nodes_path(1, 10), vec![node(1, 10, 100, "UP")]).await;
+    serve_to(mock, "legacy", "/nodebalancers", vec![nodebalancer(2)]).await;
+}
+
+// Serves the nodebalancers of `region` only to the token of `account`.
+async fn serve_region_to(mock: &MockLinode, account: &str, region: &str, items: Vec<Value>) {
+    Mock::given(method("GET"))
+        .and(path("/nodebalancers"))
+        .and(header("Authorization", format!("Bearer {}-token", account).as_str()))
+        .and(header("X-Filter", json!({ "region": region }).to_string().as_str()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": items,
+            "page": 1,
+            "pages": 1,
+            "results": items.len(),
+        })))
+        .mount(&mock.server)
+        .await;
+}
+
+async fn refuse(mock: &MockLinode, account: &str) {
+    Mock::given(method("GET"))
+        .and(path("/nodebalancers"))
+        .and(header("Authorization", format!("Bearer {}-token", account).as_str()))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&mock.server)
+        .await;
+}
+
+fn syncer(mock: &MockLinode, store: &Arc<MemoryStore>) -> Syncer {
+    let accounts = Accounts::new(vec![client(mock, "default"), client(mock, "legacy")]).unwrap();
+    Syncer::new(accounts, Arc::clone(store) as Arc<dyn Store>, REGION).discovery(Discovery::Api)
+}
+
+#[test]
+fn accounts_are_read_from_their_variables() {
+    let accounts = from_vars(&[("TOKEN", "t"), ("ACCOUNTS", "legacy, eu"), ("TOKEN_LEGACY", "l"), ("TOKEN_EU", "e"), ("API_RATE_LIMIT_EU", "100")]).unwrap();
+    assert_eq!(names(&accounts), vec!["default", "eu", "legacy"]);
+    assert_eq!(accounts.default_client().account_name(), "default");
+
+    let named_only = from_vars(&[("ACCOUNTS", "legacy"), ("TOKEN_LEGACY", "l")]).unwrap();
+    assert_eq!(named_only.default_client().account_name(), "legacy");
+
+    assert!(matches!(from_vars(&[("TOKEN", "t"), ("ACCOUNTS", "legacy")]), Err(e) if e.to_string().contains("TOKEN_LEGACY")));
+    assert!(from_vars(&[("TOKEN", "t"), ("ACCOUNTS", "legacy"), ("TOKEN_LEGACY", "l"), ("API_RATE_LIMIT_LEGACY", "lots")]).is_err());
+    assert!(from_vars(&[]).is_err());
+}
+
+#[test]
+fn nodebalancers_are_routed_to_their_account() {
+    let mock_uri = "http://127.0.0.1:9";
+    let make = |account: &str| LinodeClient::new("t", "v4", 60, 1).unwrap().base_url(mock_uri).account(account);
+    let accounts = Accounts::new(vec![make("default"), make("legacy")]).unwrap();
+    assert!(Accounts::new(vec![make("default"), make("default")]).is_err());
+
+    accounts.route(2, "legacy");
+    accounts.route(3, "gone");
+    assert_eq!(accounts.for_nodebalancer(1).unwrap().account_name(), "default");
+    assert_eq!(accounts.for_nodebalancer(2).unwrap().account_name(), "legacy");
+    assert!(accounts.for_nodebalancer(3).is_err());
+
+    // Clones with their own concurrency share the routes.
+    let limited = accounts.concurrency(2);
+    accounts.route(4, "legacy");
+    assert_eq!(limited.for_nodebalancer(4).unwrap().account_name(), "legacy");
+}
+
+#[tokio::test]
+async fn each_nodebalancer_is_synced_through_its_account() {
+    let mock = MockLinode::start().await;
+    serve_accounts(&mock).await;
+    serve_to(&mock, "legacy", &configs_path(2), vec![config(2, 20, 80, 1, 0)]).await;
+    serve_to(&mock, "legacy", &nodes_path(2, 20), vec![node(2, 20, 200, "UP")]).await;
+    let store = Arc::new(MemoryStore::new());
+
+    let report = syncer(&mock, &store).run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    let mut ids = store.nodebalancer_ids().await.unwrap();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+}
+
+#[tokio::test]
+async fn refused_tokens_are_reported_per_account() {
+    let mock = MockLinode::start().await;
+    serve_accounts(&mock).await;
+    Mock::given(method("GET"))
+        .and(path(configs_path(2)))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&mock.server)
+        .await;
+    let store = Arc::new(MemoryStore::new());
+
+    let report = syncer(&mock, &store).run_cycle().await.unwrap();
+
+    assert_eq!(report.refused, [("legacy".to_string(), 1)].into());
+    assert!(report.errors[0].contains("account legacy was refused with 401"), "{:?}", report.errors);
+    assert!(report.to_string().ends_with(", 1 refused for account legacy"));
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![1]);
+}
+
+#[tokio::test]
+async fn a_refused_listing_only_skips_that_account() {
+    let mock = MockLinode::start().await;
+    let store = Arc::new(MemoryStore::new());
+    let accounts = Accounts::new(vec![client(&mock, "default"), client(&mock, "legacy")]).unwrap();
+    let syncer = Syncer::new(accounts.clone(), Arc::clone(&store) as Arc<dyn Store>, REGION).discovery(Discovery::Api);
+    serve_accounts(&mock).await;
+    serve_to(&mock, "legacy", &configs_path(2), vec![config(2, 20, 80, 1, 0)]).await;
+    serve_to(&mock, "legacy", &nodes_path(2, 20), vec![node(2, 20, 200, "UP")]).await;
+    syncer.run_cycle().await.unwrap();
+
+    mock.server.reset().await;
+    serve_to(&mock, "default", "/nodebalancers", vec![nodebalancer(1)]).await;
+    serve_to(&mock, "default", &configs_path(1), vec![config(1, 10, 80, 1, 0)]).await;
+    serve_to(&mock, "default", &nodes_path(1, 10), vec![node(1, 10, 100, "DOWN")]).await;
+    refuse(&mock, "legacy").await;
+
+    let report = syncer.run_cycle().await.unwrap();
+
+    assert_eq!(accounts.for_nodebalancer(2).unwrap().account_name(), "legacy");
+    assert_eq!(report.refused, [("legacy".to_string(), 1)].into());
+    assert_eq!(report.errors.len(), 1, "{:?}", report.errors);
+    assert_eq!(store.node_details().await.unwrap()[0].status, NodeStatus::Down);
+    // What the refused account holds is neither removed nor audited as such.
+    let mut ids = store.nodebalancer_ids().await.unwrap();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+    let events = store.audit_events(Utc.timestamp_opt(0, 0).unwrap(), Some(2)).await.unwrap();
+    assert!(events.iter().all(|e| e.action != AuditAction::Removed), "{:?}", events);
+
+    // Routes go with the nodebalancers that are no longer listed.
+    mock.server.reset().await;
+    serve_to(&mock, "default", "/nodebalancers", vec![nodebalancer(1)]).await;
+    serve_to(&mock, "legacy", "/nodebalancers", vec![]).await;
+    syncer.run_cycle().await.unwrap();
+    assert_eq!(accounts.for_nodebalancer(2).unwrap().account_name(), "default");
+
+    // With every account refused there is nothing to sync.
+    mock.server.reset().await;
+    mock.fail("/nodebalancers", 401).await;
+    assert!(syncer.run_cycle().await.is_err());
+}
+
+#[tokio::test]
+async fn a_refused_listing_keeps_to_its_own_location() {
+    const IAD: &str = "us-iad";
+    let mock = MockLinode::start().await;
+    let accounts = Accounts::new(vec![client(&mock, "default"), client(&mock, "legacy")]).unwrap();
+    let (ord, iad) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+    let syncer = Syncer::new(accounts.concurrency(8), Arc::clone(&ord) as Arc<dyn Store>, REGION).discovery(Discovery::Api);
+    let other = Syncer::new(accounts.concurrency(8), Arc::clone(&iad) as Arc<dyn Store>, IAD).discovery(Discovery::Api);
+    let mut nb3 = nodebalancer(3);
+    nb3["region"] = json!(IAD);
+    let serve_legacy = || async {
+        serve_region_to(&mock, "legacy", REGION, vec![nodebalancer(2)]).await;
+        serve_region_to(&mock, "legacy", IAD, vec![nb3.clone()]).await;
+    };
+    serve_region_to(&mock, "default", REGION, vec![nodebalancer(1)]).await;
+    serve_region_to(&mock, "default", IAD, vec![]).await;
+    serve_legacy().await;
+    syncer.run_cycle().await.unwrap();
+    other.run_cycle().await.unwrap();
+
+    // NB 3 is routed to the refused account, but is not this location's.
+    mock.server.reset().await;
+    serve_region_to(&mock, "default", REGION, vec![nodebalancer(1)]).await;
+    refuse(&mock, "legacy").await;
+    syncer.run_cycle().await.unwrap();
+    assert_eq!(syncer.listing().nodebalancer_ids(), vec![1, 2]);
+
+    mock.server.reset().await;
+    serve_region_to(&mock, "default", REGION, vec![nodebalancer(1)]).await;
+    serve_legacy().await;
+    syncer.run_cycle().await.unwrap();
+    assert_eq!(accounts.for_nodebalancer(3).unwrap().account_name(), "legacy");
+}
+
+#[tokio::test]
+async fn a_refused_first_listing_removes_nothing() {
+    let mock = MockLinode::start().await;
+    let store = Arc::new(MemoryStore::new());
+    serve_accounts(&mock).await;
+    serve_to(&mock, "legacy", &configs_path(2), vec![config(2, 20, 80, 1, 0)]).await;
+    serve_to(&mock, "legacy", &nodes_path(2, 20), vec![node(2, 20, 200, "UP")]).await;
+    syncer(&mock, &store).run_cycle().await.unwrap();
+
+    // After a restart nothing is routed to the refused account yet.
+    mock.server.reset().await;
+    serve_to(&mock, "default", "/nodebalancers", vec![nodebalancer(1)]).await;
+    serve_to(&mock, "default", &configs_path(1), vec![config(1, 10, 80, 1, 0)]).await;
+    serve_to(&mock, "default", &nodes_path(1, 10), vec![node(1, 10, 100, "UP")]).await;
+    refuse(&mock, "legacy").await;
+    let report = syncer(&mock, &store).run_cycle().await.unwrap();
+
+    assert_eq!(report.deleted, 0);
+    let mut ids = store.nodebalancer_ids().await.unwrap();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+    let events = store.audit_events(Utc.timestamp_opt(0, 0).unwrap(), Some(2)).await.unwrap();
+    assert!(events.iter().all(|e| e.action != AuditAction::Removed), "{:?}", events);
+}
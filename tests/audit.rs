@@ -0,0 +1,105 @@
+//! The audit log of changes seen across sync cycles.
+
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::*;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use hc_nb_api_client::audit;
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::{AuditAction, AuditEvent, AuditObject};
+use hc_nb_api_client::Store;
+
+async fn all_events(store: &MemoryStore) -> Vec<AuditEvent> {
+    store.audit_events(DateTime::<Utc>::MIN_UTC, None).await.unwrap()
+}
+
+fn summary(events: &[AuditEvent]) -> Vec<(AuditObject, AuditAction, i32, i32)> {
+    events.iter().map(|e| (e.object.clone(), e.action.clone(), e.nodebalancer_id, e.object_id)).collect()
+}
+
+// NB 1 with config 10 holding nodes 100 and 101, and NB 2 with one node.
+async fn serve_region(mock: &MockLinode) {
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 2, 0)]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP"), node(1, 10, 101, "UP")]).await;
+    simple_nodebalancer(mock, 2, 1).await;
+}
+
+// NB 1 only, with a new algorithm and node 100 changed by `change`.
+async fn serve_changed(mock: &MockLinode, change: impl Fn(&mut Value)) {
+    mock.server.reset().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    let mut changed = config(1, 10, 80, 0, 1);
+    changed["algorithm"] = json!("leastconn");
+    mock.list(&configs_path(1), vec![changed]).await;
+    let mut node_100 = node(1, 10, 100, "DOWN");
+    change(&mut node_100);
+    mock.list(&nodes_path(1, 10), vec![node_100]).await;
+}
+
+#[tokio::test]
+async fn first_cycle_records_every_object_as_added() {
+    let mock = MockLinode::start().await;
+    serve_region(&mock).await;
+    let store = Arc::new(MemoryStore::new());
+
+    mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    let events = all_events(&store).await;
+    assert_eq!(events.len(), 2 + 2 + 3);
+    assert!(events.iter().all(|e| e.action == AuditAction::Added && e.before.is_none()));
+    let node_100 = events.iter().find(|e| e.object == AuditObject::Node && e.object_id == 100).unwrap();
+    assert_eq!(node_100.after.as_ref().unwrap()["weight"], 100);
+}
+
+#[tokio::test]
+async fn changes_and_removals_are_recorded_once() {
+    let mock = MockLinode::start().await;
+    serve_region(&mock).await;
+    let store = Arc::new(MemoryStore::new());
+    let syncer = mock.syncer(Arc::clone(&store));
+    syncer.run_cycle().await.unwrap();
+    let added = all_events(&store).await.len();
+
+    serve_changed(&mock, |n| n["weight"] = json!(50)).await;
+    let report = syncer.run_cycle().await.unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+    let mut events = all_events(&store).await.split_off(added);
+    events.sort_by_key(|e| (e.nodebalancer_id, e.object_id));
+    assert_eq!(summary(&events), vec![
+        (AuditObject::Config, AuditAction::Changed, 1, 10),
+        (AuditObject::Node, AuditAction::Changed, 1, 100),
+        (AuditObject::Node, AuditAction::Removed, 1, 101),
+        (AuditObject::Nodebalancer, AuditAction::Removed, 2, 2),
+        (AuditObject::Config, AuditAction::Removed, 2, 20),
+        (AuditObject::Node, AuditAction::Removed, 2, 200),
+    ]);
+    assert_eq!(audit::describe(&events[0]), r#"algorithm: "roundrobin" -> "leastconn""#);
+    assert_eq!(audit::describe(&events[1]), r#"status: "UP" -> "DOWN", weight: 100 -> 50"#);
+    assert!(events[2].after.is_none());
+
+    syncer.run_cycle().await.unwrap();
+    assert_eq!(all_events(&store).await.len(), added + events.len());
+
+    let nb_2 = store.audit_events(DateTime::<Utc>::MIN_UTC, Some(2)).await.unwrap();
+    assert_eq!(nb_2.len(), 3 + 3);
+}
+
+#[tokio::test]
+async fn changes_while_not_running_are_caught_after_a_restart() {
+    let mock = MockLinode::start().await;
+    serve_changed(&mock, |_| ()).await;
+    let store = Arc::new(MemoryStore::new());
+    mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+    let added = all_events(&store).await.len();
+
+    serve_changed(&mock, |n| n["mode"] = json!("drain")).await;
+    mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    let events = all_events(&store).await.split_off(added);
+    assert_eq!(summary(&events), vec![(AuditObject::Node, AuditAction::Changed, 1, 100)]);
+    assert_eq!(audit::describe(&events[0]), r#"mode: "accept" -> "drain""#);
+}
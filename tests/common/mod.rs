@@ -0,0 +1,178 @@
+//! Mock Linode API and fixtures shared by the integration tests.
+
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::sync::Arc;
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::sync::Discovery;
+use hc_nb_api_client::{LinodeClient, Syncer};
+use wiremock::matchers::{body_json, header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+pub const REGION: &str = "us-ord";
+
+/// A local stand-in for the Linode API list endpoints.
+pub struct MockLinode {
+    pub server: MockServer,
+}
+
+impl MockLinode {
+    pub async fn start() -> Self {
+        MockLinode { server: MockServer::start().await }
+    }
+
+    /// A client for this server with a budget that never throttles a test.
+    pub fn client(&self) -> LinodeClient {
+        LinodeClient::new("test-token", "v4", 60_000, 8)
+            .unwrap()
+            .base_url(&self.server.uri())
+    }
+
+    /// Syncs `REGION` from this server into `store`, listing NBs from the API.
+    pub fn syncer(&self, store: Arc<MemoryStore>) -> Syncer {
+        Syncer::new(self.client(), store, REGION).discovery(Discovery::Api)
+    }
+
+    /// Serves `items` from the list endpoint at `endpoint` as a single page.
+    pub async fn list(&self, endpoint: &str, items: Vec<Value>) {
+        self.pages(endpoint, vec![items]).await;
+    }
+
+    /// Serves the list endpoint at `endpoint` split over the given pages.
+    pub async fn pages(&self, endpoint: &str, pages: Vec<Vec<Value>>) {
+        let count = pages.len();
+        let results: usize = pages.iter().map(Vec::len).sum();
+        for (i, items) in pages.into_iter().enumerate() {
+            let page = i + 1;
+            Mock::given(method("GET"))
+                .and(path(endpoint))
+                .and(query_param("page", page.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "data": items,
+                    "page": page,
+                    "pages": count,
+                    "results": results,
+                })))
+                .mount(&self.server)
+                .await;
+        }
+    }
+
+    /// Serves the nodebalancers of `REGION`, only when asked with the
+    /// region filter.
+    pub async fn nodebalancers(&self, items: Vec<Value>) {
+        Mock::given(method("GET"))
+            .and(path("/nodebalancers"))
+            .and(header("X-Filter", json!({ "region": REGION }).to_string().as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": items,
+                "page": 1,
+                "pages": 1,
+                "results": items.len(),
+            })))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Answers every request to `endpoint` with `status`.
+    pub async fn fail(&self, endpoint: &str, status: u16) {
+        Mock::given(method("GET"))
+            .and(path(endpoint))
+            .respond_with(ResponseTemplate::new(status))
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Answers every request to `endpoint` with a body that is not JSON.
+    pub async fn malformed(&self, endpoint: &str) {
+        Mock::given(method("GET"))
+            .and(path(endpoint))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{\"data\": [{\"id\": 1,"))
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Answers the first `times` requests to `endpoint` with a 429 that asks to
+    /// retry immediately, then falls through to the other mocks.
+    pub async fn rate_limit(&self, endpoint: &str, times: u64) {
+        Mock::given(method("GET"))
+            .and(path(endpoint))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(times)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Accepts exactly `times` `PUT`s switching the node to `mode`, and
+    /// answers with the updated node.
+    pub async fn update_node(&self, nb_id: i32, config_id: i32, node_id: i32, mode: &str, times: u64) {
+        let mut updated = node(nb_id, config_id, node_id, "DOWN");
+        updated["mode"] = json!(mode);
+        Mock::given(method("PUT"))
+            .and(path(format!("{}/{}", nodes_path(nb_id, config_id), node_id)))
+            .and(body_json(json!({ "mode": mode })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(updated))
+            .expect(times)
+            .mount(&self.server)
+            .await;
+    }
+}
+
+pub fn configs_path(nb_id: i32) -> String {
+    format!("/nodebalancers/{}/configs", nb_id)
+}
+
+pub fn nodes_path(nb_id: i32, config_id: i32) -> String {
+    format!("/nodebalancers/{}/configs/{}/nodes", nb_id, config_id)
+}
+
+pub fn nodebalancer(id: i32) -> Value {
+    json!({
+        "id": id,
+        "ipv4": format!("192.0.2.{}", id),
+        "region": REGION,
+        "label": format!("nb-{}", id),
+        "created": "2026-01-01T00:00:00",
+        "lke_cluster": null,
+    })
+}
+
+pub fn config(nb_id: i32, id: i32, port: i32, up: i32, down: i32) -> Value {
+    json!({
+        "id": id,
+        "nodebalancer_id": nb_id,
+        "port": port,
+        "protocol": "tcp",
+        "algorithm": "roundrobin",
+        "check": "connection",
+        "stickiness": "none",
+        "cipher_suite": "recommended",
+        "nodes_status": { "up": up, "down": down },
+    })
+}
+
+pub fn node(nb_id: i32, config_id: i32, id: i32, status: &str) -> Value {
+    json!({
+        "id": id,
+        "config_id": config_id,
+        "nodebalancer_id": nb_id,
+        "address": format!("10.0.{}.{}:80", config_id, id),
+        "label": format!("node-{}", id),
+        "mode": "accept",
+        "status": status,
+        "weight": 100,
+    })
+}
+
+/// One nodebalancer with one config on port 80 holding `nodes` UP nodes,
+/// numbered from `nb_id * 100`.
+pub async fn simple_nodebalancer(mock: &MockLinode, nb_id: i32, nodes: i32) {
+    let config_id = nb_id * 10;
+    mock.list(&configs_path(nb_id), vec![config(nb_id, config_id, 80, nodes, 0)]).await;
+    let items = (0..nodes).map(|i| node(nb_id, config_id, nb_id * 100 + i, "UP")).collect();
+    mock.list(&nodes_path(nb_id, config_id), items).await;
+}
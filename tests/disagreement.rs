@@ -0,0 +1,231 @@
+//! Linode-reported status against local probes, over consecutive probes.
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use hc_nb_api_client::disagreement::{verdict, DisagreementDetector};
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::{
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeDetailObject,
+    NodeHealth,
+    NodeObject,
+    NodeProbe,
+    NodeStatus,
+    Probe,
+    Verdict,
+};
+use hc_nb_api_client::webhook::Webhook;
+use hc_nb_api_client::Store;
+use wiremock::matchers::{body_partial_json, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn at(second: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(1_700_000_000 + second, 0).unwrap()
+}
+
+fn probe(status: NodeStatus, second: i64) -> Probe {
+    let error = (status == NodeStatus::Down).then(|| "connection refused".to_string());
+    Probe { status, latency_ms: None, error, probed_at: at(second) }
+}
+
+fn node(linode: NodeStatus, probe: Option<Probe>) -> NodeDetailObject {
+    NodeDetailObject {
+        id: 100,
+        address: "10.0.0.1:80".to_string(),
+        status: linode,
+        mode: Default::default(),
+        config_id: 10,
+        nodebalancer_id: 1,
+        ipv4: "192.0.2.1".to_string(),
+        region: "us-ord".to_string(),
+        nodebalancer_label: String::new(),
+        algorithm: Default::default(),
+        port: 80,
+        up: 1,
+        down: 0,
+        probe,
+    }
+}
+
+// Feeds one observation per second through the detector, carrying the
+// tracking row along like the store would.
+fn observe(detector: &DisagreementDetector, observations: &[(NodeStatus, NodeStatus)]) -> (Option<NodeHealth>, Vec<Verdict>) {
+    let mut health: Option<NodeHealth> = None;
+    let mut changes = Vec::new();
+    for (second, (linode, probed)) in observations.iter().enumerate() {
+        let nodes = [node(linode.clone(), Some(probe(probed.clone(), second as i64)))];
+        let previous: Vec<NodeHealth> = health.iter().cloned().collect();
+        let (rows, alerts) = detector.evaluate(&nodes, &previous);
+        changes.extend(alerts.iter().map(|a| a.health.current.clone()));
+        health = rows.into_iter().next().or(health);
+    }
+    (health, changes)
+}
+
+#[test]
+fn verdict_needs_both_sides_to_be_sure() {
+    assert_eq!(verdict(&NodeStatus::Up, &NodeStatus::Down), Verdict::LinodeUpProbeDown);
+    assert_eq!(verdict(&NodeStatus::Down, &NodeStatus::Up), Verdict::LinodeDownProbeUp);
+    assert_eq!(verdict(&NodeStatus::Up, &NodeStatus::Up), Verdict::Agree);
+    assert_eq!(verdict(&NodeStatus::Down, &NodeStatus::Down), Verdict::Agree);
+    assert_eq!(verdict(&NodeStatus::Unchecked, &NodeStatus::Down), Verdict::Agree);
+}
+
+#[test]
+fn disagreement_is_reported_after_the_threshold() {
+    let detector = DisagreementDetector::new(3);
+    let (health, changes) = observe(&detector, &[
+        (NodeStatus::Up, NodeStatus::Down),
+        (NodeStatus::Up, NodeStatus::Down),
+    ]);
+    let health = health.unwrap();
+    assert!(changes.is_empty());
+    assert_eq!((health.current, health.observed, health.streak), (Verdict::Agree, Verdict::LinodeUpProbeDown, 2));
+
+    let (health, changes) = observe(&detector, &[
+        (NodeStatus::Up, NodeStatus::Down),
+        (NodeStatus::Up, NodeStatus::Down),
+        (NodeStatus::Up, NodeStatus::Down),
+        (NodeStatus::Up, NodeStatus::Down),
+    ]);
+    let health = health.unwrap();
+    assert_eq!(changes, vec![Verdict::LinodeUpProbeDown]);
+    assert_eq!((health.lastverdict, health.current, health.streak), (Verdict::Agree, Verdict::LinodeUpProbeDown, 4));
+    assert_eq!(health.since, at(2));
+}
+
+#[test]
+fn interrupted_streaks_start_over() {
+    let detector = DisagreementDetector::new(3);
+    let (health, changes) = observe(&detector, &[
+        (NodeStatus::Down, NodeStatus::Up),
+        (NodeStatus::Down, NodeStatus::Up),
+        (NodeStatus::Down, NodeStatus::Down),
+        (NodeStatus::Down, NodeStatus::Up),
+        (NodeStatus::Down, NodeStatus::Up),
+    ]);
+    assert!(changes.is_empty());
+    assert_eq!(health.unwrap().streak, 2);
+}
+
+#[test]
+fn recovery_also_has_to_hold() {
+    let detector = DisagreementDetector::new(2);
+    let (health, changes) = observe(&detector, &[
+        (NodeStatus::Up, NodeStatus::Down),
+        (NodeStatus::Up, NodeStatus::Down),
+        (NodeStatus::Up, NodeStatus::Up),
+        (NodeStatus::Up, NodeStatus::Down),
+        (NodeStatus::Up, NodeStatus::Up),
+        (NodeStatus::Up, NodeStatus::Up),
+    ]);
+    let health = health.unwrap();
+    assert_eq!(changes, vec![Verdict::LinodeUpProbeDown, Verdict::Agree]);
+    assert_eq!((health.lastverdict, health.current), (Verdict::LinodeUpProbeDown, Verdict::Agree));
+}
+
+#[test]
+fn a_probe_is_only_counted_once() {
+    let detector = DisagreementDetector::new(2);
+    let nodes = [node(NodeStatus::Up, Some(probe(NodeStatus::Down, 0)))];
+    let (rows, _) = detector.evaluate(&nodes, &[]);
+    let (again, alerts) = detector.evaluate(&nodes, &rows);
+    assert!(again.is_empty());
+    assert!(alerts.is_empty());
+
+    let unprobed = [node(NodeStatus::Up, None)];
+    assert!(detector.evaluate(&unprobed, &[]).0.is_empty());
+}
+
+#[tokio::test]
+async fn run_records_tracking_rows_and_posts_alerts() {
+    let webhook = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/alerts"))
+        .and(body_partial_json(json!({ "alert": { "health": { "node_id": 100, "current": "linode_up_probe_down" } } })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&webhook)
+        .await;
+
+    let store = Arc::new(MemoryStore::new());
+    let config: NodeBalancerConfigObject = serde_json::from_value(json!({ "id": 10, "nodebalancer_id": 1, "port": 80 })).unwrap();
+    let nodes: Vec<NodeObject> = vec![
+        serde_json::from_value(json!({ "id": 100, "config_id": 10, "nodebalancer_id": 1, "address": "10.0.0.1:80", "status": "UP" })).unwrap(),
+        serde_json::from_value(json!({ "id": 101, "config_id": 10, "nodebalancer_id": 1, "address": "10.0.0.2:80", "status": "UP" })).unwrap(),
+    ];
+    let nodebalancer = LocalNodeBalancerListObject { nb_id: 1, ipv4: "192.0.2.1".to_string(), region: "us-ord".to_string(), lke_id: None, ..Default::default() };
+    store.write_nodebalancer(nodebalancer, vec![config], nodes).await.unwrap();
+
+    let detector = DisagreementDetector::new(2).webhook(Some(format!("{}/alerts", webhook.uri())));
+    for second in 0..3 {
+        store.record_probes(vec![
+            NodeProbe { nodebalancer_id: 1, node_id: 100, probe: probe(NodeStatus::Down, second), timings: Default::default() },
+            NodeProbe { nodebalancer_id: 1, node_id: 101, probe: probe(NodeStatus::Up, second), timings: Default::default() },
+        ]).await.unwrap();
+        let detection = detector.run(store.as_ref()).await.unwrap();
+        let expected = if second == 0 { 0 } else { 1 };
+        assert_eq!(detection.disagreeing, expected, "after probe {}", second);
+        assert_eq!(detection.alerts.len() as u64, if second == 1 { 1 } else { 0 });
+    }
+
+    let health = store.node_health().await.unwrap();
+    let verdicts: Vec<_> = health.iter().map(|h| (h.node_id, h.current.clone(), h.streak)).collect();
+    assert_eq!(verdicts, vec![(100, Verdict::LinodeUpProbeDown, 3), (101, Verdict::Agree, 3)]);
+    assert_eq!(received(&webhook, 1).await, 1);
+}
+
+// Waits a second at most for `count` requests to reach `server`.
+async fn received(server: &MockServer, count: usize) -> usize {
+    for _ in 0..50 {
+        if server.received_requests().await.unwrap().len() >= count {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    server.received_requests().await.unwrap().len()
+}
+
+#[tokio::test]
+async fn alerts_are_posted_without_holding_up_the_round() {
+    let webhook = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+        .mount(&webhook)
+        .await;
+    let store = Arc::new(MemoryStore::new());
+    let config: NodeBalancerConfigObject = serde_json::from_value(json!({ "id": 10, "nodebalancer_id": 1, "port": 80 })).unwrap();
+    let nodes: Vec<NodeObject> = (100..103)
+        .map(|id| serde_json::from_value(json!({ "id": id, "config_id": 10, "nodebalancer_id": 1, "address": "10.0.0.1:80", "status": "UP" })).unwrap())
+        .collect();
+    let nodebalancer = LocalNodeBalancerListObject { nb_id: 1, ipv4: "192.0.2.1".to_string(), region: "us-ord".to_string(), lke_id: None, ..Default::default() };
+    store.write_nodebalancer(nodebalancer, vec![config], nodes).await.unwrap();
+    store.record_probes((100..103).map(|node_id| {
+        NodeProbe { nodebalancer_id: 1, node_id, probe: probe(NodeStatus::Down, 0), timings: Default::default() }
+    }).collect()).await.unwrap();
+
+    let started = Instant::now();
+    let detection = DisagreementDetector::new(1).webhook(Some(webhook.uri())).run(store.as_ref()).await.unwrap();
+
+    assert_eq!(detection.alerts.len(), 3);
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    // The first alert still goes out.
+    assert_eq!(received(&webhook, 1).await, 1);
+}
+
+#[tokio::test]
+async fn a_hanging_webhook_is_given_up_on() {
+    let webhook = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+        .mount(&webhook)
+        .await;
+
+    let started = Instant::now();
+    Webhook::new(&webhook.uri()).timeout(Duration::from_millis(100)).send(&"node 100 is down".to_string()).await;
+
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+}
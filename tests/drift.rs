@@ -0,0 +1,154 @@
+//! Config drift against a declared policy.
+
+mod common;
+
+use chrono::{Duration, Utc};
+use common::*;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+use hc_nb_api_client::drift::{glob, DriftDetector, Policy};
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::DriftViolation;
+use hc_nb_api_client::Store;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const POLICY: &str = r#"
+rules:
+  - name: tls-is-checked
+    match:
+      label: "nb-*"
+      lke_cluster: false
+      port: 443
+    expect:
+      check: [http, http_body]
+      check_attempts: { min: 3 }
+  - name: roundrobin
+    expect:
+      algorithm: roundrobin
+"#;
+
+fn fields(violations: &[DriftViolation]) -> Vec<(i32, &str, &str)> {
+    violations.iter().map(|v| (v.nodebalancer_id, v.rule.as_str(), v.field.as_str())).collect()
+}
+
+fn violation(nodebalancer_id: i32, field: &str) -> DriftViolation {
+    DriftViolation {
+        nodebalancer_id,
+        config_id: nodebalancer_id * 10,
+        port: 443,
+        rule: "tls-is-checked".to_string(),
+        field: field.to_string(),
+        expected: "at least 3".to_string(),
+        actual: "0".to_string(),
+        since: Utc::now() - Duration::hours(1),
+    }
+}
+
+// NB 1 with an unchecked config on port 443, and NB 2 on port 80.
+async fn serve_region(mock: &MockLinode, check: &str) {
+    mock.server.reset().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    let mut tls = config(1, 10, 443, 1, 0);
+    tls["check"] = json!(check);
+    mock.list(&configs_path(1), vec![tls]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP")]).await;
+    simple_nodebalancer(mock, 2, 1).await;
+}
+
+#[test]
+fn policies_are_parsed_strictly() {
+    let policy = Policy::parse(POLICY).unwrap();
+    assert_eq!(policy.rules.len(), 2);
+    assert!(policy.uses_labels());
+
+    let typo = "rules:\n  - name: x\n    expect:\n      check_atempts: { min: 3 }\n";
+    assert!(Policy::parse(typo).is_err());
+}
+
+#[test]
+fn labels_match_with_wildcards() {
+    assert!(glob("prod-*", "prod-web"));
+    assert!(glob("*-web-*", "prod-web-1"));
+    assert!(glob("nb", "nb"));
+    assert!(!glob("prod-*", "staging-web"));
+    assert!(!glob("*-web", "prod-web-1"));
+}
+
+#[tokio::test]
+async fn sync_records_violations_and_keeps_their_start() {
+    let mock = MockLinode::start().await;
+    serve_region(&mock, "connection").await;
+    let store = Arc::new(MemoryStore::new());
+    let detector = DriftDetector::new(Policy::parse(POLICY).unwrap());
+    let syncer = mock.syncer(Arc::clone(&store)).drift(Some(detector));
+
+    let report = syncer.run_cycle().await.unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    let first = store.config_drift().await.unwrap();
+    assert_eq!(fields(&first), vec![(1, "tls-is-checked", "check"), (1, "tls-is-checked", "check_attempts")]);
+    assert_eq!(first[0].actual, "connection");
+    assert_eq!(first[0].expected, "one of http, http_body");
+    assert_eq!(first[1].expected, "at least 3");
+
+    serve_region(&mock, "http").await;
+    syncer.run_cycle().await.unwrap();
+    let second = store.config_drift().await.unwrap();
+    assert_eq!(fields(&second), vec![(1, "tls-is-checked", "check_attempts")]);
+    assert_eq!(second[0].since, first[1].since);
+}
+
+#[tokio::test]
+async fn alerts_are_raised_when_violations_appear_or_go_away() {
+    let store = MemoryStore::new();
+    let detector = DriftDetector::new(Policy::default());
+    let listed = HashSet::from([1, 2]);
+    let checked = HashSet::from([1]);
+
+    let alerts = detector.run(&store, &checked, &listed, vec![violation(1, "check"), violation(1, "check_attempts")]).await.unwrap();
+    assert_eq!(alerts.len(), 2);
+    assert!(alerts.iter().all(|a| !a.resolved));
+
+    let alerts = detector.run(&store, &checked, &listed, vec![violation(1, "check_attempts")]).await.unwrap();
+    assert_eq!(alerts.len(), 1);
+    assert!(alerts[0].resolved);
+    assert_eq!(alerts[0].to_string(), "NB 1 config 10 port 443: check meets rule tls-is-checked again");
+
+    // A nodebalancer that was not checked keeps its violations until it is
+    // no longer listed.
+    store.record_config_drift(vec![2], vec![violation(2, "check")]).await.unwrap();
+    detector.run(&store, &checked, &listed, vec![violation(1, "check_attempts")]).await.unwrap();
+    assert_eq!(store.config_drift().await.unwrap().len(), 2);
+    let alerts = detector.run(&store, &checked, &HashSet::from([1]), vec![violation(1, "check_attempts")]).await.unwrap();
+    assert!(alerts.is_empty());
+    assert_eq!(fields(&store.config_drift().await.unwrap()), vec![(1, "tls-is-checked", "check_attempts")]);
+}
+
+#[tokio::test]
+async fn alerts_are_posted_without_holding_up_the_cycle() {
+    let webhook = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/alerts"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(30)))
+        .expect(1)
+        .mount(&webhook)
+        .await;
+    let store = MemoryStore::new();
+    let detector = DriftDetector::new(Policy::default()).webhook(Some(format!("{}/alerts", webhook.uri())));
+
+    let started = Instant::now();
+    let alerts = detector.run(&store, &HashSet::from([1]), &HashSet::from([1]), vec![violation(1, "check")]).await.unwrap();
+
+    assert_eq!(alerts.len(), 1);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5), "{:?}", started.elapsed());
+    // The alert still goes out.
+    for _ in 0..50 {
+        if !webhook.received_requests().await.unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(webhook.received_requests().await.unwrap().len(), 1);
+}
@@ -0,0 +1,109 @@
+//! Incremental sync cycles: what is fetched again, going by `updated`.
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use hc_nb_api_client::incremental::Incremental;
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::NodeStatus;
+use hc_nb_api_client::{Store, Syncer};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+// NB 1, last updated at `updated`, with one config and node 100 in `status`.
+async fn serve(mock: &MockLinode, updated: &str, status: &str) {
+    mock.server.reset().await;
+    let mut nb = nodebalancer(1);
+    nb["updated"] = json!(updated);
+    mock.nodebalancers(vec![nb]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 1, 0)]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, status)]).await;
+}
+
+async fn requests(mock: &MockLinode, endpoint: &str) -> usize {
+    let received = mock.server.received_requests().await.unwrap();
+    received.iter().filter(|r| r.url.path() == endpoint).count()
+}
+
+fn syncer(mock: &MockLinode, store: &Arc<MemoryStore>, full_resync: Duration, node_refresh: Duration) -> Syncer {
+    mock.syncer(Arc::clone(store)).incremental(Some(Incremental { full_resync, node_refresh }))
+}
+
+#[tokio::test]
+async fn only_updated_nodebalancers_are_fetched_again() {
+    let mock = MockLinode::start().await;
+    let store = Arc::new(MemoryStore::new());
+    let syncer = syncer(&mock, &store, HOUR, HOUR);
+
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    let first = syncer.run_cycle().await.unwrap();
+    assert_eq!((first.inserted, first.unchanged), (3, 0));
+
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    let second = syncer.run_cycle().await.unwrap();
+    assert_eq!((second.inserted, second.updated, second.unchanged), (0, 0, 1));
+    assert!(second.to_string().contains(", 1 NBs unchanged"));
+    assert_eq!(requests(&mock, &configs_path(1)).await, 0);
+    assert_eq!(requests(&mock, &nodes_path(1, 10)).await, 0);
+
+    serve(&mock, "2026-10-02T00:00:00", "UP").await;
+    let third = syncer.run_cycle().await.unwrap();
+    assert_eq!(third.unchanged, 0);
+    assert_eq!(requests(&mock, &configs_path(1)).await, 1);
+
+    // After a change of leader everything is fetched again.
+    syncer.resume();
+    serve(&mock, "2026-10-02T00:00:00", "UP").await;
+    syncer.run_cycle().await.unwrap();
+    assert_eq!(requests(&mock, &configs_path(1)).await, 1);
+}
+
+#[tokio::test]
+async fn nodes_of_unchanged_nodebalancers_are_refreshed() {
+    let mock = MockLinode::start().await;
+    let store = Arc::new(MemoryStore::new());
+    let syncer = syncer(&mock, &store, HOUR, Duration::ZERO);
+
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    syncer.run_cycle().await.unwrap();
+
+    serve(&mock, "2026-10-01T00:00:00", "DOWN").await;
+    let report = syncer.run_cycle().await.unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.unchanged, 1);
+    assert_eq!(requests(&mock, &configs_path(1)).await, 0);
+    assert_eq!(requests(&mock, &nodes_path(1, 10)).await, 1);
+
+    let nodes = store.node_details().await.unwrap();
+    assert_eq!(nodes[0].status, NodeStatus::Down);
+    let configs = store.configs().await.unwrap();
+    assert_eq!((configs[0].up, configs[0].down), (0, 1));
+}
+
+#[tokio::test]
+async fn failed_refreshes_and_due_resyncs_fetch_in_full() {
+    let mock = MockLinode::start().await;
+    let store = Arc::new(MemoryStore::new());
+
+    // Without a full resync interval every cycle fetches every config.
+    let always = syncer(&mock, &store, Duration::ZERO, HOUR);
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    always.run_cycle().await.unwrap();
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    assert_eq!(always.run_cycle().await.unwrap().unchanged, 0);
+    assert_eq!(requests(&mock, &configs_path(1)).await, 1);
+
+    // A config that cannot be refreshed may be gone.
+    let refreshing = syncer(&mock, &store, HOUR, Duration::ZERO);
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    refreshing.run_cycle().await.unwrap();
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    mock.fail(&nodes_path(1, 10), 404).await;
+    assert_eq!(refreshing.run_cycle().await.unwrap().errors.len(), 1);
+    serve(&mock, "2026-10-01T00:00:00", "UP").await;
+    refreshing.run_cycle().await.unwrap();
+    assert_eq!(requests(&mock, &configs_path(1)).await, 1);
+}
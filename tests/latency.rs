@@ -0,0 +1,163 @@
+//! The probe time series: downsampling, percentiles, retention and the HTTP
+//! API serving them.
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use hc_nb_api_client::latency::{self, percentile, Retention};
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::{
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeObject,
+    NodeProbe,
+    NodeStatus,
+    Probe,
+    ProbeSample,
+    ProbeTimings,
+};
+use hc_nb_api_client::{api, duration, Store};
+
+fn at(second: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(1_700_000_100 + second, 0).unwrap()
+}
+
+fn raw(node_id: i32, second: i64, latency_ms: Option<f64>) -> ProbeSample {
+    ProbeSample {
+        nodebalancer_id: 1,
+        node_id,
+        probed_at: at(second),
+        resolution: 0,
+        probes: 1,
+        up: latency_ms.is_some() as i32,
+        latency_ms,
+        connect_ms: Some(1.0),
+        tls_ms: None,
+        ttfb_ms: latency_ms.map(|l| l / 2.0),
+        http_status: Some(if latency_ms.is_some() { 200 } else { 503 }),
+    }
+}
+
+fn node_probe(node_id: i32, probed_at: DateTime<Utc>, latency_ms: Option<f64>) -> NodeProbe {
+    let status = if latency_ms.is_some() { NodeStatus::Up } else { NodeStatus::Down };
+    NodeProbe {
+        nodebalancer_id: 1,
+        node_id,
+        probe: Probe { status, latency_ms, error: None, probed_at },
+        timings: ProbeTimings { connect_ms: Some(1.0), ..Default::default() },
+    }
+}
+
+async fn store_with_nodes() -> Arc<MemoryStore> {
+    let store = Arc::new(MemoryStore::new());
+    let config: NodeBalancerConfigObject = serde_json::from_value(json!({ "id": 10, "nodebalancer_id": 1, "port": 80 })).unwrap();
+    let nodes: Vec<NodeObject> = [100, 101].iter().map(|id| serde_json::from_value(json!({
+        "id": id, "config_id": 10, "nodebalancer_id": 1, "address": format!("10.0.0.{}:80", id), "status": "UP",
+    })).unwrap()).collect();
+    let nodebalancer = LocalNodeBalancerListObject { nb_id: 1, ipv4: "192.0.2.1".to_string(), region: "us-ord".to_string(), lke_id: None, ..Default::default() };
+    store.write_nodebalancer(nodebalancer, vec![config], nodes).await.unwrap();
+    store
+}
+
+#[test]
+fn durations_parse_with_units() {
+    assert_eq!(duration::parse("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(duration::parse("15m"), Ok(Duration::from_secs(15 * 60)));
+    assert_eq!(duration::parse("1h"), Ok(Duration::from_secs(60 * 60)));
+    assert_eq!(duration::parse("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+    assert!(duration::parse("1w").is_err());
+    assert!(duration::parse("m").is_err());
+}
+
+#[test]
+fn percentiles_use_the_nearest_rank() {
+    let values: Vec<f64> = (1..=20).map(f64::from).collect();
+    assert_eq!(percentile(&values, 50.0), Some(10.0));
+    assert_eq!(percentile(&values, 95.0), Some(19.0));
+    assert_eq!(percentile(&values[..1], 95.0), Some(1.0));
+    assert_eq!(percentile(&[], 50.0), None);
+}
+
+#[test]
+fn downsampling_averages_each_period() {
+    let samples = vec![raw(100, 0, Some(10.0)), raw(100, 60, None), raw(100, 120, Some(20.0)), raw(100, 300, Some(40.0)), raw(101, 0, Some(5.0))];
+    let points = latency::downsample(&samples, 300);
+
+    assert_eq!(points.len(), 3);
+    let first = &points[0];
+    assert_eq!((first.node_id, first.probed_at, first.resolution), (100, at(0), 300));
+    assert_eq!((first.probes, first.up), (3, 2));
+    assert_eq!(first.latency_ms, Some(15.0));
+    assert_eq!(first.connect_ms, Some(1.0));
+    assert_eq!(first.http_status, Some(503));
+    assert_eq!((points[1].node_id, points[1].probed_at, points[1].probes), (100, at(300), 1));
+    assert_eq!((points[2].node_id, points[2].probes), (101, 1));
+}
+
+#[test]
+fn summaries_take_percentiles_of_raw_probes_only() {
+    let mut samples: Vec<ProbeSample> = (0..20).map(|i| raw(100, 600 + i, Some(f64::from(i as i32 + 1)))).collect();
+    samples.push(raw(100, 620, None));
+    samples.extend(latency::downsample(&[raw(100, 0, Some(1000.0)), raw(100, 1, None)], 300));
+
+    let summary = latency::summarize(&samples);
+    assert_eq!(summary.len(), 1);
+    let node = &summary[0];
+    assert_eq!((node.probes, node.up), (23, 21));
+    assert_eq!(node.p50_ms, Some(10.0));
+    assert_eq!(node.p95_ms, Some(19.0));
+    assert_eq!(node.ttfb_p95_ms, Some(9.5));
+    assert!((node.availability - 21.0 / 23.0).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn retention_downsamples_and_expires() {
+    let store = store_with_nodes().await;
+    let now = at(10 * 24 * 60 * 60);
+    let ago = |d: Duration| latency::ago(now, d);
+    store.record_probes(vec![
+        node_probe(100, ago(Duration::from_secs(8 * 24 * 60 * 60)), Some(1.0)),
+        node_probe(100, ago(Duration::from_secs(2 * 60 * 60)), Some(2.0)),
+        node_probe(100, ago(Duration::from_secs(2 * 60 * 60 - 10)), Some(4.0)),
+        node_probe(100, ago(Duration::from_secs(60)), Some(8.0)),
+    ]).await.unwrap();
+
+    Retention::default().apply(store.as_ref(), now).await.unwrap();
+
+    let points = store.probe_samples(DateTime::<Utc>::MIN_UTC).await.unwrap();
+    let shape: Vec<_> = points.iter().map(|p| (p.resolution, p.probes, p.latency_ms)).collect();
+    assert_eq!(shape, vec![(300, 2, Some(3.0)), (0, 1, Some(8.0))]);
+}
+
+#[tokio::test]
+async fn api_serves_nodes_latency_and_metrics() {
+    let store = store_with_nodes().await;
+    let now = Utc::now();
+    store.record_probes((1..=10).map(|i| node_probe(100, latency::ago(now, Duration::from_secs(i)), Some(i as f64))).collect()).await.unwrap();
+    store.record_probes(vec![node_probe(101, now, None)]).await.unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, api::router(vec![("us-ord".to_string(), Arc::clone(&store) as Arc<dyn Store>)])).into_future());
+    let http = reqwest::Client::new();
+
+    let nodes: Value = http.get(format!("{}/nodes", base)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(nodes.as_array().unwrap().len(), 2);
+    assert_eq!(nodes[1]["probe"]["status"], "DOWN");
+
+    let latency: Value = http.get(format!("{}/nodes/latency?window=1h", base)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(latency[0]["node_id"], 100);
+    assert_eq!(latency[0]["p50_ms"], 5.0);
+    assert_eq!(latency[0]["p95_ms"], 10.0);
+    assert_eq!(latency[1]["availability"], 0.0);
+
+    let invalid = http.get(format!("{}/nodes/latency?window=soon", base)).send().await.unwrap();
+    assert_eq!(invalid.status(), 400);
+
+    let metrics = http.get(format!("{}/metrics", base)).send().await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("# TYPE hc_nb_node_up gauge"));
+    assert!(metrics.contains("hc_nb_node_up{location=\"us-ord\",nodebalancer_id=\"1\",config_id=\"10\",node_id=\"101\",address=\"10.0.0.101:80\",source=\"probe\"} 0"));
+    assert!(metrics.contains("hc_nb_node_probe_latency_ms{location=\"us-ord\",nodebalancer_id=\"1\",node_id=\"100\",quantile=\"0.95\"} 10"));
+    assert!(metrics.contains("hc_nb_node_probe_availability{location=\"us-ord\",nodebalancer_id=\"1\",node_id=\"101\"} 0"));
+}
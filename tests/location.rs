@@ -0,0 +1,102 @@
+//! Several locations in one process: their settings, and metrics and
+//! cycles that stay apart.
+
+mod common;
+
+use common::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use hc_nb_api_client::location::{self, Defaults, LocationSettings, SHARED_LOCALDB};
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::sync::Discovery;
+use hc_nb_api_client::{metrics, Store, Syncer};
+use serde_json::json;
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn defaults() -> Defaults {
+    Defaults { sqlite_path: PathBuf::from("/data/hc-nb-client.db"), db_concurrency: 100, fetch_concurrency: 16 }
+}
+
+fn resolve(locations: &[&str], vars: &[(&str, &str)]) -> Result<Vec<LocationSettings>, hc_nb_api_client::Error> {
+    let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    let locations: Vec<String> = locations.iter().map(|l| l.to_string()).collect();
+    LocationSettings::resolve(&locations, &defaults(), |name| vars.get(name).cloned())
+}
+
+#[test]
+fn location_lists_are_split_and_named_for_variables() {
+    assert_eq!(location::parse_list(" us-ord, us-iad,,us-ord "), vec!["us-ord", "us-iad"]);
+    assert_eq!(location::env_suffix("us-ord"), "US_ORD");
+}
+
+#[test]
+fn one_location_keeps_the_shared_settings() {
+    let settings = resolve(&["us-ord"], &[]).unwrap();
+    assert_eq!(settings, vec![LocationSettings {
+        location: "us-ord".to_string(),
+        localdb_prefix: SHARED_LOCALDB.to_string(),
+        sqlite_path: PathBuf::from("/data/hc-nb-client.db"),
+        db_concurrency: 100,
+        fetch_concurrency: 16,
+    }]);
+    assert!(resolve(&[], &[]).is_err());
+}
+
+#[test]
+fn each_location_can_override_the_shared_settings() {
+    let settings = resolve(&["us-ord", "us-iad"], &[
+        ("LOCALDB_US_IAD_HOSTPORT", "10.0.0.2:5432"),
+        ("DB_CONCURRENCY_US_IAD", "10"),
+        ("FETCH_CONCURRENCY_US_ORD", "4"),
+        ("LOCALDB_US_ORD_PATH", "/data/ord.db"),
+    ]).unwrap();
+
+    assert_eq!((settings[0].localdb_prefix.as_str(), settings[1].localdb_prefix.as_str()), ("LOCALDB", "LOCALDB_US_IAD"));
+    assert_eq!((settings[0].db_concurrency, settings[0].fetch_concurrency), (100, 4));
+    assert_eq!((settings[1].db_concurrency, settings[1].fetch_concurrency), (10, 16));
+    assert_eq!(settings[0].sqlite_path, PathBuf::from("/data/ord.db"));
+    assert_eq!(settings[1].sqlite_path, PathBuf::from("/data/hc-nb-client-us-iad.db"));
+    assert!(LocationSettings::check_postgres(&settings).is_ok());
+
+    assert!(resolve(&["us-ord"], &[("DB_CONCURRENCY_US_ORD", "many")]).is_err());
+}
+
+#[test]
+fn locations_cannot_share_one_postgres_database() {
+    let settings = resolve(&["us-ord", "us-iad"], &[]).unwrap();
+    let error = LocationSettings::check_postgres(&settings).unwrap_err().to_string();
+    assert!(error.contains("LOCALDB_US_IAD_HOSTPORT"), "{}", error);
+}
+
+#[tokio::test]
+async fn locations_sync_into_their_own_stores_and_metrics() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    simple_nodebalancer(&mock, 1, 1).await;
+    let ord = Arc::new(MemoryStore::new());
+    let iad = Arc::new(MemoryStore::new());
+
+    Mock::given(method("GET"))
+        .and(path("/nodebalancers"))
+        .and(header("X-Filter", json!({ "region": "us-iad" }).to_string().as_str()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": [], "page": 1, "pages": 1, "results": 0 })))
+        .mount(&mock.server)
+        .await;
+
+    mock.syncer(Arc::clone(&ord)).run_cycle().await.unwrap();
+    Syncer::new(mock.client().concurrency(2), Arc::clone(&iad) as Arc<dyn Store>, "us-iad")
+        .discovery(Discovery::Api)
+        .run_cycle().await.unwrap();
+
+    assert_eq!(ord.nodebalancer_ids().await.unwrap(), vec![1]);
+    assert!(iad.nodebalancer_ids().await.unwrap().is_empty());
+
+    let text = metrics::render(&[
+        (REGION.to_string(), ord as Arc<dyn Store>),
+        ("us-iad".to_string(), iad as Arc<dyn Store>),
+    ]).await.unwrap();
+    assert!(text.contains(&format!("hc_nb_node_up{{location=\"{}\",nodebalancer_id=\"1\"", REGION)));
+    assert!(!text.contains("location=\"us-iad\""));
+}
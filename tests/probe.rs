@@ -0,0 +1,232 @@
+//! Probing real local listeners, and recording rounds in the store.
+
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use hc_nb_api_client::database::PgTarget;
+use hc_nb_api_client::leader::Election;
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::{
+    CheckType,
+    LocalNodeBalancerListObject,
+    NodeBalancerConfigObject,
+    NodeObject,
+    NodeStatus,
+    ProbeTarget,
+    Protocol,
+};
+use hc_nb_api_client::probe::{Checker, ProbeKind, Prober};
+use hc_nb_api_client::Store;
+use tokio::net::TcpListener;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn target(address: &str, protocol: Protocol, check: CheckType) -> ProbeTarget {
+    ProbeTarget {
+        nodebalancer_id: 1,
+        config_id: 10,
+        node_id: 100,
+        address: address.to_string(),
+        protocol,
+        check,
+        check_path: "/health".to_string(),
+        check_body: String::new(),
+        check_interval: 0,
+        check_timeout: 1,
+    }
+}
+
+async fn listener() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    (listener, address)
+}
+
+async fn closed_address() -> String {
+    let (_, address) = listener().await;
+    address
+}
+
+async fn health_server(status: u16, body: &str) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/health"))
+        .respond_with(ResponseTemplate::new(status).set_body_string(body))
+        .mount(&server)
+        .await;
+    server
+}
+
+fn address_of(server: &MockServer) -> String {
+    server.address().to_string()
+}
+
+#[test]
+fn kind_follows_the_config_check() {
+    let tls_ports = [443];
+    let http = target("10.0.0.1:80", Protocol::Http, CheckType::Http);
+    assert_eq!(ProbeKind::for_target(&http, &tls_ports), Some(ProbeKind::Http { path: "/health".to_string(), body: None }));
+
+    let mut body = target("10.0.0.1:80", Protocol::Https, CheckType::HttpBody);
+    body.check_body = "ok".to_string();
+    body.check_path = String::new();
+    assert_eq!(ProbeKind::for_target(&body, &tls_ports), Some(ProbeKind::Http { path: "/".to_string(), body: Some("ok".to_string()) }));
+
+    let tcp = target("10.0.0.1:5432", Protocol::Tcp, CheckType::Connection);
+    assert_eq!(ProbeKind::for_target(&tcp, &tls_ports), Some(ProbeKind::Tcp));
+
+    let passthrough = target("10.0.0.1:443", Protocol::Tcp, CheckType::None);
+    assert_eq!(ProbeKind::for_target(&passthrough, &tls_ports), Some(ProbeKind::Tls));
+
+    // HTTPS configs terminate TLS on the NodeBalancer.
+    let terminated = target("10.0.0.1:443", Protocol::Https, CheckType::Connection);
+    assert_eq!(ProbeKind::for_target(&terminated, &tls_ports), Some(ProbeKind::Tcp));
+
+    let udp = target("10.0.0.1:53", Protocol::Udp, CheckType::Connection);
+    assert_eq!(ProbeKind::for_target(&udp, &tls_ports), None);
+}
+
+#[tokio::test]
+async fn tcp_probe_sees_listening_and_closed_ports() {
+    let checker = Checker::new().unwrap();
+    let (_listener, open) = listener().await;
+
+    let up = checker.probe(&target(&open, Protocol::Tcp, CheckType::Connection)).await.unwrap();
+    assert_eq!(up.status, NodeStatus::Up);
+    assert!(up.latency_ms.is_some());
+    assert_eq!(up.error, None);
+
+    let down = checker.probe(&target(&closed_address().await, Protocol::Tcp, CheckType::Connection)).await.unwrap();
+    assert_eq!(down.status, NodeStatus::Down);
+    assert_eq!(down.latency_ms, None);
+    assert!(down.error.is_some());
+}
+
+#[tokio::test]
+async fn http_probe_checks_status_and_body() {
+    let checker = Checker::new().unwrap();
+    let healthy = health_server(200, "status: ok").await;
+    let failing = health_server(503, "status: ok").await;
+
+    let mut check = target(&address_of(&healthy), Protocol::Http, CheckType::HttpBody);
+    check.check_body = "ok".to_string();
+    assert_eq!(checker.probe(&check).await.unwrap().status, NodeStatus::Up);
+
+    check.check_body = "ready".to_string();
+    let wrong_body = checker.probe(&check).await.unwrap();
+    assert_eq!(wrong_body.status, NodeStatus::Down);
+    assert!(wrong_body.error.unwrap().contains("ready"));
+
+    let check = target(&address_of(&failing), Protocol::Http, CheckType::Http);
+    let unavailable = checker.probe(&check).await.unwrap();
+    assert_eq!(unavailable.status, NodeStatus::Down);
+    assert!(unavailable.error.unwrap().contains("503"));
+}
+
+#[tokio::test]
+async fn measure_times_each_phase() {
+    let checker = Checker::new().unwrap();
+    let (_listener, open) = listener().await;
+    let (_, tcp) = checker.measure(&target(&open, Protocol::Tcp, CheckType::Connection)).await.unwrap();
+    assert!(tcp.connect_ms.is_some());
+    assert_eq!((tcp.tls_ms, tcp.ttfb_ms, tcp.http_status), (None, None, None));
+
+    // A failing check keeps the phases it got through.
+    let failing = health_server(503, "").await;
+    let (probe, http) = checker.measure(&target(&address_of(&failing), Protocol::Http, CheckType::Http)).await.unwrap();
+    assert_eq!(probe.status, NodeStatus::Down);
+    assert!(http.connect_ms.is_some() && http.ttfb_ms.is_some());
+    assert_eq!(http.http_status, Some(503));
+
+    let (_, closed) = checker.measure(&target(&closed_address().await, Protocol::Http, CheckType::Http)).await.unwrap();
+    assert_eq!(closed, Default::default());
+}
+
+#[tokio::test]
+async fn slow_node_times_out() {
+    let checker = Checker::new().unwrap();
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+        .mount(&server)
+        .await;
+
+    let probe = checker.probe(&target(&address_of(&server), Protocol::Http, CheckType::Http)).await.unwrap();
+    assert_eq!(probe.status, NodeStatus::Down);
+    assert!(probe.error.unwrap().contains("timed out"));
+}
+
+#[tokio::test]
+async fn tls_probe_fails_against_plain_tcp() {
+    let server = health_server(200, "ok").await;
+    let port = server.address().port();
+    let checker = Checker::new().unwrap().tls_ports(vec![port]);
+
+    let probe = checker.probe(&target(&address_of(&server), Protocol::Tcp, CheckType::Connection)).await.unwrap();
+    assert_eq!(probe.status, NodeStatus::Down);
+    assert!(probe.error.unwrap().contains("TLS"));
+}
+
+#[tokio::test]
+async fn rounds_record_probes_and_respect_the_interval() {
+    let (_listener, open) = listener().await;
+    let closed = closed_address().await;
+    let store = Arc::new(MemoryStore::new());
+    let config: NodeBalancerConfigObject = serde_json::from_value(json!({
+        "id": 10, "nodebalancer_id": 1, "port": 80, "protocol": "tcp", "check": "connection",
+        "check_interval": 60, "check_timeout": 1,
+    })).unwrap();
+    let node = |id: i32, address: &str| -> NodeObject {
+        serde_json::from_value(json!({
+            "id": id, "config_id": 10, "nodebalancer_id": 1, "address": address, "status": "UP",
+        })).unwrap()
+    };
+    let nodebalancer = || LocalNodeBalancerListObject { nb_id: 1, ipv4: "192.0.2.1".to_string(), region: "us-ord".to_string(), lke_id: None, ..Default::default() };
+    store.write_nodebalancer(nodebalancer(), vec![config], vec![node(100, &open), node(101, &closed)]).await.unwrap();
+
+    let mut prober = Prober::new(Arc::clone(&store) as Arc<dyn Store>, Checker::new().unwrap());
+    let first = prober.run_round().await.unwrap();
+    assert_eq!((first.probed, first.up, first.down), (2, 1, 1));
+
+    let nodes = store.node_details().await.unwrap();
+    let probed: Vec<_> = nodes.iter().map(|n| (n.id, n.status.clone(), n.probe.as_ref().map(|p| p.status.clone()))).collect();
+    assert_eq!(probed, vec![
+        (100, NodeStatus::Up, Some(NodeStatus::Up)),
+        (101, NodeStatus::Up, Some(NodeStatus::Down)),
+    ]);
+
+    let samples = store.probe_samples(chrono::DateTime::<chrono::Utc>::MIN_UTC).await.unwrap();
+    assert_eq!(samples.iter().map(|s| (s.node_id, s.up)).collect::<Vec<_>>(), vec![(100, 1), (101, 0)]);
+
+    // Neither node is due again for a minute.
+    let second = prober.run_round().await.unwrap();
+    assert_eq!(second.probed, 0);
+
+    // A resync keeps the last probe.
+    let config: NodeBalancerConfigObject = serde_json::from_value(json!({ "id": 10, "nodebalancer_id": 1, "port": 80 })).unwrap();
+    store.write_nodebalancer(nodebalancer(), vec![config], vec![node(100, &open)]).await.unwrap();
+    assert!(store.node_details().await.unwrap()[0].probe.is_some());
+}
+
+#[tokio::test]
+async fn standbys_do_not_probe() {
+    let (_listener, open) = listener().await;
+    let store = Arc::new(MemoryStore::new());
+    let config: NodeBalancerConfigObject = serde_json::from_value(json!({
+        "id": 10, "nodebalancer_id": 1, "port": 80, "protocol": "tcp", "check": "connection",
+    })).unwrap();
+    let node: NodeObject = serde_json::from_value(json!({
+        "id": 100, "config_id": 10, "nodebalancer_id": 1, "address": open, "status": "UP",
+    })).unwrap();
+    let nodebalancer = LocalNodeBalancerListObject { nb_id: 1, ipv4: "192.0.2.1".to_string(), region: "us-ord".to_string(), lke_id: None, ..Default::default() };
+    store.write_nodebalancer(nodebalancer, vec![config], vec![node]).await.unwrap();
+
+    // An election that never ran leaves this replica a standby.
+    let election = Election::new(PgTarget::new("127.0.0.1:9", "unused"), "us-ord");
+    let prober = Prober::new(Arc::clone(&store) as Arc<dyn Store>, Checker::new().unwrap()).leadership(election.leadership());
+    let running = tokio::spawn(prober.run(Duration::from_millis(10)));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    running.abort();
+
+    assert!(store.node_details().await.unwrap()[0].probe.is_none());
+}
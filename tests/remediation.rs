@@ -0,0 +1,213 @@
+//! Switching nodes that keep failing probes out of rotation, and back.
+
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::{config, node, MockLinode};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use hc_nb_api_client::latency::ago;
+use hc_nb_api_client::memory::{MemoryStore, StateRow};
+use hc_nb_api_client::models::{
+    AuditAction,
+    LocalNodeBalancerListObject,
+    NodeMode,
+    NodeProbe,
+    NodeStatus,
+    Probe,
+    ProbeSample,
+};
+use hc_nb_api_client::remediation::{trend, RemediationMode, Remediator, Trend};
+use hc_nb_api_client::Store;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+fn sample(node_id: i32, probed_at: DateTime<Utc>, up: bool) -> ProbeSample {
+    ProbeSample {
+        nodebalancer_id: 1,
+        node_id,
+        probed_at,
+        resolution: 0,
+        probes: 1,
+        up: up as i32,
+        latency_ms: up.then_some(1.0),
+        connect_ms: None,
+        tls_ms: None,
+        ttfb_ms: None,
+        http_status: None,
+    }
+}
+
+// Nodes 100 to 102 of config 10, with the given modes.
+async fn store_with_modes(modes: [&str; 3]) -> Arc<MemoryStore> {
+    let store = Arc::new(MemoryStore::new());
+    sync_modes(&store, modes).await;
+    store
+}
+
+async fn sync_modes(store: &MemoryStore, modes: [&str; 3]) {
+    let nodes = modes.iter().enumerate().map(|(i, mode)| {
+        let mut n = node(1, 10, 100 + i as i32, "UP");
+        n["mode"] = Value::from(*mode);
+        serde_json::from_value(n).unwrap()
+    }).collect();
+    let nodebalancer = LocalNodeBalancerListObject { nb_id: 1, ipv4: "192.0.2.1".to_string(), region: "us-ord".to_string(), lke_id: None, ..Default::default() };
+    store.write_nodebalancer(nodebalancer, vec![serde_json::from_value(config(1, 10, 80, 3, 0)).unwrap()], nodes).await.unwrap();
+}
+
+// Probes each node over the last one and a half windows, passing or not.
+async fn probe_history(store: &MemoryStore, up: [bool; 3]) {
+    let now = Utc::now();
+    for seconds in [90, 30, 5] {
+        let probes = up.iter().enumerate().map(|(i, up)| NodeProbe {
+            nodebalancer_id: 1,
+            node_id: 100 + i as i32,
+            probe: Probe {
+                status: if *up { NodeStatus::Up } else { NodeStatus::Down },
+                latency_ms: up.then_some(1.0),
+                error: None,
+                probed_at: ago(now, Duration::from_secs(seconds)),
+            },
+            timings: Default::default(),
+        }).collect();
+        store.record_probes(probes).await.unwrap();
+    }
+}
+
+fn remediator(mock: &MockLinode, store: &Arc<MemoryStore>) -> Remediator {
+    Remediator::new(mock.client(), Arc::clone(store) as Arc<dyn Store>, RemediationMode::Drain).window(WINDOW)
+}
+
+#[test]
+fn trend_needs_a_whole_window() {
+    let now = Utc::now();
+    let start = ago(now, WINDOW);
+    let before = ago(now, Duration::from_secs(90));
+    let recent = ago(now, Duration::from_secs(10));
+
+    let failing = [sample(100, before, true), sample(100, recent, false)];
+    assert_eq!(trend(&failing.iter().collect::<Vec<_>>(), start), Trend::Failing);
+    let passing = [sample(100, before, false), sample(100, recent, true)];
+    assert_eq!(trend(&passing.iter().collect::<Vec<_>>(), start), Trend::Passing);
+    let mixed = [sample(100, before, false), sample(100, recent, false), sample(100, now, true)];
+    assert_eq!(trend(&mixed.iter().collect::<Vec<_>>(), start), Trend::Unsettled);
+    let new = [sample(100, recent, false)];
+    assert_eq!(trend(&new.iter().collect::<Vec<_>>(), start), Trend::Unsettled);
+}
+
+#[tokio::test]
+async fn only_a_fraction_of_each_config_is_taken_out() {
+    let mock = MockLinode::start().await;
+    let store = store_with_modes(["accept", "accept", "accept"]).await;
+    let now = Utc::now();
+    let nodes = store.node_details().await.unwrap();
+    let samples: Vec<_> = [90, 5].iter()
+        .flat_map(|s| [sample(100, ago(now, Duration::from_secs(*s)), false), sample(101, ago(now, Duration::from_secs(*s)), false)])
+        .collect();
+
+    let plan = remediator(&mock, &store).plan(&nodes, &[], &samples, now);
+    let drained: Vec<_> = plan.actions.iter().map(|a| (a.node_id, a.from.clone(), a.to.clone())).collect();
+    assert_eq!(drained, vec![(100, NodeMode::Accept, NodeMode::Drain)]);
+    assert_eq!(plan.held_back.iter().map(|a| a.node_id).collect::<Vec<_>>(), vec![101]);
+
+    let plan = remediator(&mock, &store).max_fraction(1.0).plan(&nodes, &[], &samples, now);
+    assert_eq!(plan.actions.len(), 2);
+}
+
+#[tokio::test]
+async fn nodes_out_of_rotation_by_hand_count_towards_the_limit() {
+    let mock = MockLinode::start().await;
+    let store = store_with_modes(["accept", "accept", "reject"]).await;
+    probe_history(&store, [false, true, false]).await;
+
+    let report = remediator(&mock, &store).run().await.unwrap();
+    assert!(report.applied.is_empty());
+    assert_eq!(report.held_back.len(), 1);
+
+    // Node 102 is not in accept mode, so it is never switched.
+    let report = remediator(&mock, &store).max_fraction(1.0).dry_run(true).run().await.unwrap();
+    assert_eq!(report.applied.iter().map(|a| a.node_id).collect::<Vec<_>>(), vec![100]);
+}
+
+fn state_row(lastmode: &str, current: &str) -> StateRow {
+    StateRow {
+        nodebalancer_id: 1,
+        nodebalancer_config_id: 10,
+        node_id: 100,
+        port: 80,
+        lastmode: lastmode.to_string(),
+        current: current.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn failing_nodes_are_drained_once() {
+    let mock = MockLinode::start().await;
+    mock.update_node(1, 10, 100, "drain", 1).await;
+    let store = store_with_modes(["accept", "accept", "accept"]).await;
+    probe_history(&store, [false, true, true]).await;
+
+    let report = remediator(&mock, &store).run().await.unwrap();
+    assert_eq!(report.applied.iter().map(|a| (a.node_id, a.to.clone())).collect::<Vec<_>>(), vec![(100, NodeMode::Drain)]);
+    assert!(report.errors.is_empty());
+    assert_eq!(store.state(), vec![state_row("accept", "drain")]);
+    let audited = store.audit_events(ago(Utc::now(), WINDOW), Some(1)).await.unwrap();
+    assert_eq!(audited.iter().map(|e| (e.action.clone(), e.object_id)).collect::<Vec<_>>(), vec![(AuditAction::Remediated, 100)]);
+    assert_eq!(audited[0].after.as_ref().unwrap()["mode"], "drain");
+
+    // Still failing, but already drained, before and after the next sync.
+    assert!(remediator(&mock, &store).run().await.unwrap().applied.is_empty());
+    sync_modes(&store, ["drain", "accept", "accept"]).await;
+    assert!(remediator(&mock, &store).run().await.unwrap().applied.is_empty());
+}
+
+#[tokio::test]
+async fn recovered_nodes_get_their_mode_back() {
+    let mock = MockLinode::start().await;
+    mock.update_node(1, 10, 100, "accept", 1).await;
+    let store = store_with_modes(["drain", "accept", "accept"]).await;
+    store.update_state(1, 10, 100, 80, "accept", "drain").await.unwrap();
+    probe_history(&store, [true, true, true]).await;
+
+    let report = remediator(&mock, &store).run().await.unwrap();
+    assert_eq!(report.applied.iter().map(|a| (a.from.clone(), a.to.clone())).collect::<Vec<_>>(), vec![(NodeMode::Drain, NodeMode::Accept)]);
+    assert_eq!(store.state(), vec![state_row("accept", "drain"), state_row("drain", "accept")]);
+}
+
+#[tokio::test]
+async fn modes_changed_by_hand_are_left_alone() {
+    let mock = MockLinode::start().await;
+    let store = store_with_modes(["reject", "accept", "accept"]).await;
+    store.update_state(1, 10, 100, 80, "accept", "drain").await.unwrap();
+    probe_history(&store, [true, true, true]).await;
+    let report = remediator(&mock, &store).run().await.unwrap();
+    assert!(report.applied.is_empty());
+}
+
+#[tokio::test]
+async fn dry_runs_change_nothing() {
+    let mock = MockLinode::start().await;
+    mock.update_node(1, 10, 100, "drain", 0).await;
+    let store = store_with_modes(["accept", "accept", "accept"]).await;
+    probe_history(&store, [false, true, true]).await;
+
+    let report = remediator(&mock, &store).dry_run(true).run().await.unwrap();
+    assert!(report.dry_run);
+    assert_eq!(report.applied.len(), 1);
+    assert!(store.state().is_empty());
+}
+
+#[tokio::test]
+async fn failed_switches_are_reported_and_not_recorded() {
+    let mock = MockLinode::start().await;
+    let store = store_with_modes(["accept", "accept", "accept"]).await;
+    probe_history(&store, [false, true, true]).await;
+
+    // No PUT is mocked, so the API answers 404.
+    let report = remediator(&mock, &store).run().await.unwrap();
+    assert!(report.applied.is_empty());
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].contains("404"));
+    assert!(store.state().is_empty());
+}
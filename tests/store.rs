@@ -0,0 +1,239 @@
+//! The in-memory and SQLite stores must agree, so tests written against
+//! `MemoryStore` say something about the real databases too.
+
+use serde_json::json;
+use hc_nb_api_client::memory::MemoryStore;
+use chrono::{TimeZone, Utc};
+use hc_nb_api_client::models::{AuditAction, AuditEvent, AuditObject, DriftViolation, LocalNodeBalancerListObject, NodeBalancerConfigObject, NodeHealth, NodeObject, NodeProbe, NodeStatus, Probe, ProbeTimings, TrafficSample, Verdict};
+use hc_nb_api_client::sqlite::SqliteStore;
+use hc_nb_api_client::Store;
+
+fn nodebalancer(id: i32) -> LocalNodeBalancerListObject {
+    LocalNodeBalancerListObject { nb_id: id, ipv4: format!("192.0.2.{}", id), region: "us-ord".to_string(), lke_id: None, ..Default::default() }
+}
+
+// As listed from the API, with its metadata.
+fn listed(id: i32) -> LocalNodeBalancerListObject {
+    LocalNodeBalancerListObject {
+        label: format!("nb-{}", id),
+        hostname: format!("nb-192-0-2-{}.chicago.nodebalancer.linode.com", id),
+        ipv6: "2001:db8::1".to_string(),
+        client_conn_throttle: 5,
+        nb_type: "common".to_string(),
+        created: "2026-01-01T00:00:00".to_string(),
+        updated: "2026-01-02T00:00:00".to_string(),
+        lke_id: Some(7),
+        lke_label: Some("prod".to_string()),
+        lke_type: Some("lkecluster".to_string()),
+        lke_url: Some("v4/lke/clusters/7".to_string()),
+        account: Some("default".to_string()),
+        ..nodebalancer(id)
+    }
+}
+
+fn config(nb_id: i32, id: i32, algorithm: &str) -> NodeBalancerConfigObject {
+    serde_json::from_value(json!({
+        "id": id,
+        "nodebalancer_id": nb_id,
+        "port": 80,
+        "algorithm": algorithm,
+        "protocol": "http",
+        "check": "http_body",
+        "check_path": "/healthz",
+        "check_body": "ok",
+        "check_interval": 10,
+        "nodes_status": { "up": 1, "down": 1 },
+    })).unwrap()
+}
+
+fn node(nb_id: i32, config_id: i32, id: i32, address: &str, status: &str) -> NodeObject {
+    serde_json::from_value(json!({
+        "id": id,
+        "config_id": config_id,
+        "nodebalancer_id": nb_id,
+        "address": address,
+        "status": status,
+        "mode": "accept",
+    })).unwrap()
+}
+
+async fn exercise(store: &dyn Store) -> String {
+    store.init().await.unwrap();
+    let first = store.write_nodebalancer(
+        listed(2),
+        vec![config(2, 20, "leastconn")],
+        vec![node(2, 20, 201, "10.0.0.2:80", "DOWN"), node(2, 20, 200, "10.0.0.1:80", "UP")],
+    ).await.unwrap();
+    let second = store.write_nodebalancer(
+        nodebalancer(1),
+        vec![config(1, 10, "no-such-algorithm")],
+        vec![node(1, 10, 100, "10.0.1.1:443", "unknown")],
+    ).await.unwrap();
+    let again = store.write_nodebalancer(
+        nodebalancer(2),
+        vec![config(2, 20, "roundrobin")],
+        vec![node(2, 20, 200, "10.0.0.1:80", "DOWN")],
+    ).await.unwrap();
+    // Written again from the main DB, without metadata.
+    let nodebalancers = store.nodebalancers().await.unwrap();
+    assert_eq!((nodebalancers[1].label.as_str(), nodebalancers[1].lke_label.as_deref()), ("nb-2", Some("prod")));
+
+    store.record_probes(vec![
+        NodeProbe {
+            nodebalancer_id: 2,
+            node_id: 200,
+            probe: Probe { status: NodeStatus::Up, latency_ms: Some(1.5), error: None, probed_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap() },
+            timings: ProbeTimings { connect_ms: Some(0.5), ttfb_ms: Some(1.0), http_status: Some(200), ..Default::default() },
+        },
+        NodeProbe {
+            nodebalancer_id: 9,
+            node_id: 900,
+            probe: Probe { status: NodeStatus::Down, latency_ms: None, error: Some("gone".to_string()), probed_at: Utc::now() },
+            timings: Default::default(),
+        },
+    ]).await.unwrap();
+
+    // One more probe in the same five minutes, two in the next.
+    for (second, status, latency_ms) in [(60, NodeStatus::Down, None), (120, NodeStatus::Up, Some(2.5)), (300, NodeStatus::Up, Some(9.0))] {
+        store.record_probes(vec![NodeProbe {
+            nodebalancer_id: 2,
+            node_id: 200,
+            probe: Probe { status, latency_ms, error: None, probed_at: Utc.timestamp_opt(1_700_000_000 + second, 0).unwrap() },
+            timings: ProbeTimings { connect_ms: Some(1.0), ..Default::default() },
+        }]).await.unwrap();
+    }
+    let raw = store.probe_samples(Utc.timestamp_opt(0, 0).unwrap()).await.unwrap();
+    store.compact_probe_samples(Utc.timestamp_opt(1_700_000_100, 0).unwrap(), 300, Utc.timestamp_opt(0, 0).unwrap()).await.unwrap();
+    let compacted = store.probe_samples(Utc.timestamp_opt(0, 0).unwrap()).await.unwrap();
+
+    let health = |node_id: i32, current: Verdict, streak: i32| NodeHealth {
+        nodebalancer_id: 2,
+        config_id: 20,
+        node_id,
+        port: 80,
+        address: "10.0.0.1:80".to_string(),
+        lastverdict: Verdict::Agree,
+        current,
+        observed: Verdict::LinodeDownProbeUp,
+        streak,
+        since: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        probed_at: Utc.timestamp_opt(1_700_000_000 + streak as i64, 0).unwrap(),
+    };
+    store.record_node_health(vec![health(201, Verdict::Agree, 1), health(200, Verdict::Agree, 1)]).await.unwrap();
+    store.record_node_health(vec![health(200, Verdict::LinodeDownProbeUp, 3)]).await.unwrap();
+
+    store.update_state(2, 20, 200, 80, "accept", "drain").await.unwrap();
+    store.update_state(2, 20, 201, 80, "accept", "reject").await.unwrap();
+    store.update_state(2, 20, 200, 80, "drain", "accept").await.unwrap();
+    let states = store.node_states().await.unwrap();
+
+    let event = |second: i64, object: AuditObject, action: AuditAction, nodebalancer_id: i32, object_id: i32, mode: Option<&str>| AuditEvent {
+        at: Utc.timestamp_opt(1_700_000_000 + second, 0).unwrap(),
+        object,
+        action: action.clone(),
+        nodebalancer_id,
+        object_id,
+        before: (action != AuditAction::Added).then(|| json!({ "mode": "accept" })),
+        after: mode.map(|mode| json!({ "mode": mode })),
+    };
+    store.record_audit(vec![
+        event(0, AuditObject::Nodebalancer, AuditAction::Added, 2, 2, Some("accept")),
+        event(0, AuditObject::Node, AuditAction::Added, 2, 200, Some("accept")),
+        event(0, AuditObject::Node, AuditAction::Added, 1, 100, Some("accept")),
+    ]).await.unwrap();
+    store.record_audit(vec![
+        event(60, AuditObject::Node, AuditAction::Changed, 2, 200, Some("drain")),
+        event(60, AuditObject::Node, AuditAction::Remediated, 2, 200, Some("accept")),
+        event(120, AuditObject::Node, AuditAction::Removed, 1, 100, None),
+    ]).await.unwrap();
+    let audit = (
+        store.audit_events(Utc.timestamp_opt(1_700_000_060, 0).unwrap(), None).await.unwrap(),
+        store.audit_events(Utc.timestamp_opt(0, 0).unwrap(), Some(1)).await.unwrap(),
+        store.audited_objects().await.unwrap(),
+    );
+
+    let violation = |nodebalancer_id: i32, field: &str, since: i64| DriftViolation {
+        nodebalancer_id,
+        config_id: nodebalancer_id * 10,
+        port: 80,
+        rule: "checked".to_string(),
+        field: field.to_string(),
+        expected: "http".to_string(),
+        actual: "none".to_string(),
+        since: Utc.timestamp_opt(1_700_000_000 + since, 0).unwrap(),
+    };
+    store.record_config_drift(vec![1, 2], vec![violation(2, "check", 0), violation(1, "check_path", 0), violation(1, "check", 0)]).await.unwrap();
+    store.record_config_drift(vec![1, 3], vec![violation(1, "check", 60)]).await.unwrap();
+    let drift = store.config_drift().await.unwrap();
+
+    let traffic = |nodebalancer_id: i32, second: i64, connections: Option<f64>| TrafficSample {
+        nodebalancer_id,
+        at: Utc.timestamp_opt(1_700_000_000 + second, 0).unwrap(),
+        connections,
+        traffic_in: Some(1000.0),
+        traffic_out: None,
+    };
+    store.record_traffic(vec![traffic(2, 0, Some(4.0)), traffic(1, 300, None), traffic(1, 0, Some(2.0))], Utc.timestamp_opt(0, 0).unwrap()).await.unwrap();
+    store.record_traffic(vec![traffic(1, 300, Some(3.0))], Utc.timestamp_opt(1_700_000_000 + 300, 0).unwrap()).await.unwrap();
+    let traffic = (
+        store.traffic_samples(Utc.timestamp_opt(0, 0).unwrap(), None).await.unwrap(),
+        store.traffic_samples(Utc.timestamp_opt(0, 0).unwrap(), Some(1)).await.unwrap(),
+    );
+
+    // NB 2 was written again without node 201, and NB 1 is no longer
+    // listed.
+    let removed = (
+        store.remove_nodebalancers(vec![1, 9]).await.unwrap(),
+        store.remove_nodebalancers(vec![1]).await.unwrap(),
+        store.config_drift().await.unwrap().len(),
+    );
+
+    // Only `node_details` promises an order.
+    let mut targets = store.probe_targets().await.unwrap();
+    targets.sort_by_key(|t| (t.nodebalancer_id, t.node_id));
+    let mut ids = store.nodebalancer_ids().await.unwrap();
+    ids.sort();
+    let mut configs = store.configs().await.unwrap();
+    configs.sort_by_key(|c| (c.nodebalancer_id, c.id));
+    let mut by_address = store.nodes_by_address("10.0.0.").await.unwrap();
+    by_address.sort_by_key(|n| (n.nodebalancer_id, n.id));
+    let mut verdicts = store.node_health().await.unwrap();
+    verdicts.sort_by_key(|h| (h.nodebalancer_id, h.node_id));
+
+    format!(
+        "{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+        (first, second, again, removed),
+        nodebalancers,
+        (audit, drift, traffic),
+        targets,
+        raw,
+        compacted,
+        states,
+        verdicts,
+        ids,
+        configs,
+        store.node_details().await.unwrap(),
+        by_address,
+        store.nodes_by_address("192.0.2.").await.unwrap(),
+        store.update_state(1, 10, 100, 443, "accept", "drain").await.map_err(|e| e.to_string()),
+    )
+}
+
+#[tokio::test]
+async fn memory_and_sqlite_stores_agree() {
+    let memory = exercise(&MemoryStore::new()).await;
+    let sqlite = exercise(&SqliteStore::in_memory().unwrap()).await;
+
+    assert_eq!(memory, sqlite);
+}
+
+#[tokio::test]
+async fn memory_store_records_state_changes() {
+    let store = MemoryStore::new();
+    store.write_nodebalancer(nodebalancer(1), vec![config(1, 10, "roundrobin")], vec![]).await.unwrap();
+    store.update_state(1, 10, 100, 80, "accept", "drain").await.unwrap();
+
+    let state = store.state();
+    assert_eq!(state.len(), 1);
+    assert_eq!((state[0].node_id, state[0].lastmode.as_str(), state[0].current.as_str()), (100, "accept", "drain"));
+}
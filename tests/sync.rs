@@ -0,0 +1,284 @@
+//! Full sync cycles against a mock Linode API, asserting on what ends up in
+//! the store.
+
+mod common;
+
+use common::*;
+use reqwest::StatusCode;
+use serde_json::json;
+use std::sync::Arc;
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::{Algorithm, NodeMode, NodeStatus};
+use hc_nb_api_client::{Error, Store};
+
+#[tokio::test]
+async fn cycle_mirrors_every_nodebalancer_config_and_node() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 2, 0), config(1, 11, 443, 0, 1)]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP"), node(1, 10, 101, "UP")]).await;
+    mock.list(&nodes_path(1, 11), vec![node(1, 11, 110, "DOWN")]).await;
+    simple_nodebalancer(&mock, 2, 1).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!((report.inserted, report.updated, report.failed), (2 + 3 + 4, 0, 0));
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![1, 2]);
+
+    let nodes = store.node_details().await.unwrap();
+    let summary: Vec<_> = nodes.iter()
+        .map(|n| (n.nodebalancer_id, n.config_id, n.id, n.port, n.status.clone()))
+        .collect();
+    assert_eq!(summary, vec![
+        (1, 10, 100, 80, NodeStatus::Up),
+        (1, 10, 101, 80, NodeStatus::Up),
+        (1, 11, 110, 443, NodeStatus::Down),
+        (2, 20, 200, 80, NodeStatus::Up),
+    ]);
+    assert_eq!(nodes[0].ipv4, "192.0.2.1");
+    assert_eq!(nodes[0].mode, NodeMode::Accept);
+    assert_eq!(nodes[2].down, 1);
+}
+
+#[tokio::test]
+async fn second_cycle_updates_instead_of_inserting() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    simple_nodebalancer(&mock, 1, 3).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let syncer = mock.syncer(Arc::clone(&store));
+    let first = syncer.run_cycle().await.unwrap();
+    let second = syncer.run_cycle().await.unwrap();
+
+    assert_eq!((first.inserted, first.updated), (5, 0));
+    assert_eq!((second.inserted, second.updated), (0, 5));
+    assert_eq!(store.node_details().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn every_page_is_followed() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    mock.pages(&configs_path(1), vec![
+        vec![config(1, 10, 80, 1, 0)],
+        vec![config(1, 11, 81, 3, 0)],
+    ]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP")]).await;
+    mock.pages(&nodes_path(1, 11), vec![
+        vec![node(1, 11, 110, "UP")],
+        vec![node(1, 11, 111, "UP")],
+        vec![node(1, 11, 112, "UP")],
+    ]).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(store.configs().await.unwrap().len(), 2);
+    let ids: Vec<i32> = store.node_details().await.unwrap().iter().map(|n| n.id).collect();
+    assert_eq!(ids, vec![100, 110, 111, 112]);
+}
+
+#[tokio::test]
+async fn rate_limited_requests_are_retried() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    simple_nodebalancer(&mock, 1, 2).await;
+    mock.rate_limit(&configs_path(1), 2).await;
+    mock.rate_limit(&nodes_path(1, 10), 1).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(store.node_details().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn persistent_rate_limiting_fails_only_that_nodebalancer() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    simple_nodebalancer(&mock, 1, 1).await;
+    simple_nodebalancer(&mock, 2, 1).await;
+    mock.rate_limit(&configs_path(2), 100).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].starts_with("NB 2:"), "{}", report.errors[0]);
+    assert!(report.errors[0].contains("429"), "{}", report.errors[0]);
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![1]);
+}
+
+#[tokio::test]
+async fn server_error_fails_only_that_nodebalancer() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2), nodebalancer(3)]).await;
+    simple_nodebalancer(&mock, 1, 1).await;
+    simple_nodebalancer(&mock, 2, 1).await;
+    simple_nodebalancer(&mock, 3, 1).await;
+    mock.fail(&nodes_path(2, 20), 500).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].starts_with("NB 2:"), "{}", report.errors[0]);
+    assert!(report.errors[0].contains("500"), "{}", report.errors[0]);
+    // Nothing of the failed NB is written, not even its configs.
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![1, 3]);
+    assert!(store.configs().await.unwrap().iter().all(|c| c.nodebalancer_id != 2));
+}
+
+#[tokio::test]
+async fn malformed_json_fails_only_that_nodebalancer() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    simple_nodebalancer(&mock, 1, 1).await;
+    simple_nodebalancer(&mock, 2, 1).await;
+    mock.malformed(&configs_path(1)).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].starts_with("NB 1:"), "{}", report.errors[0]);
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![2]);
+}
+
+#[tokio::test]
+async fn undecodable_objects_are_skipped() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 2, 0)]).await;
+    mock.list(&nodes_path(1, 10), vec![
+        node(1, 10, 100, "UP"),
+        json!({ "address": "10.0.10.1:80", "status": "UP" }),
+        node(1, 10, 102, "UP"),
+    ]).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    let ids: Vec<i32> = store.node_details().await.unwrap().iter().map(|n| n.id).collect();
+    assert_eq!(ids, vec![100, 102]);
+}
+
+#[tokio::test]
+async fn unknown_and_null_fields_are_tolerated() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    let mut cfg = config(1, 10, 80, 1, 0);
+    cfg["algorithm"] = json!("quantum");
+    cfg["nodes_status"] = json!(null);
+    cfg["brand_new_field"] = json!({ "nested": true });
+    mock.list(&configs_path(1), vec![cfg]).await;
+    let mut n = node(1, 10, 100, "UP");
+    n["status"] = json!(null);
+    n["mode"] = json!("standby");
+    mock.list(&nodes_path(1, 10), vec![n]).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    let nodes = store.node_details().await.unwrap();
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0].algorithm, Algorithm::default());
+    assert_eq!(nodes[0].status, NodeStatus::default());
+    assert_eq!(nodes[0].mode, NodeMode::default());
+    assert_eq!((nodes[0].up, nodes[0].down), (0, 0));
+}
+
+#[tokio::test]
+async fn nodebalancer_metadata_is_stored() {
+    let mock = MockLinode::start().await;
+    let mut nb = nodebalancer(1);
+    nb["hostname"] = json!("nb-192-0-2-1.chicago.nodebalancer.linode.com");
+    nb["client_conn_throttle"] = json!(10);
+    nb["type"] = json!("premium");
+    nb["lke_cluster"] = json!({ "id": 7, "label": "prod", "type": "lkecluster", "url": "v4/lke/clusters/7" });
+    mock.nodebalancers(vec![nb]).await;
+    simple_nodebalancer(&mock, 1, 1).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    let nodebalancers = store.nodebalancers().await.unwrap();
+    let nb = &nodebalancers[0];
+    assert_eq!((nb.label.as_str(), nb.hostname.as_str()), ("nb-1", "nb-192-0-2-1.chicago.nodebalancer.linode.com"));
+    assert_eq!((nb.client_conn_throttle, nb.nb_type.as_str(), nb.created.as_str()), (10, "premium", "2026-01-01T00:00:00"));
+    assert_eq!((nb.lke_id, nb.lke_label.as_deref(), nb.lke_url.as_deref()), (Some(7), Some("prod"), Some("v4/lke/clusters/7")));
+    assert_eq!(store.node_details().await.unwrap()[0].nodebalancer_label, "nb-1");
+}
+
+#[tokio::test]
+async fn what_is_no_longer_listed_is_deleted() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 2, 0), config(1, 11, 443, 1, 0)]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP"), node(1, 10, 101, "UP")]).await;
+    mock.list(&nodes_path(1, 11), vec![node(1, 11, 110, "UP")]).await;
+    simple_nodebalancer(&mock, 2, 1).await;
+    let store = Arc::new(MemoryStore::new());
+    let syncer = mock.syncer(Arc::clone(&store));
+    syncer.run_cycle().await.unwrap();
+
+    // Node 101, config 11 with its node, and NB 2 with its own are gone.
+    mock.server.reset().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 1, 0)]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP")]).await;
+    let report = syncer.run_cycle().await.unwrap();
+
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.deleted, 2 + 1 + 3);
+    assert!(report.to_string().contains(", 6 deleted"));
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![1]);
+    let configs = store.configs().await.unwrap();
+    assert_eq!(configs.iter().map(|c| c.id).collect::<Vec<_>>(), vec![10]);
+    let nodes = store.node_details().await.unwrap();
+    assert_eq!(nodes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![100]);
+
+    // A nodebalancer that is listed again is read back again.
+    mock.server.reset().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 1, 0)]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP")]).await;
+    simple_nodebalancer(&mock, 2, 1).await;
+    syncer.run_cycle().await.unwrap();
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![1, 2]);
+}
+
+#[tokio::test]
+async fn failing_discovery_fails_the_cycle() {
+    let mock = MockLinode::start().await;
+    mock.fail("/nodebalancers", 503).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let result = mock.syncer(Arc::clone(&store)).run_cycle().await;
+
+    match result {
+        Err(Error::ApiStatus { status, .. }) => assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE),
+        other => panic!("expected a 503, got {:?}", other),
+    }
+    assert!(store.nodebalancer_ids().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn empty_region_is_a_successful_cycle() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![]).await;
+
+    let store = Arc::new(MemoryStore::new());
+    let report = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+
+    assert_eq!((report.inserted, report.updated, report.failed), (0, 0, 0));
+    assert!(report.errors.is_empty());
+}
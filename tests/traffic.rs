@@ -0,0 +1,138 @@
+//! NodeBalancer traffic stats: pulled from the API, kept in the store and
+//! served by the HTTP API and metrics.
+
+mod common;
+
+use chrono::{DateTime, Duration as Delta, DurationRound, Utc};
+use common::*;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::NodeBalancerStats;
+use hc_nb_api_client::traffic::{self, TrafficCollector};
+use hc_nb_api_client::{api, metrics, Store};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn stats(connections: Vec<(DateTime<Utc>, f64)>, traffic_in: Vec<(DateTime<Utc>, f64)>, traffic_out: Vec<(DateTime<Utc>, f64)>) -> Value {
+    let series = |points: Vec<(DateTime<Utc>, f64)>| points.into_iter().map(|(at, v)| json!([at.timestamp_millis(), v])).collect::<Vec<_>>();
+    json!({
+        "data": {
+            "connections": series(connections),
+            "traffic": { "in": series(traffic_in), "out": series(traffic_out) },
+        },
+        "title": "balancer (1) - day (5 min avg)",
+    })
+}
+
+async fn serve_stats(mock: &MockLinode, nb_id: i32, body: Value) {
+    Mock::given(method("GET"))
+        .and(path(format!("/nodebalancers/{}/stats", nb_id)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(&mock.server)
+        .await;
+}
+
+// NB 1 and NB 2 synced from the mock.
+async fn synced(mock: &MockLinode) -> Arc<MemoryStore> {
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    simple_nodebalancer(mock, 1, 1).await;
+    simple_nodebalancer(mock, 2, 1).await;
+    let store = Arc::new(MemoryStore::new());
+    mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+    store
+}
+
+#[test]
+fn series_are_merged_by_time() {
+    let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let t1 = t0 + Delta::minutes(5);
+    let body = stats(vec![(t0, 5.0), (t1, 6.0)], vec![(t0, 800.0)], vec![(t1, 1600.0)]);
+    let parsed: NodeBalancerStats = serde_json::from_value(body).unwrap();
+
+    let samples = traffic::samples(7, &parsed);
+    let shape: Vec<_> = samples.iter().map(|s| (s.nodebalancer_id, s.at, s.connections, s.traffic_in, s.traffic_out)).collect();
+    assert_eq!(shape, vec![(7, t0, Some(5.0), Some(800.0), None), (7, t1, Some(6.0), None, Some(1600.0))]);
+
+    let sparse: NodeBalancerStats = serde_json::from_value(json!({ "data": { "connections": [[1_700_000_000_000i64, null]] } })).unwrap();
+    assert_eq!(traffic::samples(7, &sparse)[0].connections, None);
+}
+
+#[tokio::test]
+async fn rounds_record_every_nodebalancer_and_expire_old_samples() {
+    let mock = MockLinode::start().await;
+    let store = synced(&mock).await;
+    let now = Utc::now().duration_trunc(Delta::minutes(5)).unwrap();
+    let old = now - Delta::days(2);
+    serve_stats(&mock, 1, stats(vec![(old, 1.0), (now, 12.0)], vec![(now, 8000.0)], vec![(now, 4000.0)])).await;
+    mock.fail("/nodebalancers/2/stats", 400).await;
+
+    let collector = TrafficCollector::new(mock.client(), Arc::clone(&store) as Arc<dyn Store>)
+        .retention(Duration::from_secs(24 * 60 * 60));
+    let report = collector.run_round().await.unwrap();
+
+    assert_eq!((report.nodebalancers, report.samples), (1, 2));
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].starts_with("NB 2: "), "{:?}", report.errors);
+    let stored = store.traffic_samples(DateTime::<Utc>::MIN_UTC, None).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!((stored[0].at, stored[0].connections), (now, Some(12.0)));
+}
+
+#[tokio::test]
+async fn a_listing_collects_from_the_first_cycle_on_and_only_what_it_listed() {
+    let mock = MockLinode::start().await;
+    // Synced by an earlier leader, NB 2 has since gone.
+    let store = synced(&mock).await;
+    let syncer = mock.syncer(Arc::clone(&store));
+    let collector = TrafficCollector::new(mock.client(), Arc::clone(&store) as Arc<dyn Store>).listing(syncer.listing());
+    let mut listing = syncer.listing();
+
+    assert!(tokio::time::timeout(Duration::from_millis(50), listing.first_cycle()).await.is_err());
+    let report = collector.run_round().await.unwrap();
+    assert_eq!((report.nodebalancers, report.errors.len()), (0, 0));
+
+    mock.server.reset().await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    simple_nodebalancer(&mock, 1, 1).await;
+    let now = Utc::now().duration_trunc(Delta::minutes(5)).unwrap();
+    serve_stats(&mock, 1, stats(vec![(now, 1.0)], vec![], vec![])).await;
+    serve_stats(&mock, 2, stats(vec![(now, 2.0)], vec![], vec![])).await;
+    syncer.run_cycle().await.unwrap();
+
+    listing.first_cycle().await;
+    assert_eq!(listing.nodebalancer_ids(), vec![1]);
+    let report = collector.run_round().await.unwrap();
+    assert_eq!((report.nodebalancers, report.samples), (1, 1));
+    let requests = mock.server.received_requests().await.unwrap();
+    assert!(!requests.iter().any(|r| r.url.path() == "/nodebalancers/2/stats"));
+}
+
+#[tokio::test]
+async fn traffic_is_served_and_exported() {
+    let mock = MockLinode::start().await;
+    let store = synced(&mock).await;
+    let now = Utc::now().duration_trunc(Delta::minutes(5)).unwrap();
+    let before = now - Delta::minutes(5);
+    serve_stats(&mock, 1, stats(vec![(before, 10.0), (now, 2.0)], vec![(before, 9000.0), (now, 100.0)], vec![(now, 50.0)])).await;
+    serve_stats(&mock, 2, stats(vec![(now, 3.0)], vec![], vec![])).await;
+    TrafficCollector::new(mock.client(), Arc::clone(&store) as Arc<dyn Store>).run_round().await.unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, api::router(vec![(REGION.to_string(), Arc::clone(&store) as Arc<dyn Store>)])).into_future());
+    let http = reqwest::Client::new();
+
+    let samples: Value = http.get(format!("{}/traffic?nb_id=1&window=1h", base)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(samples.as_array().unwrap().len(), 2);
+    assert_eq!(samples[0]["connections"], 10.0);
+    assert_eq!(samples[1]["traffic_out"], 50.0);
+    let all: Value = http.get(format!("{}/traffic", base)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(all.as_array().unwrap().len(), 3);
+
+    let text = metrics::render(&[(REGION.to_string(), store as Arc<dyn Store>)]).await.unwrap();
+    assert!(text.contains(&format!("hc_nb_nodebalancer_connections{{location=\"{}\",nodebalancer_id=\"1\"}} 2", REGION)));
+    assert!(text.contains(&format!("hc_nb_nodebalancer_traffic_bits_per_second{{location=\"{}\",nodebalancer_id=\"1\",direction=\"in\"}} 100", REGION)));
+    assert!(!text.contains(&format!("hc_nb_nodebalancer_traffic_bits_per_second{{location=\"{}\",nodebalancer_id=\"2\"", REGION)));
+}
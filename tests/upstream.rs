@@ -0,0 +1,104 @@
+//! The per-cycle report and the heartbeats written to the main DB.
+
+mod common;
+
+use chrono::{TimeDelta, Utc};
+use common::*;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use hc_nb_api_client::memory::MemoryStore;
+use hc_nb_api_client::models::{ClientInstance, NodeMode, NodeProbe, NodeStatus, Probe};
+use hc_nb_api_client::upstream::{self, Liveness};
+use hc_nb_api_client::Store;
+
+#[tokio::test]
+async fn report_counts_the_region_and_lists_unhealthy_nodes() {
+    let mock = MockLinode::start().await;
+    mock.nodebalancers(vec![nodebalancer(1), nodebalancer(2)]).await;
+    mock.list(&configs_path(1), vec![config(1, 10, 80, 1, 1), config(1, 11, 443, 0, 0)]).await;
+    mock.list(&nodes_path(1, 10), vec![node(1, 10, 100, "UP"), node(1, 10, 101, "DOWN")]).await;
+    mock.list(&nodes_path(1, 11), vec![]).await;
+    simple_nodebalancer(&mock, 2, 2).await;
+    let store = Arc::new(MemoryStore::new());
+    let cycle = mock.syncer(Arc::clone(&store)).run_cycle().await.unwrap();
+    store.record_probes(vec![NodeProbe {
+        nodebalancer_id: 2,
+        node_id: 200,
+        probe: Probe { status: NodeStatus::Down, latency_ms: None, error: Some("refused".to_string()), probed_at: Utc::now() },
+        timings: Default::default(),
+    }]).await.unwrap();
+
+    let started = Utc::now();
+    let report = upstream::summarize(store.as_ref(), REGION, started, &cycle, &HashSet::from([1, 2])).await.unwrap();
+
+    assert_eq!((report.location.as_str(), report.synced_at), (REGION, started));
+    assert_eq!((report.nodebalancers, report.configs, report.nodes), (2, 3, 4));
+    assert_eq!((report.up, report.down, report.errors), (3, 1, 0));
+    let unhealthy: Vec<_> = report.unhealthy.iter().map(|n| (n.node_id, n.status.clone(), n.probe_status.clone())).collect();
+    assert_eq!(unhealthy, vec![(101, NodeStatus::Down, None), (200, NodeStatus::Up, Some(NodeStatus::Down))]);
+    assert!(report.unhealthy.iter().all(|n| n.mode == NodeMode::Accept));
+
+    let report = upstream::summarize(store.as_ref(), REGION, started, &cycle, &HashSet::from([1])).await.unwrap();
+    assert_eq!((report.nodebalancers, report.configs, report.nodes), (1, 2, 2));
+    assert_eq!(report.unhealthy.iter().map(|n| n.node_id).collect::<Vec<_>>(), vec![101]);
+}
+
+#[tokio::test]
+async fn reporting_without_a_main_db_is_a_cycle_error() {
+    let mock = MockLinode::start().await;
+    simple_nodebalancer(&mock, 1, 1).await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    let store = Arc::new(MemoryStore::new());
+
+    let report = mock.syncer(Arc::clone(&store)).report_upstream(true).run_cycle().await.unwrap();
+
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].contains("upstream reporting needs MAINDB_HOSTPORT"), "{:?}", report.errors);
+    assert_eq!(store.nodebalancer_ids().await.unwrap(), vec![1]);
+}
+
+fn instance(id: &str, location: &str, minutes_ago: i64) -> ClientInstance {
+    let now = Utc::now();
+    ClientInstance {
+        instance_id: id.to_string(),
+        location: location.to_string(),
+        version: "0.1.0".to_string(),
+        started_at: now - TimeDelta::hours(1),
+        last_heartbeat: now - TimeDelta::minutes(minutes_ago),
+    }
+}
+
+#[test]
+fn locations_are_live_stale_or_missing() {
+    let instances = vec![
+        instance("ord-old", "us-ord", 90),
+        instance("ord-new", "us-ord", 1),
+        instance("iad", "us-iad", 30),
+        instance("lab", "lab", 2),
+    ];
+    let expected = vec!["us-iad".to_string(), "us-ord".to_string(), "eu-central".to_string()];
+
+    let statuses = upstream::locations(instances, &expected, Utc::now(), Duration::from_secs(600));
+
+    let summary: Vec<_> = statuses.iter().map(|s| (s.location.as_str(), s.liveness, s.instance.as_ref().map(|i| i.instance_id.as_str()))).collect();
+    assert_eq!(summary, vec![
+        ("eu-central", Liveness::Missing, None),
+        ("lab", Liveness::Live, Some("lab")),
+        ("us-iad", Liveness::Stale, Some("iad")),
+        ("us-ord", Liveness::Live, Some("ord-new")),
+    ]);
+}
+
+#[tokio::test]
+async fn heartbeats_without_a_main_db_are_a_cycle_error() {
+    let mock = MockLinode::start().await;
+    simple_nodebalancer(&mock, 1, 1).await;
+    mock.nodebalancers(vec![nodebalancer(1)]).await;
+    let syncer = mock.syncer(Arc::new(MemoryStore::new())).heartbeat(Some(instance("ord", REGION, 0)));
+
+    let report = syncer.run_cycle().await.unwrap();
+
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].starts_with("heartbeat: "), "{:?}", report.errors);
+}